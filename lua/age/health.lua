@@ -0,0 +1,36 @@
+-- `:checkhealth age`
+--
+-- The report itself is built by the rust side, see `App::health`.
+local M = {}
+
+M.check = function()
+  vim.health.start("age.nvim")
+
+  local ok, age = pcall(require, "age")
+  if not ok then
+    vim.health.error("failed to load `lua/age.so`, run `just install`", { age })
+    return
+  end
+
+  local report = age.health()
+
+  if report.identity_source then
+    vim.health.info("identity: " .. report.identity_source)
+  end
+
+  if report.identity_error then
+    vim.health.error(report.identity_error)
+  else
+    vim.health.ok("identity loaded")
+  end
+
+  if report.agent_socket then
+    if report.agent_error then
+      vim.health.warn("agent at " .. report.agent_socket .. ": " .. report.agent_error)
+    else
+      vim.health.ok(("agent at %s holds %d identities"):format(report.agent_socket, report.agent_identities))
+    end
+  end
+end
+
+return M
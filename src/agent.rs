@@ -0,0 +1,611 @@
+//! A small identity agent in the spirit of `ssh-agent`.
+//!
+//! One Neovim instance runs the agent (`:Age agent start`), which holds the
+//! unlocked identities in memory and answers unwrap requests on a Unix
+//! socket. Every instance with `$AGE_NVIM_AGENT_SOCK` pointing at that
+//! socket asks the agent first (see `crypt::load_identities`), so a
+//! passphrase-protected identity is only unlocked once.
+//!
+//! The private keys never leave the agent, clients only send the recipient
+//! stanzas of a file header and get the file key back.
+//!
+//! ## Protocol
+//!
+//! One request per connection, lines separated by `\n`, binary values are
+//! standard base64 (`-` when empty).
+//!
+//! ```text
+//! ping                               -> ok <identities>
+//! add <identity file>                -> ok <identities>
+//! lock                               -> ok 0
+//! stop                               -> ok 0
+//! unwrap                             -> ok <file key> | none | err <message>
+//! stanza <tag> <body> [args...]
+//! <empty line>
+//! ```
+//!
+//! Identities are forgotten on `lock`, and `timeout` after they were added.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use age::secrecy::{ExposeSecret, SecretString};
+use age_core::format::{FileKey, Stanza};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::crypt::{self, BoxedIdentity};
+use crate::error::AgeError;
+
+/// Environment variable pointing clients at the agent socket.
+pub(crate) const SOCK_VAR: &str = "AGE_NVIM_AGENT_SOCK";
+
+/// How long a client waits for the agent before giving up.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often expired identities are dropped when no request comes in.
+const EXPIRE_EVERY: Duration = Duration::from_secs(1);
+
+/// `$XDG_RUNTIME_DIR/age.nvim/agent.sock`, falls back to `run`, Neovim's
+/// private `stdpath('run')`.
+pub(crate) fn default_socket(run: PathBuf) -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("age.nvim/agent.sock"),
+        _ => run.join("age.nvim/agent.sock"),
+    }
+}
+
+/// Refuses a socket in a directory someone else could have planted or can
+/// write to: it has to be a real directory (not a symlink), owned by us,
+/// with mode 0700.
+fn check_socket_dir(socket: &Path) -> Result<(), AgeError> {
+    let dir = match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let meta = std::fs::symlink_metadata(dir)?;
+    let uid = rustix::process::geteuid().as_raw();
+    if !meta.file_type().is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(AgeError::from(format!(
+            "refusing agent socket in {}, it must be a directory owned by you with mode 0700",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// The identities held by the agent, each with the time it expires.
+struct Keyring {
+    identities: Vec<(BoxedIdentity, Option<Instant>)>,
+    timeout: Option<Duration>,
+}
+
+impl Keyring {
+    /// adds `identities`, they expire `timeout` from now
+    fn add(&mut self, identities: Vec<BoxedIdentity>) {
+        let expires = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.identities
+            .extend(identities.into_iter().map(|identity| (identity, expires)));
+    }
+
+    /// drops the identities that outlived `timeout`
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.identities
+            .retain(|(_, expires)| expires.is_none_or(|expires| expires > now));
+    }
+}
+
+/// Handle to the agent running in this instance.
+#[derive(Debug)]
+pub(crate) struct Agent {
+    socket: PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Agent {
+    /// Binds `socket` and serves requests on a background thread.
+    ///
+    /// A stale socket left behind by a dead agent is replaced, a live one
+    /// is an error.
+    pub(crate) fn start(socket: PathBuf, timeout: Option<Duration>) -> Result<Self, AgeError> {
+        if let Some(parent) = socket.parent() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        check_socket_dir(&socket)?;
+
+        if socket.exists() {
+            if AgentClient::new(socket.clone()).ping().is_ok() {
+                return Err(AgeError::from(format!(
+                    "an agent is already running at {}",
+                    socket.display()
+                )));
+            }
+            std::fs::remove_file(&socket)?;
+        }
+
+        let listener = UnixListener::bind(&socket)?;
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let keyring = Arc::new(Mutex::new(Keyring {
+            identities: Vec::new(),
+            timeout,
+        }));
+
+        // expired keys leave memory even when nobody asks the agent
+        if let Some(timeout) = timeout {
+            let keyring = Arc::clone(&keyring);
+            let stopped = Arc::clone(&stopped);
+            std::thread::spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    std::thread::sleep(timeout.min(EXPIRE_EVERY));
+                    match keyring.lock() {
+                        Ok(mut keyring) => keyring.expire(),
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+
+        let thread_stopped = Arc::clone(&stopped);
+        let thread_socket = socket.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    // a misbehaving client must not take the agent down
+                    let _ = serve(stream, &keyring, &thread_stopped);
+                }
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+            let _ = std::fs::remove_file(thread_socket);
+        });
+
+        Ok(Self { socket, stopped })
+    }
+
+    pub(crate) fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        !self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        if self.is_running() {
+            let _ = AgentClient::new(self.socket.clone()).stop();
+        }
+    }
+}
+
+/// Handles a single connection.
+fn serve(
+    stream: UnixStream,
+    keyring: &Mutex<Keyring>,
+    stopped: &AtomicBool,
+) -> Result<(), AgeError> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+
+    let mut keyring = keyring
+        .lock()
+        .map_err(|_| AgeError::from("agent keyring is poisoned"))?;
+    keyring.expire();
+
+    let response = match words.next().unwrap_or_default() {
+        "ping" => format!("ok {}", keyring.identities.len()),
+        "add" => {
+            let contents = words.next().map(decode).transpose()?.unwrap_or_default();
+            let contents = SecretString::from(String::from_utf8(contents)?);
+            match crypt::parse_identities(contents.expose_secret()) {
+                Ok(identities) => {
+                    keyring.add(identities);
+                    format!("ok {}", keyring.identities.len())
+                }
+                Err(err) => format!("err {err}"),
+            }
+        }
+        "lock" => {
+            keyring.identities.clear();
+            "ok 0".to_owned()
+        }
+        "stop" => {
+            keyring.identities.clear();
+            stopped.store(true, Ordering::SeqCst);
+            "ok 0".to_owned()
+        }
+        "unwrap" => {
+            let stanzas = read_stanzas(&mut reader)?;
+            let unwrapped = keyring
+                .identities
+                .iter()
+                .find_map(|(identity, _)| identity.unwrap_stanzas(&stanzas));
+            match unwrapped {
+                Some(Ok(file_key)) => format!("ok {}", STANDARD.encode(file_key.expose_secret())),
+                Some(Err(err)) => format!("err {err}"),
+                None => "none".to_owned(),
+            }
+        }
+        other => format!("err unknown request `{other}`"),
+    };
+
+    writer.write_all(response.as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn read_stanzas(reader: &mut impl BufRead) -> Result<Vec<Stanza>, AgeError> {
+    let mut stanzas = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            return Ok(stanzas);
+        }
+
+        let mut words = line.split_whitespace();
+        let (Some("stanza"), Some(tag), Some(body)) = (words.next(), words.next(), words.next())
+        else {
+            return Err(AgeError::from("malformed stanza"));
+        };
+        stanzas.push(Stanza {
+            tag: tag.to_owned(),
+            args: words.map(str::to_owned).collect(),
+            body: decode(body)?,
+        });
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        "-".to_owned()
+    } else {
+        STANDARD.encode(bytes)
+    }
+}
+
+fn decode(word: &str) -> Result<Vec<u8>, AgeError> {
+    if word == "-" {
+        return Ok(Vec::new());
+    }
+    STANDARD
+        .decode(word)
+        .map_err(|err| AgeError::from(format!("bad base64 from agent: {err}")))
+}
+
+/// Talks to an agent, usually the one at `$AGE_NVIM_AGENT_SOCK`.
+pub(crate) struct AgentClient {
+    socket: PathBuf,
+}
+
+impl AgentClient {
+    pub(crate) fn new(socket: PathBuf) -> Self {
+        Self { socket }
+    }
+
+    pub(crate) fn from_env() -> Option<Self> {
+        std::env::var_os(SOCK_VAR)
+            .filter(|sock| !sock.is_empty())
+            .map(|sock| Self::new(sock.into()))
+    }
+
+    pub(crate) fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// number of identities held by the agent
+    pub(crate) fn ping(&self) -> Result<usize, AgeError> {
+        self.count(self.request("ping\n")?)
+    }
+
+    /// hands the contents of an (unlocked) identity file to the agent
+    pub(crate) fn add(&self, contents: &SecretString) -> Result<usize, AgeError> {
+        let request = format!("add {}\n", encode(contents.expose_secret().as_bytes()));
+        self.count(self.request(&request)?)
+    }
+
+    pub(crate) fn lock(&self) -> Result<(), AgeError> {
+        self.count(self.request("lock\n")?).map(|_| ())
+    }
+
+    pub(crate) fn stop(&self) -> Result<(), AgeError> {
+        self.count(self.request("stop\n")?).map(|_| ())
+    }
+
+    fn unwrap(&self, stanzas: &[Stanza]) -> Result<Option<FileKey>, AgeError> {
+        let mut request = "unwrap\n".to_owned();
+        for stanza in stanzas {
+            request.push_str(&format!("stanza {} {}", stanza.tag, encode(&stanza.body)));
+            for arg in &stanza.args {
+                request.push(' ');
+                request.push_str(arg);
+            }
+            request.push('\n');
+        }
+        request.push('\n');
+
+        let response = self.request(&request)?;
+        match response.split_once(' ') {
+            _ if response == "none" => Ok(None),
+            Some(("ok", file_key)) => {
+                let file_key = decode(file_key)?;
+                let file_key: [u8; 16] = file_key
+                    .try_into()
+                    .map_err(|_| AgeError::from("agent returned a malformed file key"))?;
+                Ok(Some(FileKey::new(Box::new(file_key))))
+            }
+            _ => Err(Self::error(response)),
+        }
+    }
+
+    fn request(&self, request: &str) -> Result<String, AgeError> {
+        check_socket_dir(&self.socket)?;
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        Ok(response.trim_end().to_owned())
+    }
+
+    fn count(&self, response: String) -> Result<usize, AgeError> {
+        match response.split_once(' ') {
+            Some(("ok", count)) => count
+                .parse()
+                .map_err(|_| AgeError::from("malformed response from agent")),
+            _ => Err(Self::error(response)),
+        }
+    }
+
+    fn error(response: String) -> AgeError {
+        AgeError::from(format!(
+            "agent: {}",
+            response.strip_prefix("err ").unwrap_or(&response)
+        ))
+    }
+}
+
+/// An `age::Identity` backed by the agent.
+///
+/// An unreachable agent or one without a matching key is treated as "no
+/// match", so the other identities still get their turn.
+pub(crate) struct AgentIdentity(AgentClient);
+
+impl AgentIdentity {
+    pub(crate) fn from_env() -> Option<Self> {
+        AgentClient::from_env().map(Self)
+    }
+}
+
+impl age::Identity for AgentIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        self.unwrap_stanzas(std::slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, age::DecryptError>> {
+        match self.0.unwrap(stanzas) {
+            Ok(Some(file_key)) => Some(Ok(file_key)),
+            Ok(None) | Err(_) => None,
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::time::Duration;
+
+    use age::secrecy::{ExposeSecret, SecretString};
+    use tempfile::TempDir;
+
+    use crate::agent::{Agent, AgentClient, AgentIdentity, Keyring};
+    use crate::crypt::{
+        decrypt_from_string_with, encrypt_to_string, parse_identities, BoxedIdentity,
+    };
+
+    // Layout:
+    //   <tmp>/
+    //     key.txt              <- generated age identity file
+    //     run/agent.sock       <- agent socket
+    struct Fixture {
+        dir: TempDir,
+        key: SecretString,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().expect("failed to create temp dir");
+            let identity = age::x25519::Identity::generate();
+            let key = identity.to_string();
+            std::fs::write(dir.path().join("key.txt"), key.expose_secret()).unwrap();
+
+            Self { dir, key }
+        }
+
+        fn socket(&self) -> std::path::PathBuf {
+            self.dir.path().join("run/agent.sock")
+        }
+
+        fn start(&self, timeout: Option<Duration>) -> Agent {
+            Agent::start(self.socket(), timeout).unwrap()
+        }
+
+        fn client(&self) -> AgentClient {
+            AgentClient::new(self.socket())
+        }
+
+        fn identity(&self) -> Vec<BoxedIdentity> {
+            vec![Box::new(AgentIdentity(self.client()))]
+        }
+
+        fn encrypted(&self, plaintext: &str) -> String {
+            let key_file = self.dir.path().join("key.txt");
+            encrypt_to_string(
+                plaintext.to_owned(),
+                vec![key_file.to_string_lossy().to_string()],
+            )
+            .unwrap()
+        }
+    }
+
+    #[test]
+    fn decrypts_through_agent() {
+        let f = Fixture::new();
+        let _agent = f.start(None);
+
+        assert_eq!(f.client().add(&f.key).unwrap(), 1);
+
+        let encrypted = f.encrypted("secret\n");
+        let decrypted = decrypt_from_string_with(encrypted, &f.identity()).unwrap();
+        assert_eq!(decrypted, "secret\n");
+    }
+
+    #[test]
+    fn locked_agent_cannot_decrypt() {
+        let f = Fixture::new();
+        let _agent = f.start(None);
+
+        f.client().add(&f.key).unwrap();
+        f.client().lock().unwrap();
+        assert_eq!(f.client().ping().unwrap(), 0);
+
+        let encrypted = f.encrypted("secret\n");
+        assert!(decrypt_from_string_with(encrypted, &f.identity()).is_err());
+    }
+
+    #[test]
+    fn identities_expire_after_timeout() {
+        let f = Fixture::new();
+        let _agent = f.start(Some(Duration::from_millis(50)));
+
+        f.client().add(&f.key).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(f.client().ping().unwrap(), 0);
+    }
+
+    #[test]
+    fn each_identity_expires_on_its_own() {
+        let f = Fixture::new();
+        let mut keyring = Keyring {
+            identities: Vec::new(),
+            timeout: Some(Duration::from_millis(100)),
+        };
+        keyring.add(parse_identities(f.key.expose_secret()).unwrap());
+        std::thread::sleep(Duration::from_millis(60));
+        keyring.add(parse_identities(f.key.expose_secret()).unwrap());
+        std::thread::sleep(Duration::from_millis(60));
+
+        // adding the second didn't extend the first
+        keyring.expire();
+        assert_eq!(keyring.identities.len(), 1);
+    }
+
+    #[test]
+    fn wrong_identity_is_no_match() {
+        let f = Fixture::new();
+        let other = Fixture::new();
+        let _agent = f.start(None);
+
+        f.client().add(&other.key).unwrap();
+
+        let encrypted = f.encrypted("secret\n");
+        assert!(decrypt_from_string_with(encrypted, &f.identity()).is_err());
+    }
+
+    #[test]
+    fn unreachable_agent_falls_back_to_other_identities() {
+        let f = Fixture::new();
+        let key_file = f.dir.path().join("key.txt");
+        let mut identities = f.identity(); // no agent started
+        identities.extend(
+            crate::crypt::parse_identities(&std::fs::read_to_string(key_file).unwrap()).unwrap(),
+        );
+
+        let encrypted = f.encrypted("secret\n");
+        let decrypted = decrypt_from_string_with(encrypted, &identities).unwrap();
+        assert_eq!(decrypted, "secret\n");
+    }
+
+    #[test]
+    fn add_rejects_garbage() {
+        let f = Fixture::new();
+        let _agent = f.start(None);
+
+        let result = f.client().add(&SecretString::from("not a key".to_owned()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn second_agent_on_same_socket_fails() {
+        let f = Fixture::new();
+        let _agent = f.start(None);
+
+        assert!(Agent::start(f.socket(), None).is_err());
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let f = Fixture::new();
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(f.socket().parent().unwrap())
+            .unwrap();
+        drop(std::os::unix::net::UnixListener::bind(f.socket()).unwrap());
+
+        let _agent = f.start(None);
+        assert_eq!(f.client().ping().unwrap(), 0);
+    }
+
+    #[test]
+    fn shared_socket_dirs_are_refused() {
+        let f = Fixture::new();
+        let shared = f.dir.path().join("shared");
+        std::fs::DirBuilder::new()
+            .mode(0o777)
+            .create(&shared)
+            .unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(Agent::start(shared.join("agent.sock"), None).is_err());
+        assert!(AgentClient::new(shared.join("agent.sock")).ping().is_err());
+
+        let _agent = f.start(None);
+        let link = f.dir.path().join("link");
+        std::os::unix::fs::symlink(f.socket().parent().unwrap(), &link).unwrap();
+        assert!(AgentClient::new(link.join("agent.sock")).ping().is_err());
+        assert_eq!(f.client().ping().unwrap(), 0);
+    }
+
+    #[test]
+    fn stop_removes_socket() {
+        let f = Fixture::new();
+        let agent = f.start(None);
+
+        drop(agent);
+        for _ in 0..50 {
+            if !f.socket().exists() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("socket was not removed");
+    }
+}
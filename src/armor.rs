@@ -0,0 +1,305 @@
+//! Finds ASCII armored age blocks in buffer lines.
+//!
+//! A block may be indented or behind a comment leader, as long as every line
+//! of it is:
+//!
+//! ```text
+//! # -----BEGIN AGE ENCRYPTED FILE-----
+//! # YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSA0MTJ6eFpNSkJzWWZQOGhp
+//! # -----END AGE ENCRYPTED FILE-----
+//! ```
+//!
+//! [`scan`] also reports blocks `age` would refuse: no or a truncated END
+//! line, and base64 lines that aren't 64 columns or don't decode.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+pub(crate) const BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+pub(crate) const END: &str = "-----END AGE ENCRYPTED FILE-----";
+
+/// Columns of every armor line but the last, as `age` writes and expects.
+const COLUMNS: usize = 64;
+
+/// An armored block, lines are 0-based and inclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    /// the armor without the leader, ready to decrypt
+    pub(crate) text: String,
+}
+
+/// All complete blocks in `lines`, in order.
+pub(crate) fn blocks<S: AsRef<str>>(lines: &[S]) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    // (first line, leader, armor lines so far)
+    let mut open: Option<(usize, &str, Vec<&str>)> = None;
+
+    for (row, line) in lines.iter().enumerate() {
+        let line = line.as_ref();
+
+        // a new BEGIN restarts an unterminated block
+        if let Some(col) = line.find(BEGIN) {
+            open = Some((row, &line[..col], vec![BEGIN]));
+            continue;
+        }
+
+        let Some((_, leader, armor)) = open.as_mut() else {
+            continue;
+        };
+        let content = line.strip_prefix(*leader).unwrap_or(line).trim();
+        armor.push(content);
+
+        if content == END {
+            if let Some((start, _, armor)) = open.take() {
+                blocks.push(Block {
+                    start,
+                    end: row,
+                    text: armor.join("\n"),
+                });
+            }
+        }
+    }
+
+    blocks
+}
+
+/// A block as [`scan`] found it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Found {
+    Block(Block),
+    /// broken armor, `line` is where it shows
+    Malformed {
+        line: usize,
+        reason: String,
+    },
+}
+
+/// All blocks in `lines`, in order, and those that are broken.
+pub(crate) fn scan<S: AsRef<str>>(lines: &[S]) -> Vec<Found> {
+    let mut found = Vec::new();
+    // (first line, leader, armor rows so far)
+    let mut open: Option<(usize, &str, Vec<usize>)> = None;
+    let content = |row: usize, leader: &str| {
+        let line = lines[row].as_ref();
+        line.strip_prefix(leader).unwrap_or(line).trim()
+    };
+
+    for (row, line) in lines.iter().enumerate() {
+        let line = line.as_ref();
+
+        if let Some(col) = line.find(BEGIN) {
+            if let Some((start, _, _)) = open.replace((row, &line[..col], Vec::new())) {
+                found.push(Found::Malformed {
+                    line: start,
+                    reason: "no END line before the next block".to_owned(),
+                });
+            }
+            continue;
+        }
+
+        let Some((start, leader, rows)) = open.as_mut() else {
+            continue;
+        };
+        let text = content(row, leader);
+        if text == END {
+            let (start, leader, rows) = (*start, *leader, std::mem::take(rows));
+            open = None;
+            let armor = rows
+                .iter()
+                .map(|row| content(*row, leader))
+                .collect::<Vec<_>>();
+            found.push(match malformed(&armor) {
+                Some((index, reason)) => Found::Malformed {
+                    line: rows.get(index).copied().unwrap_or(start),
+                    reason,
+                },
+                None => Found::Block(Block {
+                    start,
+                    end: row,
+                    text: [&[BEGIN], armor.as_slice(), &[END]].concat().join("\n"),
+                }),
+            });
+        } else if text.starts_with("-----") {
+            open = None;
+            found.push(Found::Malformed {
+                line: row,
+                reason: format!("truncated END line, expected `{END}`"),
+            });
+        } else {
+            rows.push(row);
+        }
+    }
+
+    if let Some((start, _, _)) = open {
+        found.push(Found::Malformed {
+            line: start,
+            reason: "no END line, the block is cut off".to_owned(),
+        });
+    }
+    found
+}
+
+/// The first broken line of the base64 in `armor`, and why.
+fn malformed(armor: &[&str]) -> Option<(usize, String)> {
+    let Some(last) = armor.len().checked_sub(1) else {
+        return Some((0, "the block is empty".to_owned()));
+    };
+    for (index, line) in armor.iter().enumerate() {
+        if line.len() > COLUMNS || (index < last && line.len() != COLUMNS) || line.is_empty() {
+            return Some((
+                index,
+                format!(
+                    "armor lines are {COLUMNS} columns, this one is {}",
+                    line.len()
+                ),
+            ));
+        }
+        if let Some(bad) = line
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=')))
+        {
+            return Some((index, format!("`{bad}` is not base64")));
+        }
+    }
+    BASE64_STANDARD
+        .decode(armor.concat())
+        .err()
+        .map(|err| (last, format!("bad base64: {err}")))
+}
+
+/// The block that contains `row`, if any.
+pub(crate) fn block_at<S: AsRef<str>>(lines: &[S], row: usize) -> Option<Block> {
+    blocks(lines)
+        .into_iter()
+        .find(|block| block.start <= row && row <= block.end)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use crate::armor::{block_at, blocks, scan, Found, BEGIN, END};
+
+    const LINE: &str = "YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSBzb21ldGhpbmcgc29tZXRo";
+
+    fn malformed(found: &[Found]) -> Vec<(usize, &str)> {
+        found
+            .iter()
+            .filter_map(|found| match found {
+                Found::Malformed { line, reason } => Some((*line, reason.as_str())),
+                Found::Block(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_plain_blocks() {
+        let lines = [
+            "before", BEGIN, "AAAA", "BBBB", END, "between", BEGIN, "CCCC", END,
+        ];
+
+        let found = blocks(&lines);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].start, found[0].end), (1, 4));
+        assert_eq!(found[0].text, format!("{BEGIN}\nAAAA\nBBBB\n{END}"));
+        assert_eq!((found[1].start, found[1].end), (6, 8));
+    }
+
+    #[test]
+    fn strips_indent_and_comment_leader() {
+        let lines = [
+            format!("  # {BEGIN}"),
+            "  # AAAA".to_owned(),
+            format!("  # {END}"),
+        ];
+
+        let block = blocks(&lines).pop().unwrap();
+        assert_eq!(block.text, format!("{BEGIN}\nAAAA\n{END}"));
+    }
+
+    #[test]
+    fn unterminated_block_is_ignored() {
+        let lines = [BEGIN, "AAAA", "no end"];
+        assert!(blocks(&lines).is_empty());
+    }
+
+    #[test]
+    fn second_begin_restarts() {
+        let lines = [BEGIN, "stale", BEGIN, "AAAA", END];
+
+        let found = blocks(&lines);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].start, 2);
+    }
+
+    #[test]
+    fn block_under_cursor() {
+        let lines = ["a", BEGIN, "AAAA", END, "b"];
+
+        assert!(block_at(&lines, 0).is_none());
+        assert_eq!(block_at(&lines, 1).unwrap().start, 1);
+        assert_eq!(block_at(&lines, 3).unwrap().end, 3);
+        assert!(block_at(&lines, 4).is_none());
+    }
+
+    #[test]
+    fn scan_finds_valid_blocks() {
+        let lines = [
+            format!("# {BEGIN}"),
+            format!("# {LINE}"),
+            "# aGVsbG8=".to_owned(),
+            format!("# {END}"),
+        ];
+
+        let found = scan(&lines);
+        assert_eq!(found.len(), 1);
+        let Found::Block(block) = &found[0] else {
+            panic!("{found:?}");
+        };
+        assert_eq!(block, &blocks(&lines)[0]);
+    }
+
+    #[test]
+    fn scan_reports_cut_off_blocks() {
+        let found = scan(&[BEGIN, LINE, "-----END AGE ENCRYPTED"]);
+        assert_eq!(
+            malformed(&found),
+            [(
+                2,
+                "truncated END line, expected `-----END AGE ENCRYPTED FILE-----`"
+            )]
+        );
+
+        let found = scan(&["before", BEGIN, LINE]);
+        assert_eq!(
+            malformed(&found),
+            [(1, "no END line, the block is cut off")]
+        );
+
+        let found = scan(&[BEGIN, LINE, BEGIN, "aGVsbG8=", END]);
+        assert_eq!(
+            malformed(&found),
+            [(0, "no END line before the next block")]
+        );
+        assert!(matches!(found[1], Found::Block(_)));
+    }
+
+    #[test]
+    fn scan_reports_bad_base64() {
+        let found = scan(&[BEGIN, "aGVsbG8=", LINE, END]);
+        assert_eq!(
+            malformed(&found),
+            [(1, "armor lines are 64 columns, this one is 8")]
+        );
+
+        let found = scan(&[BEGIN, "aGVs!G8=", END]);
+        assert_eq!(malformed(&found), [(1, "`!` is not base64")]);
+
+        let found = scan(&[BEGIN, "aGVsbG8", END]);
+        assert_eq!(malformed(&found)[0].0, 1);
+        assert!(malformed(&found)[0].1.starts_with("bad base64"));
+
+        let found = scan(&[BEGIN, END]);
+        assert_eq!(malformed(&found), [(0, "the block is empty")]);
+    }
+}
@@ -0,0 +1,266 @@
+//! `age-nvim`, the encryption of age.nvim outside of Neovim.
+//!
+//! Identities and recipients are found with the plugin's code (see
+//! `age_nvim::identity`), but outside of Neovim
+//! there is no `setup()`: `key_file`, `key_cmd` and `recipient_rules` are
+//! unknown here. Only the environment, the default key files, sidecars and
+//! `.age-recipients` are shared, see `HELP`.
+//!
+//! ```sh
+//!
+//! age-nvim decrypt [-i KEY]... [-o OUT] [FILE]
+//! age-nvim encrypt [-r RECIPIENT]... [-R FILE]... [-i KEY]... [-o OUT] [FILE]
+//! age-nvim textconv FILE      # git diff
+//! age-nvim clean [PATH]       # git filter, plaintext -> ciphertext
+//! age-nvim smudge [PATH]      # git filter, ciphertext -> plaintext
+//!
+//! ```
+//!
+//! `FILE` defaults to stdin and `-o` to stdout. Output is always armored.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use age_nvim::crypt::{self, BoxedIdentity, BoxedRecipient};
+use age_nvim::error::AgeError;
+use age_nvim::identity::{self, IdentitySource, KeyCmd};
+use age_nvim::types::expand_tilde;
+
+const USAGE: &str = "\
+usage: age-nvim decrypt [-i KEY]... [-o OUT] [FILE]
+       age-nvim encrypt [-r RECIPIENT]... [-R FILE]... [-i KEY]... [-o OUT] [FILE]
+       age-nvim textconv FILE
+       age-nvim clean [PATH]
+       age-nvim smudge [PATH]";
+
+const HELP: &str = "
+
+Without -i, the identity comes from $AGE_IDENTITY, $SOPS_AGE_KEY_FILE,
+$SOPS_AGE_KEY, ~/.config/sops/age/keys.txt or ~/.config/age/keys.txt, then
+the agent at $AGE_NVIM_AGENT_SOCK. Without -r/-R, recipients come from the
+file's sidecar, the nearest .age-recipients, else that identity.
+
+key_file, key_cmd and recipient_rules from the plugin's setup() are NOT
+read. To get the same keys as in Neovim, use $AGE_IDENTITY instead of
+key_file, and keep recipients in sidecars (:Age encrypt -R) or
+.age-recipients instead of recipient_rules.";
+
+/// The flags shared by all subcommands.
+#[derive(Debug, Default)]
+struct Args {
+    recipients: Vec<String>,
+    recipient_files: Vec<String>,
+    identities: Vec<String>,
+    output: Option<PathBuf>,
+    file: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, AgeError> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| AgeError::from(format!("{flag} needs a value")))
+            };
+            match arg.as_str() {
+                "-r" | "--recipient" => parsed.recipients.push(value(&arg)?),
+                "-R" | "--recipients-file" => parsed.recipient_files.push(value(&arg)?),
+                "-i" | "--identity" => parsed.identities.push(value(&arg)?),
+                "-o" | "--output" => parsed.output = Some(expand_tilde(value(&arg)?)),
+                flag if flag.starts_with('-') && flag != "-" => {
+                    return Err(AgeError::from(format!("unknown flag {flag}\n{USAGE}")));
+                }
+                _ if parsed.file.is_some() => {
+                    return Err(AgeError::from(format!("unexpected argument {arg}")));
+                }
+                file => parsed.file = Some(expand_tilde(file)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The contents of `FILE`, or stdin when it is missing or `-`.
+    fn input(&self) -> Result<Vec<u8>, AgeError> {
+        match self.file.as_deref() {
+            Some(path) if path != Path::new("-") => Ok(std::fs::read(path)?),
+            _ => stdin(),
+        }
+    }
+
+    /// Writes to `-o`, or stdout.
+    fn write(&self, contents: &[u8]) -> Result<(), AgeError> {
+        match &self.output {
+            Some(path) => Ok(std::fs::write(path, contents)?),
+            None => stdout(contents),
+        }
+    }
+
+    /// `-i` key files, or the discovered identity with the agent as a
+    /// last resort.
+    fn identities(&self) -> Result<Vec<BoxedIdentity>, AgeError> {
+        if !self.identities.is_empty() {
+            return crypt::load_identities(self.identities.clone());
+        }
+        discover()
+            .and_then(|source| source.identities())
+            .or_else(|err| crypt::agent_identity().map(|agent| vec![agent]).ok_or(err))
+    }
+
+    /// The recipients for `target`, resolved like `:Age encrypt` does,
+    /// minus the `recipient_rules` only `setup()` knows about.
+    fn recipients(&self, target: Option<&Path>) -> Result<Vec<BoxedRecipient>, AgeError> {
+        let mut explicit = self.recipients.clone();
+        for file in &self.recipient_files {
+            let contents = std::fs::read_to_string(expand_tilde(file))?;
+            explicit.extend(crypt::recipient_lines(&contents));
+        }
+        for key_file in &self.identities {
+            let contents = std::fs::read_to_string(expand_tilde(key_file))?;
+            explicit.extend(crypt::identity_public_keys(&contents)?);
+        }
+        identity::recipients_for(target, explicit)
+    }
+}
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("age-nvim: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), AgeError> {
+    let command = args.next().unwrap_or_default();
+    let args = Args::parse(args)?;
+
+    match command.as_str() {
+        "decrypt" | "d" => decrypt(&args),
+        "encrypt" | "e" => encrypt(&args),
+        "textconv" => textconv(&args),
+        "clean" => clean(&args),
+        "smudge" => smudge(&args),
+        "help" | "-h" | "--help" => {
+            println!("{USAGE}{HELP}");
+            Ok(())
+        }
+        "" => Err(AgeError::from(format!("missing command\n{USAGE}"))),
+        _ => Err(AgeError::from(format!(
+            "unknown command {command}\n{USAGE}"
+        ))),
+    }
+}
+
+fn decrypt(args: &Args) -> Result<(), AgeError> {
+    let ciphertext = args.input()?;
+    if crypt::is_passphrase_encrypted(&ciphertext) {
+        return Err(AgeError::from(
+            "the file is encrypted with a passphrase, use `age -d`",
+        ));
+    }
+    args.write(&crypt::decrypt_binary_with(
+        &ciphertext,
+        &args.identities()?,
+    )?)
+}
+
+fn encrypt(args: &Args) -> Result<(), AgeError> {
+    let plaintext = args.input()?;
+    // the sidecar and `.age-recipients` belong to where the ciphertext goes
+    let target = args.output.clone().or_else(|| {
+        args.file
+            .as_ref()
+            .filter(|file| file.as_path() != Path::new("-"))
+            .map(|file| file.with_added_extension("age"))
+    });
+    let recipients = args.recipients(target.as_deref())?;
+    args.write(&crypt::encrypt_bytes_with(&plaintext, &recipients)?)
+}
+
+/// `git diff` hands over a temporary copy of the blob, print it decrypted.
+fn textconv(args: &Args) -> Result<(), AgeError> {
+    if args.file.is_none() {
+        return Err(AgeError::from("textconv needs a file"));
+    }
+    decrypt(args)
+}
+
+/// Plaintext on stdin, ciphertext on stdout. When the version in the index
+/// decrypts to the same plaintext it is reused as is, age output is never
+/// the same twice and git would see every checked out file as modified.
+fn clean(args: &Args) -> Result<(), AgeError> {
+    let plaintext = stdin()?;
+    // already encrypted, eg: committed before the filter was set up
+    if is_ciphertext(&plaintext) {
+        return stdout(&plaintext);
+    }
+
+    let path = args.file.as_deref();
+    if let Some(staged) = path.and_then(index_blob) {
+        let unchanged = args
+            .identities()
+            .and_then(|identities| crypt::decrypt_binary_with(&staged, &identities))
+            .is_ok_and(|decrypted| decrypted == plaintext);
+        if unchanged {
+            return stdout(&staged);
+        }
+    }
+
+    let recipients = args.recipients(path)?;
+    stdout(&crypt::encrypt_bytes_with(&plaintext, &recipients)?)
+}
+
+/// Ciphertext on stdin, plaintext on stdout. Whatever can't be decrypted
+/// (no key on this machine, not age at all) is checked out unchanged.
+fn smudge(args: &Args) -> Result<(), AgeError> {
+    let ciphertext = stdin()?;
+    let decrypted = args
+        .identities()
+        .and_then(|identities| crypt::decrypt_binary_with(&ciphertext, &identities));
+
+    match decrypted {
+        Ok(plaintext) => stdout(&plaintext),
+        Err(err) => {
+            if is_ciphertext(&ciphertext) {
+                eprintln!("age-nvim: left encrypted: {err}");
+            }
+            stdout(&ciphertext)
+        }
+    }
+}
+
+fn stdin() -> Result<Vec<u8>, AgeError> {
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+    Ok(input)
+}
+
+fn stdout(contents: &[u8]) -> Result<(), AgeError> {
+    Ok(std::io::stdout().write_all(contents)?)
+}
+
+fn discover() -> Result<IdentitySource, AgeError> {
+    IdentitySource::discover("", &KeyCmd::default())
+}
+
+/// Armored or binary age.
+fn is_ciphertext(contents: &[u8]) -> bool {
+    contents.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+        || contents.starts_with(b"age-encryption.org/v1\n")
+}
+
+/// The staged version of `path`, relative to the work tree root where git
+/// runs filters.
+fn index_blob(path: &Path) -> Option<Vec<u8>> {
+    let output = std::process::Command::new("git")
+        .args(["cat-file", "blob"])
+        .arg(format!(":{}", path.display()))
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
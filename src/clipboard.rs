@@ -0,0 +1,182 @@
+//! Secrets copied to a register, used by `:Age yank` and `copy_secret()`.
+//!
+//! ```vim
+//!
+//! :Age yank " the current file
+//! :Age yank secrets/db.env.age 1 " only its first line
+//! :Age yank web/github 1 " a password store entry, like `pass -c`
+//!
+//! ```
+//!
+//! ```lua
+//!
+//! require('age').copy_secret("~/secrets/token.age", { line = 1, register = "+", timeout = 10 })
+//!
+//! ```
+//!
+//! The plaintext never goes through a buffer. After `clipboard_timeout`
+//! seconds the register gets its previous contents back, unless something
+//! else was copied to it in the meantime.
+
+use std::time::Duration;
+
+use nvim_oxi::conversion::FromObject;
+use nvim_oxi::{Array, Dictionary, Function};
+
+use crate::error::AgeError;
+
+/// The system clipboard.
+pub(crate) const DEFAULT_REGISTER: &str = "+";
+
+/// How long a copied secret stays in the register by default.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// What to copy, and where to.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Yank {
+    /// 1-based, the whole file when missing
+    pub(crate) line: Option<usize>,
+    pub(crate) register: Option<String>,
+    /// seconds, `clipboard_timeout` when missing
+    pub(crate) timeout: Option<i64>,
+}
+
+impl Yank {
+    /// `{ line = 1, register = "+", timeout = 45 }`, all optional.
+    pub(crate) fn from_dict(opts: &Dictionary) -> Self {
+        Yank {
+            line: opts
+                .get("line")
+                .and_then(|line| i64::from_object(line.clone()).ok())
+                .and_then(|line| usize::try_from(line).ok()),
+            register: opts
+                .get("register")
+                .and_then(|register| nvim_oxi::String::from_object(register.clone()).ok())
+                .map(|register| register.to_string())
+                .filter(|register| !register.is_empty()),
+            timeout: opts
+                .get("timeout")
+                .and_then(|timeout| i64::from_object(timeout.clone()).ok()),
+        }
+    }
+}
+
+/// `[file] [line]`, a trailing number is the line.
+pub(crate) fn parse_args(args: &[String]) -> Result<(Option<String>, Yank), AgeError> {
+    let line = |arg: &str| {
+        arg.parse::<usize>()
+            .map_err(|_| AgeError::from(format!("{arg} is not a line number")))
+    };
+    let (file, line) = match args {
+        [] => (None, None),
+        [arg] => match arg.parse::<usize>() {
+            Ok(number) => (None, Some(number)),
+            Err(_) => (Some(arg.clone()), None),
+        },
+        [file, number] => (Some(file.clone()), Some(line(number)?)),
+        _ => return Err(AgeError::from("usage: :Age yank [file] [line]")),
+    };
+    Ok((
+        file,
+        Yank {
+            line,
+            ..Yank::default()
+        },
+    ))
+}
+
+/// Seconds from the config or `copy_secret()`, `0` never restores.
+pub(crate) fn timeout(secs: i64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs.unsigned_abs()))
+}
+
+/// The whole `plaintext` without its final newline, or only line `line`.
+pub(crate) fn select(plaintext: &str, line: Option<usize>) -> Result<String, AgeError> {
+    let Some(line) = line else {
+        return Ok(plaintext.strip_suffix('\n').unwrap_or(plaintext).to_owned());
+    };
+    let lines = plaintext.lines().count();
+    line.checked_sub(1)
+        .and_then(|index| plaintext.lines().nth(index))
+        .map(str::to_owned)
+        .ok_or_else(|| AgeError::from(format!("there is no line {line}, only {lines}")))
+}
+
+/// Puts `secret` in `register` and gives the register its previous contents
+/// back after `timeout`, if it still holds the secret by then.
+pub(crate) fn copy(
+    register: &str,
+    secret: &str,
+    timeout: Option<Duration>,
+) -> Result<(), AgeError> {
+    let previous: Array = nvim_oxi::api::call_function("getreg", (register, 1, 1))?;
+    let previous_type: nvim_oxi::String = nvim_oxi::api::call_function("getregtype", (register,))?;
+    let _: i64 = nvim_oxi::api::call_function("setreg", (register, secret, "v"))?;
+
+    let Some(timeout) = timeout else {
+        return Ok(());
+    };
+    let (register, secret) = (register.to_owned(), secret.to_owned());
+    let restore = Function::<i64, Result<(), nvim_oxi::Error>>::from_fn_once(move |_timer| {
+        let current: nvim_oxi::String =
+            nvim_oxi::api::call_function("getreg", (register.as_str(),))?;
+        let current = current.to_string_lossy();
+        if current.strip_suffix('\n').unwrap_or(&current) == secret {
+            let _: i64 = nvim_oxi::api::call_function(
+                "setreg",
+                (register.as_str(), previous, previous_type),
+            )?;
+        }
+        Ok(())
+    });
+    let millis = i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX);
+    let _: i64 = nvim_oxi::api::call_function("timer_start", (millis, restore))?;
+    Ok(())
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::clipboard::{parse_args, select, timeout, Yank};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    #[test]
+    fn selects_the_file_or_a_line() {
+        let plaintext = "hunter2\nuser: me\n";
+        assert_eq!(select(plaintext, None).unwrap(), "hunter2\nuser: me");
+        assert_eq!(select(plaintext, Some(1)).unwrap(), "hunter2");
+        assert_eq!(select(plaintext, Some(2)).unwrap(), "user: me");
+        assert!(select(plaintext, Some(3)).is_err());
+        assert!(select(plaintext, Some(0)).is_err());
+        assert_eq!(select("no newline", None).unwrap(), "no newline");
+    }
+
+    #[test]
+    fn file_and_line_arguments() {
+        assert_eq!(parse_args(&[]).unwrap(), (None, Yank::default()));
+
+        let (file, yank) = parse_args(&args(&["2"])).unwrap();
+        assert_eq!((file, yank.line), (None, Some(2)));
+
+        let (file, yank) = parse_args(&args(&["web/github"])).unwrap();
+        assert_eq!((file.as_deref(), yank.line), (Some("web/github"), None));
+
+        let (file, yank) = parse_args(&args(&["db.env.age", "1"])).unwrap();
+        assert_eq!((file.as_deref(), yank.line), (Some("db.env.age"), Some(1)));
+
+        assert!(parse_args(&args(&["db.env.age", "first"])).is_err());
+        assert!(parse_args(&args(&["a", "1", "b"])).is_err());
+    }
+
+    #[test]
+    fn zero_never_restores() {
+        assert_eq!(timeout(45), Some(Duration::from_secs(45)));
+        assert_eq!(timeout(0), None);
+        assert_eq!(timeout(-1), None);
+    }
+}
@@ -1,14 +1,27 @@
-use std::path::Path;
+use std::cell::RefCell;
 
 use nvim_oxi::Function;
 
-use crate::error::AgeError;
+use crate::complete::{self, Completer, Kind};
+use crate::flags::{self, Value};
+use crate::pass::Store;
 
 #[derive(Debug)]
 pub enum Command {
     EncryptFile,
     DecryptFile,
     GenKey,
+    Agent,
+    Peek,
+    Recover,
+    Merge,
+    Pass,
+    Generate,
+    Yank,
+    Otp,
+    EncryptValues,
+    EncryptSections,
+    DecryptSections,
 }
 
 /// Parses a command and its argument from strings.
@@ -25,12 +38,45 @@ impl Command {
             "decrypt" => Some(Command::DecryptFile),
             "encrypt" => Some(Command::EncryptFile),
             "genkey" => Some(Command::GenKey),
+            "agent" => Some(Command::Agent),
+            "peek" => Some(Command::Peek),
+            "recover" => Some(Command::Recover),
+            "merge" => Some(Command::Merge),
+            "pass" => Some(Command::Pass),
+            "generate" => Some(Command::Generate),
+            "yank" => Some(Command::Yank),
+            "otp" => Some(Command::Otp),
+            "encrypt-values" => Some(Command::EncryptValues),
+            "encrypt-sections" => Some(Command::EncryptSections),
+            "decrypt-sections" => Some(Command::DecryptSections),
             _ => None,
         }
     }
+
+    /// The full name, as used in messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::EncryptFile => "encrypt",
+            Command::DecryptFile => "decrypt",
+            Command::GenKey => "genkey",
+            Command::Agent => "agent",
+            Command::Peek => "peek",
+            Command::Recover => "recover",
+            Command::Merge => "merge",
+            Command::Pass => "pass",
+            Command::Generate => "generate",
+            Command::Yank => "yank",
+            Command::Otp => "otp",
+            Command::EncryptValues => "encrypt-values",
+            Command::EncryptSections => "encrypt-sections",
+            Command::DecryptSections => "decrypt-sections",
+        }
+    }
 }
 
 pub fn completion() -> Function<(String, String, usize), Vec<String>> {
+    let completer = RefCell::new(Completer::default());
+
     Function::from_fn({
         move |args: (String, String, usize)| {
             let (arg_lead, cmd_line, _cursor_pos) = args;
@@ -38,7 +84,7 @@ pub fn completion() -> Function<(String, String, usize), Vec<String>> {
             //  ["Age", "encrypt", ""]
             //  arguments[0] = "Age"
             //  arguments[1] = "encrypt"
-            //  arguments[2..] = Otional file paths
+            //  arguments[2..] = flags and file paths
             let arguments: Vec<&str> = cmd_line.split_whitespace().collect();
 
             let is_first_arg = if cmd_line.ends_with(' ') {
@@ -48,82 +94,99 @@ pub fn completion() -> Function<(String, String, usize), Vec<String>> {
             };
 
             if is_first_arg {
-                let completions = vec!["decrypt".into(), "encrypt".into(), "genkey".into()];
+                let completions = vec![
+                    "decrypt".into(),
+                    "encrypt".into(),
+                    "genkey".into(),
+                    "agent".into(),
+                    "peek".into(),
+                    "recover".into(),
+                    "merge".into(),
+                    "pass".into(),
+                    "generate".into(),
+                    "yank".into(),
+                    "otp".into(),
+                    "encrypt-values".into(),
+                    "encrypt-sections".into(),
+                    "decrypt-sections".into(),
+                ];
 
                 return completions
                     .into_iter()
                     .filter(|c: &String| c.starts_with(&arg_lead))
                     .collect::<Vec<_>>();
             }
-            // should we provide file paths ???
-            let last_arg = arguments
-                .last()
-                .map(|s| s.to_string())
-                .unwrap_or("".to_owned());
-            get_key_files(last_arg).unwrap_or_default()
-        }
-    })
-}
 
-fn get_key_files(arg: String) -> Result<Vec<String>, AgeError> {
-    let input = match arg.starts_with('~') {
-        true => expand_tilde(arg).to_string_lossy().to_string(),
-        false => {
-            if arg.starts_with('/') {
-                arg
+            // the argument before the one being completed
+            let previous = if cmd_line.ends_with(' ') {
+                arguments.last()
             } else {
-                std::env::var("PWD")?
-            }
-        }
-    };
-
-    let mut key_files = Vec::new();
-
-    let walker = walkdir::WalkDir::new(input).into_iter();
-
-    for entry in walker.filter_entry(|e| !should_skip(e)) {
-        let filenane = entry?.path().to_path_buf();
-        let name = filenane
-            .file_name()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_default();
-        if name.ends_with("key.txt") || name.ends_with("keys.txt") {
-            key_files.push(filenane.to_string_lossy().to_string());
-        }
-    }
-    Ok(key_files)
-}
+                arguments.iter().rev().nth(1)
+            };
 
-fn should_skip(entry: &walkdir::DirEntry) -> bool {
-    for blacklisted in [".git", ".cargo", ".cache", "node_modules", ".DS_Store"] {
-        let s = entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with(blacklisted))
-            .unwrap_or(false);
-        if s {
-            return true;
-        }
-    }
+            let Some(command) = arguments.get(1).and_then(|c| Command::from_str(c)) else {
+                return Vec::new();
+            };
 
-    false
-}
+            if let Command::Agent = command {
+                if previous == arguments.get(1) {
+                    return ["start", "add", "lock", "stop", "status"]
+                        .into_iter()
+                        .filter(|c| c.starts_with(&arg_lead))
+                        .map(|c| c.to_owned())
+                        .collect();
+                }
+                if arguments.get(2) == Some(&"add") {
+                    return completer.borrow_mut().complete(&arg_lead, Kind::Key);
+                }
+                return Vec::new();
+            }
 
-fn expand_tilde<P: AsRef<Path>>(path: P) -> std::path::PathBuf {
-    let p = path.as_ref();
-    if !p.starts_with("~") {
-        return p.to_path_buf();
-    }
+            if let Command::Pass = command {
+                if previous == arguments.get(1) {
+                    return ["ls", "show", "edit", "insert", "rm", "mv"]
+                        .into_iter()
+                        .filter(|c| c.starts_with(&arg_lead))
+                        .map(|c| c.to_owned())
+                        .collect();
+                }
+                return Store::from_env()
+                    .dir_path("")
+                    .map(|root| completer.borrow_mut().complete_pass(&root, &arg_lead))
+                    .unwrap_or_default();
+            }
 
-    if let Ok(home_dir) = std::env::var("HOME") {
-        if p == Path::new("~") {
-            return home_dir.into();
-        }
+            if arg_lead.starts_with('-') {
+                return flags::FLAGS
+                    .iter()
+                    .filter(|flag| flag.allowed(&command))
+                    .flat_map(|flag| [flag.short, flag.long])
+                    .filter(|name| name.starts_with(&arg_lead))
+                    .map(|name| name.to_owned())
+                    .collect();
+            }
 
-        if let Ok(suffix) = p.strip_prefix("~") {
-            return Path::new(&home_dir).join(suffix);
+            match previous.and_then(|p| flags::find(p)).and_then(|f| f.value) {
+                Some(Value::Recipient | Value::Text) => Vec::new(),
+                Some(Value::File) => completer.borrow_mut().complete(&arg_lead, Kind::Key),
+                Some(Value::Output) => complete::paths(&arg_lead),
+                None => match command {
+                    Command::DecryptFile
+                    | Command::Peek
+                    | Command::Merge
+                    | Command::Yank
+                    | Command::Otp => completer.borrow_mut().complete(&arg_lead, Kind::Encrypted),
+                    Command::EncryptFile | Command::EncryptSections | Command::DecryptSections => {
+                        completer.borrow_mut().complete(&arg_lead, Kind::Key)
+                    }
+                    Command::GenKey
+                    | Command::Agent
+                    | Command::Recover
+                    | Command::Pass
+                    | Command::Generate
+                    | Command::EncryptValues => Vec::new(),
+                },
+            }
         }
-    }
-
-    p.to_path_buf()
+    })
 }
@@ -0,0 +1,375 @@
+//! File candidates for `:Age` completion, and entries for `:Age pass`.
+//!
+//! The directory typed so far is searched at most [`MAX_DEPTH`] levels deep,
+//! skipping hidden and `.gitignore`d files. Results are cached for a few
+//! seconds, so pressing `<Tab>` again while typing doesn't walk the tree again.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::types::expand_tilde;
+
+/// How many directory levels below the typed directory are searched.
+const MAX_DEPTH: usize = 4;
+/// Stop walking after this many entries, so huge trees can't hang the editor.
+const MAX_ENTRIES: usize = 5_000;
+/// At most this many candidates are offered.
+const MAX_RESULTS: usize = 200;
+/// How long a search is reused.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Names of files that hold identities or recipients.
+const KEY_FILES: [&str; 6] = [
+    "key.txt",
+    "keys.txt",
+    "identity.txt",
+    "identities.txt",
+    "recipients.txt",
+    ".agekey",
+];
+
+/// Which files are offered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// `.age` files, to decrypt
+    Encrypted,
+    /// identity and recipient files
+    Key,
+}
+
+impl Kind {
+    fn matches(self, path: &Path) -> bool {
+        match self {
+            Kind::Encrypted => path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("age")),
+            Kind::Key => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .is_some_and(|name| KEY_FILES.iter().any(|key| name.ends_with(key))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Search {
+    root: PathBuf,
+    kind: Kind,
+    at: Instant,
+    /// matching files, relative to `root`, shallowest first
+    files: Vec<String>,
+    /// directories directly in `root`, to continue completing into
+    dirs: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Completer {
+    ttl: Duration,
+    last: Option<Search>,
+}
+
+impl Default for Completer {
+    fn default() -> Self {
+        Completer {
+            ttl: CACHE_TTL,
+            last: None,
+        }
+    }
+}
+
+impl Completer {
+    /// Candidates of `kind` for `lead`, relative to the working directory.
+    pub(crate) fn complete(&mut self, lead: &str, kind: Kind) -> Vec<String> {
+        match std::env::current_dir() {
+            Ok(cwd) => self.complete_in(&cwd, lead, kind),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Candidates of `kind` for `lead`, with relative leads taken from `base`.
+    ///
+    /// Candidates keep the directory as typed, `sub/` completes to
+    /// `sub/deeper/key.txt` and `~/` stays `~/`.
+    fn complete_in(&mut self, base: &Path, lead: &str, kind: Kind) -> Vec<String> {
+        let (dir, prefix) = split_lead(lead);
+        let search = self.search(base.join(expand_tilde(dir)), kind);
+
+        let files = search.files.iter().filter(|file| {
+            file.starts_with(prefix)
+                || Path::new(file)
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(prefix))
+        });
+        let dirs = search.dirs.iter().filter(|d| d.starts_with(prefix));
+
+        files
+            .chain(dirs)
+            .take(MAX_RESULTS)
+            .map(|candidate| format!("{dir}{candidate}"))
+            .collect()
+    }
+
+    /// Entries of the pass store at `root` that start with `lead`, named
+    /// like `:Age pass` takes them, eg: `email/work`.
+    pub(crate) fn complete_pass(&mut self, root: &Path, lead: &str) -> Vec<String> {
+        self.search(root.to_path_buf(), Kind::Encrypted)
+            .files
+            .iter()
+            .filter_map(|file| file.strip_suffix(".age"))
+            .filter(|name| name.starts_with(lead))
+            .take(MAX_RESULTS)
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// The last search if it's still fresh, otherwise a new one.
+    fn search(&mut self, root: PathBuf, kind: Kind) -> &Search {
+        let fresh = self
+            .last
+            .as_ref()
+            .is_some_and(|s| s.root == root && s.kind == kind && s.at.elapsed() < self.ttl);
+        match self.last.take() {
+            Some(last) if fresh => self.last.insert(last),
+            _ => self.last.insert(search(root, kind)),
+        }
+    }
+}
+
+/// Any path under the directory of `lead` that starts with what is typed,
+/// used for `-o` where the file may not exist yet.
+pub(crate) fn paths(lead: &str) -> Vec<String> {
+    let (dir, prefix) = split_lead(lead);
+    let search = match dir {
+        "" => PathBuf::from("."),
+        dir => expand_tilde(dir),
+    };
+    let Ok(entries) = std::fs::read_dir(search) else {
+        return Vec::new();
+    };
+
+    let mut paths = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{dir}{name}{slash}"))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+/// Splits `lead` into the directory typed so far, with its trailing `/`,
+/// and the start of a name.
+fn split_lead(lead: &str) -> (&str, &str) {
+    match lead.rfind('/') {
+        Some(i) => lead.split_at(i + 1),
+        None => ("", lead),
+    }
+}
+
+fn search(root: PathBuf, kind: Kind) -> Search {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+
+    let walker = ignore::WalkBuilder::new(&root)
+        .max_depth(Some(MAX_DEPTH))
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    for entry in walker.filter_map(|entry| entry.ok()).take(MAX_ENTRIES) {
+        let Ok(relative) = entry.path().strip_prefix(&root) else {
+            continue;
+        };
+        let relative_str = relative.to_string_lossy().to_string();
+        let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+
+        if is_dir && entry.depth() == 1 {
+            dirs.push(format!("{relative_str}/"));
+        } else if !is_dir && kind.matches(relative) {
+            files.push((entry.depth(), relative_str));
+        }
+    }
+
+    files.sort();
+    Search {
+        root,
+        kind,
+        at: Instant::now(),
+        files: files.into_iter().map(|(_, file)| file).collect(),
+        dirs,
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use crate::complete::{split_lead, Completer, Kind, MAX_DEPTH};
+
+    fn tree(files: &[&str]) -> TempDir {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    fn complete(base: &Path, lead: &str, kind: Kind) -> Vec<String> {
+        Completer::default().complete_in(base, lead, kind)
+    }
+
+    #[test]
+    fn split_lead_keeps_trailing_slash() {
+        assert_eq!(split_lead("sub/ke"), ("sub/", "ke"));
+        assert_eq!(split_lead("sub/"), ("sub/", ""));
+        assert_eq!(split_lead("ke"), ("", "ke"));
+        assert_eq!(split_lead("/abs/k"), ("/abs/", "k"));
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(Kind::Encrypted.matches(Path::new("a/secret.txt.age")));
+        assert!(Kind::Encrypted.matches(Path::new("SECRET.AGE")));
+        assert!(!Kind::Encrypted.matches(Path::new("age.txt")));
+
+        assert!(Kind::Key.matches(Path::new("keys.txt")));
+        assert!(Kind::Key.matches(Path::new("team-recipients.txt")));
+        assert!(Kind::Key.matches(Path::new("me.agekey")));
+        assert!(!Kind::Key.matches(Path::new("notes.txt")));
+    }
+
+    #[test]
+    fn encrypted_files_shallowest_first() {
+        let dir = tree(&["b/deep.age", "top.age", "notes.txt", "keys.txt"]);
+
+        let found = complete(dir.path(), "", Kind::Encrypted);
+        assert_eq!(found, ["top.age", "b/deep.age", "b/"]);
+    }
+
+    #[test]
+    fn key_files() {
+        let dir = tree(&["keys.txt", "age/recipients.txt", "secret.age"]);
+
+        let found = complete(dir.path(), "", Kind::Key);
+        assert_eq!(found, ["keys.txt", "age/recipients.txt", "age/"]);
+    }
+
+    #[test]
+    fn relative_lead_keeps_its_directory() {
+        let dir = tree(&["sub/one.age", "sub/deeper/two.age", "other.age"]);
+
+        assert_eq!(
+            complete(dir.path(), "sub/", Kind::Encrypted),
+            ["sub/one.age", "sub/deeper/two.age", "sub/deeper/"]
+        );
+        assert_eq!(
+            complete(dir.path(), "sub/tw", Kind::Encrypted),
+            ["sub/deeper/two.age"]
+        );
+        assert_eq!(complete(dir.path(), "oth", Kind::Encrypted), ["other.age"]);
+    }
+
+    #[test]
+    fn absolute_lead() {
+        let dir = tree(&["keys.txt"]);
+        let lead = format!("{}/", dir.path().display());
+
+        let found = complete(Path::new("/nowhere"), &lead, Kind::Key);
+        assert_eq!(found, [format!("{lead}keys.txt")]);
+    }
+
+    #[test]
+    fn respects_gitignore() {
+        let dir = tree(&["kept.age", "build/ignored.age", "skip.age"]);
+        fs::write(dir.path().join(".gitignore"), "build/\nskip.age\n").unwrap();
+
+        assert_eq!(complete(dir.path(), "", Kind::Encrypted), ["kept.age"]);
+    }
+
+    #[test]
+    fn skips_hidden() {
+        let dir = tree(&[".git/objects/x.age", ".hidden.age", "shown.age"]);
+
+        assert_eq!(complete(dir.path(), "", Kind::Encrypted), ["shown.age"]);
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let within = format!("{}x.age", "d/".repeat(MAX_DEPTH - 1));
+        let beyond = format!("{}y.age", "d/".repeat(MAX_DEPTH));
+        let dir = tree(&[&within, &beyond]);
+
+        assert_eq!(
+            complete(dir.path(), "", Kind::Encrypted),
+            [within, "d/".to_owned()]
+        );
+    }
+
+    #[test]
+    fn results_are_cached() {
+        let dir = tree(&["one.age"]);
+        let mut completer = Completer::default();
+
+        assert_eq!(
+            completer.complete_in(dir.path(), "", Kind::Encrypted),
+            ["one.age"]
+        );
+        fs::write(dir.path().join("two.age"), "").unwrap();
+        assert_eq!(
+            completer.complete_in(dir.path(), "o", Kind::Encrypted),
+            ["one.age"]
+        );
+
+        // another kind or directory searches again
+        assert!(completer.complete_in(dir.path(), "", Kind::Key).is_empty());
+        assert_eq!(
+            completer.complete_in(dir.path(), "", Kind::Encrypted),
+            ["one.age", "two.age"]
+        );
+    }
+
+    #[test]
+    fn pass_entries() {
+        let dir = tree(&[
+            "web/github.age",
+            "email.age",
+            ".age-recipients",
+            "notes.txt",
+        ]);
+        let mut completer = Completer::default();
+
+        assert_eq!(
+            completer.complete_pass(dir.path(), ""),
+            ["email", "web/github"]
+        );
+        assert_eq!(completer.complete_pass(dir.path(), "we"), ["web/github"]);
+    }
+
+    #[test]
+    fn cache_expires() {
+        let dir = tree(&["one.age"]);
+        let mut completer = Completer {
+            ttl: Duration::ZERO,
+            last: None,
+        };
+
+        completer.complete_in(dir.path(), "", Kind::Encrypted);
+        fs::write(dir.path().join("two.age"), "").unwrap();
+        assert_eq!(
+            completer.complete_in(dir.path(), "", Kind::Encrypted),
+            ["one.age", "two.age"]
+        );
+    }
+}
@@ -7,19 +7,102 @@
 //!  config = function()
 //!    require('age').setup({
 //!      key_file = vim.fn.expand("~/.config/sops/age/keys.txt"),
+//!      -- or read the identity from a password manager instead
+//!      -- key_cmd = { "pass", "show", "age/key" },
+//!      -- key_cmd_cache = true, -- keep the output in memory for the session
 //!      encrypt_and_del = true,
+//!      -- seconds the identity agent keeps unlocked keys, 0 = until locked
+//!      agent_timeout = 900,
+//!      -- where `:Age peek` shows the plaintext, "float" or "split"
+//!      peek = "float",
+//!      -- seconds before `:Age yank` restores the register, 0 = never
+//!      clipboard_timeout = 45,
+//!      -- copy plaintext that `:Age decrypt` overwrites to `<file>~`
+//!      backup = true,
+//!      backup_ext = "~",
+//!      -- encrypted recovery journals instead of swap files for decrypted buffers
+//!      recovery = true,
+//!      -- keep undo history across sessions, encrypted like the file
+//!      undo = true,
+//!      -- values `:Age encrypt-values` encrypts in YAML, JSON and TOML files,
+//!      -- matched against the key or the dotted path, all strings when empty
+//!      encrypted_keys = { "password", "*_token", "stringData.*" },
+//!      -- Markdown and Org sections tagged `:crypt:` or `<!-- age -->` are
+//!      -- decrypted on read and written encrypted
+//!      auto_sections = true,
+//!      -- `vim.diagnostic` errors for broken armored blocks, warnings for
+//!      -- blocks none of your identities can decrypt
+//!      diagnostics = true,
+//!      -- recipients for files without a sidecar, first match wins
+//!      recipient_rules = {
+//!        { pattern = "secrets/prod/*", recipients_file = "~/team/prod.txt" },
+//!        { pattern = "*.env.age", recipients = { "age1..." } },
+//!      },
+//!      -- called after `User AgeDecryptPost` / `AgeEncryptPost` with the same data
+//!      on_decrypt = function(data) end,
+//!      on_encrypt = function(data) end,
 //!    })
 //!  end
 //!
 //! ```
 
+use std::time::Duration;
+
 use nvim_oxi::String;
-use nvim_oxi::{conversion::FromObject, Dictionary};
+use nvim_oxi::{conversion::FromObject, Array, Dictionary, Function};
+
+use crate::clipboard;
+use crate::identity::KeyCmd;
+use crate::peek::PeekStyle;
+use crate::recipients::Rule;
+
+/// How long the identity agent keeps unlocked keys by default.
+const DEFAULT_AGENT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Appended to the name of plaintext files backed up before overwriting.
+const DEFAULT_BACKUP_EXT: &str = "~";
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Config {
     pub key_file: String,
+    pub key_cmd: KeyCmd,
     pub encrypt_and_del: bool,
+    pub agent_timeout: Option<Duration>,
+    pub peek: PeekStyle,
+    pub clipboard_timeout: Option<Duration>,
+    pub backup: bool,
+    pub backup_ext: std::string::String,
+    pub recovery: bool,
+    pub undo: bool,
+    pub recipient_rules: Vec<Rule>,
+    pub encrypted_keys: Vec<std::string::String>,
+    pub auto_sections: bool,
+    pub diagnostics: bool,
+    pub on_decrypt: Option<Function<Dictionary, ()>>,
+    pub on_encrypt: Option<Function<Dictionary, ()>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            key_file: String::default(),
+            key_cmd: KeyCmd::default(),
+            encrypt_and_del: false,
+            agent_timeout: Some(DEFAULT_AGENT_TIMEOUT),
+            peek: PeekStyle::default(),
+            clipboard_timeout: Some(clipboard::DEFAULT_TIMEOUT),
+            backup: true,
+            backup_ext: DEFAULT_BACKUP_EXT.to_owned(),
+            recovery: true,
+            undo: true,
+            recipient_rules: Vec::new(),
+            encrypted_keys: Vec::new(),
+            auto_sections: false,
+            diagnostics: true,
+            on_decrypt: None,
+            on_encrypt: None,
+        }
+    }
 }
 
 impl Config {
@@ -30,10 +113,136 @@ impl Config {
                 .and_then(|key_file_obj| String::from_object(key_file_obj.clone()).ok())
                 .unwrap_or_else(|| "".into()),
 
+            key_cmd: KeyCmd::new(
+                options
+                    .get("key_cmd")
+                    .and_then(|key_cmd| Array::from_object(key_cmd.clone()).ok())
+                    .map(|argv| {
+                        argv.into_iter()
+                            .filter_map(|arg| String::from_object(arg).ok())
+                            .map(|arg| arg.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                options
+                    .get("key_cmd_cache")
+                    .and_then(|cache| bool::from_object(cache.clone()).ok())
+                    .unwrap_or(true),
+            ),
+
             encrypt_and_del: options
                 .get("encrypt_and_del")
                 .and_then(|encrypt_and_del| bool::from_object(encrypt_and_del.clone()).ok())
                 .unwrap_or(false),
+
+            agent_timeout: options
+                .get("agent_timeout")
+                .and_then(|timeout| i64::from_object(timeout.clone()).ok())
+                .map_or(Some(DEFAULT_AGENT_TIMEOUT), |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs.unsigned_abs()))
+                }),
+
+            peek: options
+                .get("peek")
+                .and_then(|peek| String::from_object(peek.clone()).ok())
+                .and_then(|peek| PeekStyle::from_str(&peek.to_string()))
+                .unwrap_or_default(),
+
+            clipboard_timeout: options
+                .get("clipboard_timeout")
+                .and_then(|timeout| i64::from_object(timeout.clone()).ok())
+                .map_or(Some(clipboard::DEFAULT_TIMEOUT), clipboard::timeout),
+
+            backup: options
+                .get("backup")
+                .and_then(|backup| bool::from_object(backup.clone()).ok())
+                .unwrap_or(true),
+
+            backup_ext: options
+                .get("backup_ext")
+                .and_then(|ext| String::from_object(ext.clone()).ok())
+                .map(|ext| ext.to_string())
+                .filter(|ext| !ext.is_empty())
+                .unwrap_or_else(|| DEFAULT_BACKUP_EXT.to_owned()),
+
+            recovery: options
+                .get("recovery")
+                .and_then(|recovery| bool::from_object(recovery.clone()).ok())
+                .unwrap_or(true),
+
+            undo: options
+                .get("undo")
+                .and_then(|undo| bool::from_object(undo.clone()).ok())
+                .unwrap_or(true),
+
+            recipient_rules: options
+                .get("recipient_rules")
+                .and_then(|rules| Array::from_object(rules.clone()).ok())
+                .map(|rules| {
+                    rules
+                        .into_iter()
+                        .filter_map(|rule| Dictionary::from_object(rule).ok())
+                        .filter_map(|rule| rule_from_dict(&rule))
+                        .collect()
+                })
+                .unwrap_or_default(),
+
+            encrypted_keys: options
+                .get("encrypted_keys")
+                .and_then(|keys| Array::from_object(keys.clone()).ok())
+                .map(|keys| {
+                    keys.into_iter()
+                        .filter_map(|key| String::from_object(key).ok())
+                        .map(|key| key.to_string())
+                        .filter(|key| !key.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+
+            auto_sections: options
+                .get("auto_sections")
+                .and_then(|auto| bool::from_object(auto.clone()).ok())
+                .unwrap_or(false),
+
+            diagnostics: options
+                .get("diagnostics")
+                .and_then(|diagnostics| bool::from_object(diagnostics.clone()).ok())
+                .unwrap_or(true),
+
+            on_decrypt: options
+                .get("on_decrypt")
+                .and_then(|callback| Function::from_object(callback.clone()).ok()),
+
+            on_encrypt: options
+                .get("on_encrypt")
+                .and_then(|callback| Function::from_object(callback.clone()).ok()),
         }
     }
 }
+
+/// `{ pattern = "...", recipients = { ... }, recipients_file = "..." }`,
+/// entries without a pattern are ignored.
+fn rule_from_dict(rule: &Dictionary) -> Option<Rule> {
+    let string = |key: &str| {
+        rule.get(key)
+            .and_then(|value| String::from_object(value.clone()).ok())
+            .map(|value| value.to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    Some(Rule {
+        pattern: string("pattern")?,
+        recipients: rule
+            .get("recipients")
+            .and_then(|recipients| Array::from_object(recipients.clone()).ok())
+            .map(|recipients| {
+                recipients
+                    .into_iter()
+                    .filter_map(|recipient| String::from_object(recipient).ok())
+                    .map(|recipient| recipient.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        recipients_file: string("recipients_file"),
+    })
+}
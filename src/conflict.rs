@@ -0,0 +1,160 @@
+//! What to do when `:Age decrypt` would overwrite an existing plaintext file.
+//!
+//! ```vim
+//!
+//! :Age decrypt  " asks: overwrite, diff, write elsewhere or cancel
+//! :Age! decrypt " overwrites without asking
+//!
+//! ```
+//!
+//! Overwritten files are copied to `<file><backup_ext>` first unless
+//! `backup = false`.
+//!
+//! `:Age encrypt` asks the same way, minus writing elsewhere, when the
+//! `.age` file changed on disk since it was decrypted.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::AgeError;
+
+/// The user's answer to an overwrite conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Choice {
+    Overwrite,
+    Diff,
+    Rename,
+    Cancel,
+}
+
+impl Choice {
+    /// Choices for `confirm()`, in the order of [`Choice::from_confirm`].
+    pub(crate) const BUTTONS: &'static str = "&Overwrite\n&Diff\n&Write elsewhere\n&Cancel";
+
+    /// Choices for `confirm()` when the `.age` file changed on disk, in the
+    /// order of [`Choice::from_changed`].
+    pub(crate) const CHANGED_BUTTONS: &'static str = "&Overwrite\n&Diff\n&Cancel";
+
+    /// Maps the return value of `confirm()` with [`Choice::CHANGED_BUTTONS`].
+    pub(crate) fn from_changed(answer: i64) -> Self {
+        match answer {
+            1 => Choice::Overwrite,
+            2 => Choice::Diff,
+            _ => Choice::Cancel,
+        }
+    }
+
+    /// Maps the return value of `confirm()`, `0` is `<Esc>`.
+    pub(crate) fn from_confirm(answer: i64) -> Self {
+        match answer {
+            1 => Choice::Overwrite,
+            2 => Choice::Diff,
+            3 => Choice::Rename,
+            _ => Choice::Cancel,
+        }
+    }
+}
+
+/// A path next to `path` that doesn't exist yet, `notes.txt` becomes
+/// `notes.decrypted.txt`, then `notes.decrypted-2.txt` and so on.
+pub(crate) fn alternate_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut n = 1;
+    loop {
+        let suffix = if n == 1 {
+            String::new()
+        } else {
+            format!("-{n}")
+        };
+        let candidate = path.with_file_name(format!("{stem}.decrypted{suffix}{ext}"));
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Copies `path` to `<path><ext>`, replacing an older backup.
+pub(crate) fn backup(path: &Path, ext: &str) -> Result<PathBuf, AgeError> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(ext);
+    let backup = PathBuf::from(name);
+
+    fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::conflict::{alternate_path, backup, Choice};
+
+    #[test]
+    fn confirm_answers() {
+        assert_eq!(Choice::from_confirm(1), Choice::Overwrite);
+        assert_eq!(Choice::from_confirm(2), Choice::Diff);
+        assert_eq!(Choice::from_confirm(3), Choice::Rename);
+        assert_eq!(Choice::from_confirm(4), Choice::Cancel);
+        // <Esc>
+        assert_eq!(Choice::from_confirm(0), Choice::Cancel);
+    }
+
+    #[test]
+    fn changed_answers() {
+        assert_eq!(Choice::from_changed(1), Choice::Overwrite);
+        assert_eq!(Choice::from_changed(2), Choice::Diff);
+        assert_eq!(Choice::from_changed(3), Choice::Cancel);
+        assert_eq!(Choice::from_changed(0), Choice::Cancel);
+    }
+
+    #[test]
+    fn alternate_path_is_free() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "").unwrap();
+
+        let first = alternate_path(&path);
+        assert_eq!(first, dir.path().join("notes.decrypted.txt"));
+
+        fs::write(&first, "").unwrap();
+        assert_eq!(
+            alternate_path(&path),
+            dir.path().join("notes.decrypted-2.txt")
+        );
+    }
+
+    #[test]
+    fn alternate_path_without_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            alternate_path(&dir.path().join("secret")),
+            dir.path().join("secret.decrypted")
+        );
+    }
+
+    #[test]
+    fn backup_copies_and_replaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+
+        fs::write(&path, "first").unwrap();
+        let saved = backup(&path, "~").unwrap();
+        assert_eq!(saved, dir.path().join("notes.txt~"));
+        assert_eq!(fs::read_to_string(&saved).unwrap(), "first");
+
+        fs::write(&path, "second").unwrap();
+        backup(&path, "~").unwrap();
+        assert_eq!(fs::read_to_string(&saved).unwrap(), "second");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+    }
+}
@@ -1,19 +1,68 @@
-use age::secrecy::ExposeSecret;
+use age::secrecy::{ExposeSecret, SecretString};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use nvim_oxi::api::opts::BufDeleteOpts;
-use nvim_oxi::{print, Dictionary, Result as OxiResult};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use nvim_oxi::api::opts::{
+    BufDeleteOpts, ClearAutocmdsOpts, CreateAugroupOpts, CreateAutocmdOpts, OptionOpts,
+};
+use nvim_oxi::api::types::RegisterType;
+use nvim_oxi::{print, Array, Dictionary, Object, Result as OxiResult};
 
+use crate::agent::{self, Agent, AgentClient};
+use crate::armor;
+use crate::clipboard::{self, Yank};
 use crate::command::Command;
-use crate::crypt::{decrypt_to_file, decrypt_to_string};
+use crate::config::Config;
+use crate::conflict::{self, Choice};
+use crate::crypt::{
+    self, decrypt_to_string, decrypt_to_string_with, BoxedIdentity, BoxedRecipient,
+};
 use crate::error::AgeError;
-use crate::types::{ExistingAgeFile, ExistingNonAgeFile};
-use crate::{config::Config, crypt::encrypt_to_file};
+use crate::events::{self, Event, EventData};
+use crate::flags::Flags;
+use crate::generate;
+use crate::identity::IdentitySource;
+use crate::lock::{self, Owner};
+use crate::merge;
+use crate::otp::Totp;
+use crate::pass::{self, Store};
+use crate::peek;
+use crate::recipients::{self, Origin, RecipientSet};
+use crate::recovery;
+use crate::sections::{self, Syntax};
+use crate::state::{self, Buffers, Values};
+use crate::structured::{self, Format, Secret};
+use crate::types::{expand_tilde, ExistingAgeFile, ExistingNonAgeFile};
+use crate::undo;
+
+/// Identities and where they come from.
+type Identities = (Vec<BoxedIdentity>, Vec<String>);
 
 #[derive(Debug)]
 pub struct App {
     config: Config,
+    /// the identity agent, when this instance runs it
+    agent: RefCell<Option<Agent>>,
+    /// buffers that came from, or were saved to, an `.age` file
+    buffers: RefCell<Buffers>,
+    /// lock files held for decrypted buffers, by buffer handle
+    locks: RefCell<HashMap<i32, PathBuf>>,
+    /// YAML, JSON and TOML buffers with `ENC[age,...]` values, and
+    /// Markdown or Org buffers with encrypted sections
+    values: RefCell<Values>,
+    /// buffers with sections in the clear, sealed on every write
+    decrypted: RefCell<HashMap<i32, Syntax>>,
+    /// sections encrypted for a write: the edits that bring them back, and
+    /// whether they were left out rather than encrypted
+    sealed: RefCell<HashMap<i32, (Vec<sections::Edit>, bool)>>,
+    /// armored blocks already checked for diagnostics, and whether an
+    /// identity unwraps them
+    unwrappable: RefCell<HashMap<String, bool>>,
 }
 
 impl App {
@@ -21,7 +70,16 @@ impl App {
     ///
     /// This function initializes the application state with the specified `Config`.
     pub fn new(config: Config) -> Self {
-        App { config }
+        App {
+            config,
+            agent: RefCell::new(None),
+            buffers: RefCell::new(Buffers::default()),
+            locks: RefCell::new(HashMap::new()),
+            values: RefCell::new(Values::default()),
+            decrypted: RefCell::new(HashMap::new()),
+            sealed: RefCell::new(HashMap::new()),
+            unwrappable: RefCell::new(HashMap::new()),
+        }
     }
 
     /// Sets up the application with the provided options from a `Dictionary`.
@@ -31,6 +89,8 @@ impl App {
     pub fn setup(&mut self, dict: Dictionary) -> OxiResult<()> {
         let config = Config::from_dict(dict);
         self.config = config;
+        // the identities may have changed
+        self.unwrappable.borrow_mut().clear();
         Ok(())
     }
 
@@ -39,19 +99,25 @@ impl App {
     /// Based on the command and argument passed, the corresponding action (such as
     /// setting the font or closing the window) is performed.
     pub fn handle_command(
-        &mut self,
+        &self,
         cmd: Command,
         raw_args: Vec<String>,
+        bang: bool,
     ) -> Result<(), crate::error::AgeError> {
-        let filenames = if raw_args.is_empty() {
-            vec![self.config.key_file.to_string()]
-        } else {
-            raw_args
-        };
-
         match &cmd {
+            // ```vim
+            //
+            // :Age decrypt " uses the discovered identity
+            // :Age decrypt -i /path/to/keys.txt -o /path/to/out.txt
+            // :Age! decrypt " overwrite the plaintext without asking
+            //
+            // ```
             Command::DecryptFile => {
-                if let Err(err) = self.decrypt_current_file(filenames) {
+                let result = Flags::parse(&cmd, raw_args).and_then(|mut flags| {
+                    flags.force = bang;
+                    self.decrypt_current_file(flags)
+                });
+                if let Err(err) = result {
                     print!("{}", err);
                 }
                 Ok(())
@@ -59,17 +125,178 @@ impl App {
             // ```vim
             //
             // :Age encrypt " uses public key from config
-            // :Age encrypt /path/to/recipents.txt " list for public keys
+            // :Age encrypt /path/to/keys.txt " list for public keys
+            // :Age encrypt -r age1... -R /path/to/recipients.txt -o out.age
+            // :Age encrypt --passphrase
+            // :Age! encrypt " don't ask when recipients would be dropped
             //
             // ```
             Command::EncryptFile => {
-                if let Err(err) = self.encrypt_current_file(filenames) {
+                let result = Flags::parse(&cmd, raw_args).and_then(|mut flags| {
+                    flags.force = bang;
+                    self.encrypt_current_file(flags)
+                });
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age agent start " serve identities to other instances
+            // :Age agent add " unlock the configured identity into the agent
+            // :Age agent add /path/to/keys.txt
+            // :Age agent lock " forget all identities
+            // :Age agent stop
+            // :Age agent status
+            //
+            // ```
+            Command::Agent => {
+                if let Err(err) = self.agent_command(raw_args) {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age peek " armored block under the cursor, or the current file
+            // :Age peek /path/to/secret.txt.age
+            //
+            // ```
+            Command::Peek => {
+                let result = Flags::parse(&cmd, raw_args).and_then(|flags| self.peek(flags));
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age recover " restore unsaved edits lost in a crash
+            //
+            // ```
+            Command::Recover => {
+                if let Err(err) = self.recover() {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age merge " three-way diff of a conflicted file, `:w` stages it
+            // :Age merge secrets/db.env.age
+            //
+            // ```
+            Command::Merge => {
+                if let Err(err) = self.merge(raw_args) {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age pass " list the passage store
+            // :Age pass show email/work
+            // :Age pass edit email/work " `:w` encrypts it
+            // :Age pass insert email/work
+            // :Age pass rm email/work
+            // :Age pass mv email/work email/old
+            // :Age! pass rm email " don't ask
+            //
+            // ```
+            Command::Pass => {
+                if let Err(err) = self.pass_command(raw_args, bang) {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age generate " a password at the cursor
+            // :Age generate 32 --charset alnum
+            // :Age generate --words 6
+            // :Age generate -o ~/.passage/store/web/github.age
+            //
+            // ```
+            Command::Generate => {
+                let result = Flags::parse(&cmd, raw_args).and_then(|mut flags| {
+                    flags.force = bang;
+                    self.generate(flags)
+                });
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age yank " the current file to the clipboard
+            // :Age yank secrets/db.env.age 1 " its first line only
+            // :Age yank web/github 1 " a password store entry
+            //
+            // ```
+            Command::Yank => {
+                let result = clipboard::parse_args(&raw_args)
+                    .and_then(|(file, yank)| self.copy_secret(file, yank));
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age otp " the current TOTP code of the current file
+            // :Age otp accounts/github.otp.age
+            // :Age! otp web/github " copied to the clipboard instead
+            //
+            // ```
+            Command::Otp => {
+                if let Err(err) = self.otp(raw_args, bang) {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age encrypt-values " write with `encrypted_keys` values encrypted
+            // :Age encrypt-values -r age1... -R /path/to/recipients.txt
+            // :Age encrypt-values -o /path/to/other.yaml
+            //
+            // ```
+            Command::EncryptValues => {
+                let result =
+                    Flags::parse(&cmd, raw_args).and_then(|flags| self.encrypt_values(flags));
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            // ```vim
+            //
+            // :Age encrypt-sections " headings tagged :crypt: or <!-- age -->
+            // :Age encrypt-sections -R /path/to/recipients.txt
+            // :Age decrypt-sections
+            // :Age decrypt-sections -i /path/to/keys.txt
+            //
+            // ```
+            Command::EncryptSections => {
+                let result =
+                    Flags::parse(&cmd, raw_args).and_then(|flags| self.encrypt_sections(flags));
+                if let Err(err) = result {
+                    print!("{}", err);
+                }
+                Ok(())
+            }
+            Command::DecryptSections => {
+                let result =
+                    Flags::parse(&cmd, raw_args).and_then(|flags| self.decrypt_sections(flags));
+                if let Err(err) = result {
                     print!("{}", err);
                 }
                 Ok(())
             }
             Command::GenKey => {
-                let re = self.gen_new_key();
+                let re = Flags::parse(&cmd, raw_args).and_then(|flags| self.gen_new_key(flags));
                 if let Err(err) = re {
                     print!("{}", err);
                 }
@@ -78,7 +305,420 @@ impl App {
         }
     }
 
-    fn gen_new_key(&self) -> Result<(), AgeError> {
+    /// Finds where the default identity comes from.
+    ///
+    /// See [`IdentitySource`] for the lookup order.
+    fn identity_source(&self) -> Result<IdentitySource, AgeError> {
+        IdentitySource::discover(&self.config.key_file.to_string(), &self.config.key_cmd)
+    }
+
+    /// Identities from the key files given on the command line,
+    /// or from the discovered identity if none are given.
+    ///
+    /// With an agent around, a missing or locked identity is not an error,
+    /// the agent may still hold the key.
+    fn identities(&self, key_files: Vec<String>) -> Result<Vec<BoxedIdentity>, AgeError> {
+        self.named_identities(key_files)
+            .map(|(identities, _)| identities)
+    }
+
+    /// `identities`, and where they come from for `status()`. The source
+    /// is resolved once, so `key_cmd` doesn't run twice.
+    fn named_identities(&self, key_files: Vec<String>) -> Result<Identities, AgeError> {
+        if !key_files.is_empty() {
+            return Ok((crypt::load_identities(key_files.clone())?, key_files));
+        }
+        self.identity_source()
+            .and_then(|source| Ok((source.identities()?, vec![source.to_string()])))
+            .or_else(|err| {
+                crypt::agent_identity()
+                    .map(|agent| (vec![agent], vec!["agent".to_owned()]))
+                    .ok_or(err)
+            })
+    }
+
+    /// Identities at hand without running `key_cmd` or asking for anything:
+    /// key files, `$SOPS_AGE_KEY`, a cached `key_cmd` output or the agent.
+    fn quiet_identities(&self) -> Result<Vec<BoxedIdentity>, AgeError> {
+        IdentitySource::discover_cached(&self.config.key_file.to_string(), &self.config.key_cmd)
+            .and_then(|source| source.identities())
+            .or_else(|err| crypt::agent_identity().map(|agent| vec![agent]).ok_or(err))
+    }
+
+    /// Identities that can decrypt `ciphertext`, files made with
+    /// `age --passphrase` ask for the passphrase instead.
+    fn identities_for(
+        &self,
+        ciphertext: &[u8],
+        name: &str,
+        key_files: Vec<String>,
+    ) -> Result<Vec<BoxedIdentity>, AgeError> {
+        self.named_identities_for(ciphertext, name, key_files)
+            .map(|(identities, _)| identities)
+    }
+
+    /// `identities_for`, with the names of `named_identities`.
+    fn named_identities_for(
+        &self,
+        ciphertext: &[u8],
+        name: &str,
+        key_files: Vec<String>,
+    ) -> Result<Identities, AgeError> {
+        if crypt::is_passphrase_encrypted(ciphertext) {
+            let passphrase = prompt_passphrase(&format!("Passphrase for {}: ", name))?;
+            return Ok((
+                vec![crypt::passphrase_identity(passphrase)],
+                vec!["passphrase".to_owned()],
+            ));
+        }
+        self.named_identities(key_files)
+    }
+
+    /// Asks for a new passphrase twice, for `--passphrase`. Like `age`, an
+    /// empty one is autogenerated and shown.
+    fn new_passphrase(&self) -> Result<BoxedRecipient, AgeError> {
+        let passphrase = prompt_passphrase("Passphrase (leave empty to autogenerate): ")?;
+        if passphrase.expose_secret().is_empty() {
+            let generated = generate::passphrase(generate::DEFAULT_WORDS)?;
+            print!("Using an autogenerated passphrase: {generated}");
+            return Ok(crypt::passphrase_recipient(generated.into()));
+        }
+        let confirm = prompt_passphrase("Confirm passphrase: ")?;
+        if passphrase.expose_secret() != confirm.expose_secret() {
+            return Err(AgeError::from("passphrases didn't match"));
+        }
+        Ok(crypt::passphrase_recipient(passphrase))
+    }
+
+    /// The recipients `target` is encrypted to, from `-r`, `-R` and `-i`
+    /// (or plain key files) when given.
+    ///
+    /// See [`recipients`] for the lookup order otherwise.
+    fn recipient_set(&self, flags: &Flags, target: &Path) -> Result<RecipientSet, AgeError> {
+        self.resolve_recipients(flags, target, || self.identity_source())
+    }
+
+    /// The recipients of `target` without running `key_cmd` or prompting,
+    /// the identity is only a fallback when its source is at hand.
+    fn quiet_recipient_set(&self, target: &Path) -> Result<RecipientSet, AgeError> {
+        self.resolve_recipients(&Flags::default(), target, || {
+            IdentitySource::discover_cached(&self.config.key_file.to_string(), &self.config.key_cmd)
+        })
+    }
+
+    fn resolve_recipients(
+        &self,
+        flags: &Flags,
+        target: &Path,
+        source: impl FnOnce() -> Result<IdentitySource, AgeError>,
+    ) -> Result<RecipientSet, AgeError> {
+        let mut explicit = flags.recipients.clone();
+        for file in &flags.recipient_files {
+            explicit.extend(recipients::read_recipients_file(&expand_tilde(file))?);
+        }
+        for file in &flags.identities {
+            explicit.extend(crypt::identity_public_keys(&fs::read_to_string(
+                crypt::get_full_path(file)?,
+            )?)?);
+        }
+
+        let identity = || {
+            let source = source()?;
+            Ok(RecipientSet::new(
+                source.public_keys()?,
+                Origin::Identity(source.to_string()),
+            ))
+        };
+
+        // the password store has its own rules, see [`pass`]
+        let store = Store::from_env();
+        if explicit.is_empty() && store.contains(target) {
+            return store.recipients(target, identity);
+        }
+
+        recipients::resolve(target, explicit, &self.config.recipient_rules, identity)
+    }
+
+    /// Asks before opening `age_file` when another instance has it open,
+    /// `false` when the user backs out. With `force` it is only a warning.
+    fn confirm_lock(&self, age_file: &Path, force: bool) -> Result<bool, AgeError> {
+        match lock::check(age_file, &Owner::current())? {
+            lock::Status::Free | lock::Status::Ours => Ok(true),
+            lock::Status::Stale => {
+                print!("Removed a stale lock of {}", age_file.display());
+                Ok(true)
+            }
+            lock::Status::Held(owner) => {
+                let message = format!("{} is being edited by {owner}.", age_file.display());
+                if force {
+                    nvim_oxi::api::err_writeln(&format!("age.nvim: {message}"));
+                    return Ok(true);
+                }
+                let answer: i64 = nvim_oxi::api::call_function(
+                    "confirm",
+                    (message, "&Open anyway\n&Cancel", 2, "Warning"),
+                )?;
+                Ok(answer == 1)
+            }
+        }
+    }
+
+    /// Locks `age_file` while `buf` is loaded. A lock of another instance,
+    /// opened anyway, is left to it and not taken over.
+    fn lock(&self, buf: &nvim_oxi::api::Buffer, age_file: &Path) -> Result<(), AgeError> {
+        let owner = Owner::current();
+        let Some(path) = lock::acquire(age_file, &owner)? else {
+            return Ok(());
+        };
+        let previous = self.locks.borrow_mut().insert(buf.handle(), path.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != path) {
+            lock::release(&previous, &owner)?;
+        }
+        Ok(())
+    }
+
+    /// Releases the lock held for `bufnr`, eg: when it is unloaded.
+    pub fn release_lock(&self, bufnr: i32) -> Result<(), AgeError> {
+        let path = self.locks.borrow_mut().remove(&bufnr);
+        match path {
+            Some(path) => lock::release(&path, &Owner::current()),
+            None => Ok(()),
+        }
+    }
+
+    /// Releases every lock, eg: when Neovim exits.
+    pub fn release_locks(&self) -> Result<(), AgeError> {
+        let owner = Owner::current();
+        for (_, path) in self.locks.borrow_mut().drain() {
+            lock::release(&path, &owner)?;
+        }
+        Ok(())
+    }
+
+    /// Asks before overwriting `target` when it changed on disk since `buf`
+    /// was decrypted from it. `false` when the user backs out or diffs, the
+    /// new version is then shown in a diff split.
+    fn confirm_unchanged(
+        &self,
+        buf: &nvim_oxi::api::Buffer,
+        target: &Path,
+    ) -> Result<bool, AgeError> {
+        let tracked = self
+            .buffers
+            .borrow()
+            .get(buf.handle())
+            .is_some_and(|state| state.source == target);
+        if !tracked || !target.exists() {
+            return Ok(true);
+        }
+
+        let mtime = modified(target);
+        let changed = self
+            .buffers
+            .borrow()
+            .changed_on_disk(buf.handle(), mtime, || fs::read(target).unwrap_or_default());
+        if !changed {
+            return Ok(true);
+        }
+
+        let answer: i64 = nvim_oxi::api::call_function(
+            "confirm",
+            (
+                format!(
+                    "{} changed on disk since it was decrypted.",
+                    target.display()
+                ),
+                Choice::CHANGED_BUTTONS,
+                3,
+                "Warning",
+            ),
+        )?;
+        match Choice::from_changed(answer) {
+            Choice::Overwrite => Ok(true),
+            Choice::Diff => {
+                let ciphertext = fs::read(target)?;
+                let identities =
+                    self.identities_for(&ciphertext, &target.display().to_string(), vec![])?;
+                let theirs = crypt::decrypt_bytes_with(&ciphertext, &identities)?;
+
+                let scratch = peek::scratch(&theirs)?;
+                nvim_oxi::api::command("vertical rightbelow split")?;
+                nvim_oxi::api::get_current_win().set_buf(&scratch)?;
+                nvim_oxi::api::command("diffthis | wincmd p | diffthis")?;
+
+                // seen now, the next encrypt doesn't ask again
+                self.buffers
+                    .borrow_mut()
+                    .stamp(buf.handle(), &ciphertext, mtime);
+                print!("The new version is on the right, `:Age encrypt` again once merged");
+                Ok(false)
+            }
+            Choice::Rename | Choice::Cancel => Ok(false),
+        }
+    }
+
+    /// Warns before re-encrypting `target` to fewer recipients than it has
+    /// now, or without keys from its sidecar. `false` when the user backs out,
+    /// with `force` the warning is only shown.
+    fn confirm_recipients(
+        &self,
+        target: &Path,
+        new: &[String],
+        count: usize,
+        force: bool,
+    ) -> Result<bool, AgeError> {
+        let mut warnings = Vec::new();
+
+        if target.exists() {
+            let before = crypt::recipient_count(&fs::read(target)?).unwrap_or_default();
+            if before > count {
+                warnings.push(format!(
+                    "{} is encrypted to {before} recipients, it would be re-encrypted to {count}.",
+                    target.display()
+                ));
+            }
+        }
+
+        let sidecar = recipients::sidecar_path(target);
+        if sidecar.is_file() {
+            let dropped = recipients::missing(&recipients::read_recipients_file(&sidecar)?, new);
+            if !dropped.is_empty() {
+                warnings.push(format!(
+                    "No longer encrypted to (from {}):\n  {}",
+                    sidecar.display(),
+                    dropped.join("\n  ")
+                ));
+            }
+        }
+
+        if warnings.is_empty() {
+            return Ok(true);
+        }
+        let message = warnings.join("\n");
+        if force {
+            nvim_oxi::api::err_writeln(&format!("age.nvim: {message}"));
+            return Ok(true);
+        }
+
+        let answer: i64 = nvim_oxi::api::call_function(
+            "confirm",
+            (message, "&Encrypt anyway\n&Cancel", 2, "Warning"),
+        )?;
+        Ok(answer == 1)
+    }
+
+    fn agent_command(&self, args: Vec<String>) -> Result<(), AgeError> {
+        let mut args = args.into_iter();
+        let action = args.next().unwrap_or_else(|| "status".to_owned());
+
+        if action == "start" {
+            let socket = match AgentClient::from_env() {
+                Some(client) => client.socket().to_path_buf(),
+                None => {
+                    let run: String = nvim_oxi::api::call_function("stdpath", ("run",))?;
+                    agent::default_socket(run.into())
+                }
+            };
+            let agent = Agent::start(socket, self.config.agent_timeout)?;
+
+            // inherited by `:terminal` and everything else started from here
+            std::env::set_var(agent::SOCK_VAR, agent.socket());
+            print!(
+                "age agent listening, export {}={}",
+                agent::SOCK_VAR,
+                agent.socket().display()
+            );
+            self.agent.replace(Some(agent));
+            return Ok(());
+        }
+
+        let client = AgentClient::from_env().ok_or(AgeError::from(format!(
+            "${} is not set, run `:Age agent start` first",
+            agent::SOCK_VAR
+        )))?;
+
+        match action.as_str() {
+            "add" => {
+                let key_files = args.collect::<Vec<_>>();
+                let raw = if key_files.is_empty() {
+                    let source = self.identity_source()?;
+                    vec![(source.to_string(), source.raw()?)]
+                } else {
+                    key_files
+                        .into_iter()
+                        .map(|file| Ok((file.clone(), fs::read(crypt::get_full_path(&file)?)?)))
+                        .collect::<Result<Vec<_>, AgeError>>()?
+                };
+
+                let mut count = 0;
+                for (name, raw) in raw {
+                    count = client.add(&self.unlock_identity(&name, raw)?)?;
+                }
+                print!("age agent holds {} identities", count);
+            }
+            "lock" => {
+                client.lock()?;
+                print!("age agent locked");
+            }
+            "stop" => {
+                client.stop()?;
+                if self.agent.take().is_some() {
+                    std::env::remove_var(agent::SOCK_VAR);
+                }
+                print!("age agent stopped");
+            }
+            "status" => {
+                let count = client.ping()?;
+                print!(
+                    "age agent at {} holds {} identities",
+                    client.socket().display(),
+                    count
+                );
+            }
+            other => return Err(AgeError::from(format!("Unknown agent command: {other}"))),
+        }
+        Ok(())
+    }
+
+    /// Turns the contents of an identity file into an unlocked identity file,
+    /// asking for the passphrase if it is passphrase-protected.
+    fn unlock_identity(&self, name: &str, raw: Vec<u8>) -> Result<SecretString, AgeError> {
+        let plaintext = if crypt::is_passphrase_encrypted(&raw) {
+            let passphrase = prompt_passphrase(&format!("Passphrase for {}: ", name))?;
+            crypt::decrypt_with_passphrase(&raw, passphrase)?
+        } else {
+            raw
+        };
+
+        Ok(String::from_utf8(plaintext)?.into())
+    }
+
+    /// Reports the state of the plugin for `:checkhealth age`.
+    pub fn health(&self) -> Dictionary {
+        let mut report = Dictionary::new();
+        match self.identity_source() {
+            Ok(source) => {
+                report.insert("identity_source", Object::from(source.to_string()));
+                if let Err(err) = source.identities() {
+                    report.insert("identity_error", Object::from(err.to_string()));
+                }
+            }
+            Err(err) => report.insert("identity_error", Object::from(err.to_string())),
+        }
+        if let Some(client) = AgentClient::from_env() {
+            report.insert(
+                "agent_socket",
+                Object::from(client.socket().display().to_string()),
+            );
+            match client.ping() {
+                Ok(count) => report.insert("agent_identities", Object::from(count as i64)),
+                Err(err) => report.insert("agent_error", Object::from(err.to_string())),
+            }
+        }
+        report
+    }
+
+    fn gen_new_key(&self, flags: Flags) -> Result<(), AgeError> {
         let key = age::x25519::Identity::generate();
         let time = chrono::Local::now();
         let formatted_time = time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
@@ -90,7 +730,10 @@ impl App {
             key.to_string().expose_secret()
         );
 
-        let path = current_dir()?.join("key.txt");
+        let path = match flags.output {
+            Some(output) => expand_tilde(output),
+            None => current_dir()?.join("key.txt"),
+        };
 
         std::fs::write(path.as_path(), contents)?;
         nvim_oxi::print!("Generated key file: {}", path.display());
@@ -98,93 +741,1435 @@ impl App {
         Ok(())
     }
 
-    fn decrypt_current_file(&self, filenames: Vec<String>) -> Result<(), AgeError> {
-        let current_file_bufnr = nvim_oxi::api::get_current_buf();
-        let current_file_path = current_file_bufnr.get_name()?;
+    /// Puts a random password or passphrase at the cursor, or encrypts it
+    /// into a new file with `-o`, see [`generate`].
+    fn generate(&self, flags: Flags) -> Result<(), AgeError> {
+        let secret = match flags.words {
+            Some(words) => generate::passphrase(words)?,
+            None => generate::password(
+                flags.length.unwrap_or(generate::DEFAULT_LENGTH),
+                &generate::charset(
+                    flags
+                        .charset
+                        .as_deref()
+                        .unwrap_or(generate::DEFAULT_CHARSET),
+                )?,
+            )?,
+        };
+
+        let Some(output) = &flags.output else {
+            nvim_oxi::api::put(
+                [secret.as_str()].into_iter(),
+                RegisterType::Charwise,
+                true,
+                true,
+            )?;
+            return Ok(());
+        };
+
+        let path = expand_tilde(output);
+        if path.exists() && !flags.force {
+            return Err(AgeError::from(format!(
+                "{} already exists, `:Age! generate` overwrites it",
+                path.display()
+            )));
+        }
+        let set = self.recipient_set(&flags, &path)?;
+        let recipients = set.load()?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        crypt::encrypt_bytes_to_file_with(format!("{secret}\n").as_bytes(), &path, &recipients)?;
+        if set.origin == Origin::Flags {
+            recipients::write_sidecar(&path, &set.recipients)?;
+        }
+        print!(
+            "Generated {} for {} recipients ({})",
+            path.display(),
+            recipients.len(),
+            set.origin
+        );
+        Ok(())
+    }
+
+    fn decrypt_current_file(&self, flags: Flags) -> Result<(), AgeError> {
+        // `:Age decrypt file.age` leaves the current buffer alone
+        let (current_file_bufnr, current_file_path) = match flags.input {
+            Some(input) => (None, expand_tilde(input)),
+            None => {
+                let bufnr = nvim_oxi::api::get_current_buf();
+                let path = bufnr.get_name()?;
+                (Some(bufnr), path)
+            }
+        };
         let current_file = ExistingAgeFile::try_from(current_file_path)?;
 
-        let out_path = current_file.strip_age();
+        // without a swap file only the lock tells about other instances
+        if !self.confirm_lock(current_file.path(), flags.force)? {
+            return Ok(());
+        }
 
-        if out_path.as_path().exists() {
-            fs::remove_file(out_path.as_path())?;
+        let mut data = EventData::default().path(current_file.path());
+        if let Some(bufnr) = &current_file_bufnr {
+            data = data.bufnr(bufnr.handle());
         }
+        self.emit(Event::DecryptPre, &data)?;
 
-        decrypt_to_file(current_file.path(), out_path.as_path(), filenames)?;
+        let ciphertext = fs::read(current_file.path())?;
+        let (identities, identity_names) =
+            self.named_identities_for(&ciphertext, &current_file.to_string(), flags.identities)?;
+        let plaintext = crypt::decrypt_bytes_with(&ciphertext, &identities)?;
 
-        let new_scratch_buf = nvim_oxi::api::create_buf(false, true)?;
-        nvim_oxi::api::set_current_buf(&new_scratch_buf)?;
+        let mut out_path = match flags.output {
+            Some(output) => expand_tilde(output),
+            None => current_file.strip_age(),
+        };
 
-        let opts = BufDeleteOpts::builder()
-            .force(true) // Force deletion, ignoring unsaved changes
-            .build();
+        // never clobber local edits to the plaintext silently
+        let mut diff = false;
+        let differs = out_path.exists() && fs::read(&out_path)? != plaintext.as_bytes();
+        if differs && !flags.force {
+            let answer: i64 = nvim_oxi::api::call_function(
+                "confirm",
+                (
+                    format!("{} already exists and differs.", out_path.display()),
+                    Choice::BUTTONS,
+                    4,
+                ),
+            )?;
+            match Choice::from_confirm(answer) {
+                Choice::Overwrite => {}
+                Choice::Diff => diff = true,
+                Choice::Rename => {
+                    let alternate = conflict::alternate_path(&out_path);
+                    let answer: nvim_oxi::String = nvim_oxi::api::call_function(
+                        "input",
+                        ("Write to: ", alternate.display().to_string(), "file"),
+                    )?;
+                    if answer.is_empty() {
+                        return Ok(());
+                    }
+                    out_path = expand_tilde(answer.to_string());
+                    if out_path.exists() {
+                        return Err(AgeError::from(format!(
+                            "{} already exists",
+                            out_path.display()
+                        )));
+                    }
+                }
+                Choice::Cancel => return Ok(()),
+            }
+        }
 
-        // we are deleting the buffer not the file.
-        nvim_oxi::api::Buffer::delete(current_file_bufnr, &opts)?;
+        if !diff {
+            if differs && out_path.exists() && self.config.backup {
+                let saved = conflict::backup(&out_path, &self.config.backup_ext)?;
+                print!("Backed up {} to {}", out_path.display(), saved.display());
+            }
+            fs::write(&out_path, &plaintext)?;
+        }
+
+        if let Some(current_file_bufnr) = current_file_bufnr {
+            let new_scratch_buf = nvim_oxi::api::create_buf(false, true)?;
+            nvim_oxi::api::set_current_buf(&new_scratch_buf)?;
+
+            let opts = BufDeleteOpts::builder()
+                .force(true) // Force deletion, ignoring unsaved changes
+                .build();
+
+            // we are deleting the buffer not the file.
+            nvim_oxi::api::Buffer::delete(current_file_bufnr, &opts)?;
+        }
 
+        // the recovery journal stands in for the swap file
         let command = format!(
-            "edit {}",
+            "{}edit {}",
+            if self.config.recovery {
+                "noswapfile "
+            } else {
+                ""
+            },
             out_path.display().to_string().replace(' ', "\\ ")
         );
         nvim_oxi::api::command(&command)?;
 
-        Ok(())
-    }
-
-    fn encrypt_current_file(&self, filenames: Vec<String>) -> Result<(), AgeError> {
-        let current_file_path = nvim_oxi::api::get_current_buf().get_name()?;
-        let current_file = ExistingNonAgeFile::try_from(current_file_path)?;
-        let list_buf = nvim_oxi::api::list_bufs();
-
-        let d = list_buf.len();
-        // if len is one will will create a new buf
-        if d == 1 {
-            // is a scrach buf may be we can show some
-            let new_scratch_buf = nvim_oxi::api::create_buf(false, true)?;
-            nvim_oxi::api::set_current_buf(&new_scratch_buf)?;
-        } else {
-            for buf in list_buf {
-                if buf.get_name()?.to_string_lossy() != current_file.to_string() {
-                    nvim_oxi::api::set_current_buf(&buf)?;
-                    break;
-                }
+        // a plain undo file would hold the text, see `undo`
+        let opts = OptionOpts::builder()
+            .buffer(nvim_oxi::api::get_current_buf())
+            .build();
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        if !diff && !crypt::is_passphrase_encrypted(&ciphertext) {
+            if let Err(err) = self.restore_undo(current_file.path(), &identities) {
+                nvim_oxi::api::err_writeln(&format!("age.nvim: undo history: {err}"));
             }
         }
 
-        let new_file = current_file.append_age();
-
-        encrypt_to_file(current_file.path(), new_file.as_path(), filenames)?;
+        // the plaintext on disk is left alone, `:diffget` what you need
+        if diff {
+            let buf = peek::scratch(&plaintext)?;
+            nvim_oxi::api::command("vertical rightbelow split")?;
+            nvim_oxi::api::get_current_win().set_buf(&buf)?;
+            nvim_oxi::api::command("diffthis | wincmd p | diffthis")?;
+        }
 
-        if self.config.encrypt_and_del {
-            fs::remove_file(current_file.path())?;
+        // in sync with the decrypted text, a diffed buffer starts out diverged
+        let buf = nvim_oxi::api::get_current_buf();
+        let contents = if diff {
+            plaintext.into_bytes()
+        } else {
+            buffer_contents(&buf)?
+        };
+        self.track(
+            &buf,
+            current_file.path().to_path_buf(),
+            Some(out_path.clone()),
+            identity_names,
+            crypt::recipient_count(&ciphertext).unwrap_or_default(),
+            &contents,
+        );
+        self.buffers
+            .borrow_mut()
+            .stamp(buf.handle(), &ciphertext, modified(current_file.path()));
+        if let Err(err) = self.lock(&buf, current_file.path()) {
+            nvim_oxi::api::err_writeln(&format!("age.nvim: can't lock: {err}"));
         }
 
+        let data = data.plaintext(&out_path).bufnr(buf.handle());
+        self.emit(Event::DecryptPost, &data)?;
+        self.recovery_notice(current_file.path());
+
         Ok(())
     }
 
-    pub fn decrypt_to_string(&self, file_path: String) -> Result<String, AgeError> {
-        let file = ExistingNonAgeFile::try_from(file_path.as_str())?;
+    /// Shows the plaintext of the given file, the armored block under the
+    /// cursor or the current `.age` file, without writing it anywhere.
+    fn peek(&self, flags: Flags) -> Result<(), AgeError> {
+        let (title, ciphertext) = match flags.input {
+            Some(input) => {
+                let file = ExistingAgeFile::try_from(expand_tilde(input))?;
+                (file.to_string(), fs::read(file.path())?)
+            }
+            None => {
+                let buf = nvim_oxi::api::get_current_buf();
+                let lines = buf
+                    .get_lines(.., false)?
+                    .map(|line| line.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                let (row, _) = nvim_oxi::api::get_current_win().get_cursor()?;
+
+                match armor::block_at(&lines, row.saturating_sub(1)) {
+                    Some(block) => (
+                        format!("lines {}-{}", block.start + 1, block.end + 1),
+                        block.text.into_bytes(),
+                    ),
+                    None => {
+                        let file = ExistingAgeFile::try_from(buf.get_name()?).map_err(|_| {
+                            AgeError::from("No armored age block under the cursor to peek at")
+                        })?;
+                        (file.to_string(), fs::read(file.path())?)
+                    }
+                }
+            }
+        };
+
+        let identities = self.identities_for(&ciphertext, &title, flags.identities)?;
+        let plaintext = crypt::decrypt_bytes_with(&ciphertext, &identities)?;
+
+        peek::open(&title, &plaintext, self.config.peek)
+    }
+
+    /// Encrypts the lines of the current buffer, unsaved edits included.
+    ///
+    /// Unnamed and scratch buffers need `-o`, they are wiped afterwards so
+    /// the secret is never written anywhere as plaintext.
+    fn encrypt_current_file(&self, flags: Flags) -> Result<(), AgeError> {
+        let current_buf = nvim_oxi::api::get_current_buf();
+        let current_file = current_buf.get_name()?;
+        let buftype: nvim_oxi::String = nvim_oxi::api::get_option_value(
+            "buftype",
+            &OptionOpts::builder().buffer(current_buf.clone()).build(),
+        )?;
+        let is_scratch = current_file.as_os_str().is_empty() || !buftype.is_empty();
+
+        if !is_scratch
+            && current_file
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("age"))
+        {
+            return Err(AgeError::from(
+                "File have `.age` extension, it's already encrypted.",
+            ));
+        }
+
+        let new_file = match flags.output {
+            Some(ref output) => expand_tilde(output),
+            // back to the file it was decrypted from
+            None => match self
+                .buffers
+                .borrow()
+                .get(current_buf.handle())
+                .map(|state| state.source.clone())
+            {
+                Some(source) => source,
+                None if is_scratch => {
+                    return Err(AgeError::from(
+                        "This buffer has no file, use `:Age encrypt -o path.age`",
+                    ))
+                }
+                None => current_file.with_added_extension("age"),
+            },
+        };
+
+        // git or a sync tool may have replaced it since it was decrypted
+        if !flags.force && !self.confirm_unchanged(&current_buf, &new_file)? {
+            return Ok(());
+        }
+
+        let (recipients, set) = if flags.passphrase {
+            (vec![self.new_passphrase()?], None)
+        } else {
+            let set = self.recipient_set(&flags, &new_file)?;
+            let recipients = set.load()?;
+            (recipients, Some(set))
+        };
+        let keys = set
+            .as_ref()
+            .map(|set| set.recipients.as_slice())
+            .unwrap_or_default();
+        if !self.confirm_recipients(&new_file, keys, recipients.len(), flags.force)? {
+            print!("Not encrypted");
+            return Ok(());
+        }
+
+        let mut data = EventData::default()
+            .path(&new_file)
+            .bufnr(current_buf.handle())
+            .recipients(recipients.len());
+        if !is_scratch {
+            data = data.plaintext(&current_file);
+        }
+        self.emit(Event::EncryptPre, &data)?;
+
+        let plaintext = buffer_contents(&current_buf)?;
+        crypt::encrypt_bytes_to_file_with(&plaintext, new_file.as_path(), &recipients)?;
+        recovery::remove(&self.journal_dir()?, &new_file)?;
+        // the file is written, losing the undo history mustn't stop the rest
+        if !is_scratch && set.is_some() {
+            if let Err(err) = self.save_undo(&current_buf, &new_file, &recipients) {
+                nvim_oxi::api::err_writeln(&format!("age.nvim: undo history: {err}"));
+            }
+        }
+
+        // remember explicit recipients for the next save
+        if let Some(set) = set.as_ref().filter(|set| set.origin == Origin::Flags) {
+            recipients::write_sidecar(&new_file, &set.recipients)?;
+        }
+
+        let list_buf = nvim_oxi::api::list_bufs();
+
+        let d = list_buf.len();
+        // if len is one will will create a new buf
+        if d == 1 {
+            // is a scrach buf may be we can show some
+            let new_scratch_buf = nvim_oxi::api::create_buf(false, true)?;
+            nvim_oxi::api::set_current_buf(&new_scratch_buf)?;
+        } else {
+            for buf in list_buf {
+                if buf != current_buf {
+                    nvim_oxi::api::set_current_buf(&buf)?;
+                    break;
+                }
+            }
+        }
+
+        if is_scratch {
+            let opts = BufDeleteOpts::builder().force(true).build();
+            self.buffers.borrow_mut().forget(current_buf.handle());
+            current_buf.delete(&opts)?;
+        } else {
+            let identities = self
+                .buffers
+                .borrow()
+                .get(current_buf.handle())
+                .map(|state| state.identities.clone())
+                .unwrap_or_default();
+            self.track(
+                &current_buf,
+                new_file.clone(),
+                Some(current_file.clone()),
+                identities,
+                recipients.len(),
+                &plaintext,
+            );
+            self.buffers.borrow_mut().stamp(
+                current_buf.handle(),
+                &fs::read(&new_file)?,
+                modified(&new_file),
+            );
 
-        // Logic: If private_key_file is set, use that.
-        if !self.config.key_file.is_empty() {
-            let id_file = self.config.key_file.to_string();
-            return decrypt_to_string(file.path(), vec![id_file]);
+            if self.config.encrypt_and_del && current_file.exists() {
+                fs::remove_file(&current_file)?;
+            }
         }
 
-        Err(AgeError::new(
-            "the field `key_file` in config is missing".to_owned(),
-        ))
+        let noun = if recipients.len() == 1 {
+            "recipient"
+        } else {
+            "recipients"
+        };
+        match &set {
+            Some(set) => print!(
+                "Encrypted to {} for {} {noun} ({})",
+                new_file.display(),
+                recipients.len(),
+                set.origin
+            ),
+            None => print!("Encrypted to {} with a passphrase", new_file.display()),
+        }
+
+        self.emit(Event::EncryptPost, &data)?;
+
+        Ok(())
     }
 
-    pub fn decrypt_from_string(&self, encrypted: String) -> Result<String, AgeError> {
-        // Logic: If private_key_file is set, use that. Otherwise use the string.
-        if !self.config.key_file.is_empty() {
-            let id_file = self.config.key_file.to_string();
-            return crate::crypt::decrypt_from_string(encrypted, vec![id_file]);
+    /// Opens `base | result | theirs` for the conflicted file in `args` or
+    /// the current buffer, see [`merge`].
+    fn merge(&self, args: Vec<String>) -> Result<(), AgeError> {
+        let path = match args.as_slice() {
+            [] => nvim_oxi::api::get_current_buf().get_name()?,
+            [path] => expand_tilde(path),
+            _ => return Err(AgeError::from("only one file can be merged at a time")),
+        };
+        let path = std::path::absolute(&path)?;
+        let versions = merge::versions(&path)?;
+
+        let name = path.display().to_string();
+        let decrypt = |ciphertext: &[u8]| -> Result<String, AgeError> {
+            let identities = self.identities_for(ciphertext, &name, vec![])?;
+            crypt::decrypt_bytes_with(ciphertext, &identities)
+        };
+        let base = versions.base.as_deref().map(decrypt).transpose()?;
+        let ours = decrypt(&versions.ours)?;
+        let theirs = decrypt(&versions.theirs)?;
+
+        // the result is written by `finish_merge`, never as plaintext
+        let mut result = nvim_oxi::api::create_buf(true, false)?;
+        result.set_name(merge::result_name(&path))?;
+        let opts = OptionOpts::builder().buffer(result.clone()).build();
+        nvim_oxi::api::set_option_value("buftype", "acwrite", &opts)?;
+        nvim_oxi::api::set_option_value("bufhidden", "wipe", &opts)?;
+        nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        let text = ours.strip_suffix('\n').unwrap_or(&ours);
+        result.set_lines(.., false, text.split('\n'))?;
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+
+        nvim_oxi::api::command("tabnew")?;
+        let scratch = nvim_oxi::api::get_current_buf();
+        nvim_oxi::api::get_current_win().set_buf(&result)?;
+        scratch.delete(&BufDeleteOpts::builder().force(true).build())?;
+        let result_win = nvim_oxi::api::get_current_win();
+
+        if let Some(base) = base {
+            let mut buf = peek::scratch(&base)?;
+            buf.set_name(format!("{}base://{}", merge::SCHEME, path.display()))?;
+            nvim_oxi::api::command("leftabove vertical split")?;
+            nvim_oxi::api::get_current_win().set_buf(&buf)?;
+            nvim_oxi::api::set_current_win(&result_win)?;
+        }
+        let mut buf = peek::scratch(&theirs)?;
+        buf.set_name(format!("{}theirs://{}", merge::SCHEME, path.display()))?;
+        nvim_oxi::api::command("rightbelow vertical split")?;
+        nvim_oxi::api::get_current_win().set_buf(&buf)?;
+
+        nvim_oxi::api::command("windo diffthis")?;
+        nvim_oxi::api::set_current_win(&result_win)?;
+
+        print!("Resolve in the middle window, `:w` encrypts and stages it");
+        Ok(())
+    }
+
+    /// `:w` of a merge result: encrypts it to the recipients of its `.age`
+    /// file, writes it over the conflicted file and stages it.
+    pub fn finish_merge(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        let name = buf.get_name()?.display().to_string();
+        let path = merge::source_of(&name)
+            .ok_or_else(|| AgeError::from(format!("{name} is not a merge result")))?;
+
+        let set = self.recipient_set(&Flags::default(), &path)?;
+        let recipients = set.load()?;
+        if !self.confirm_recipients(&path, &set.recipients, recipients.len(), false)? {
+            return Ok(());
+        }
+
+        let data = EventData::default()
+            .path(&path)
+            .bufnr(buf.handle())
+            .recipients(recipients.len());
+        self.emit(Event::EncryptPre, &data)?;
+        crypt::encrypt_bytes_to_file_with(&buffer_contents(buf)?, &path, &recipients)?;
+        merge::stage(&path)?;
+
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+        print!(
+            "Merged {} for {} recipients ({}) and staged it",
+            path.display(),
+            recipients.len(),
+            set.origin
+        );
+        self.emit(Event::EncryptPost, &data)?;
+        Ok(())
+    }
+
+    fn pass_command(&self, args: Vec<String>, force: bool) -> Result<(), AgeError> {
+        let store = Store::from_env();
+        let mut args = args.into_iter();
+        let action = args.next().unwrap_or_else(|| "ls".to_owned());
+
+        match (action.as_str(), args.collect::<Vec<_>>().as_slice()) {
+            ("ls", []) => self.pass_ls(&store, ""),
+            ("ls", [dir]) => self.pass_ls(&store, dir),
+            ("show", [name]) => {
+                let path = store.path(name)?;
+                let plaintext = self.pass_decrypt(&store, &path)?;
+                peek::open(name, &plaintext, self.config.peek)
+            }
+            ("edit", [name]) => self.pass_edit(&store, name, force),
+            ("insert", [name]) => self.pass_insert(&store, name, force),
+            ("rm", [name]) => {
+                if !force && !confirm(&format!("Are you sure you would like to delete {name}?"))? {
+                    return Ok(());
+                }
+                store.remove(name)?;
+                print!("Removed {name}");
+                Ok(())
+            }
+            ("mv", [from, to]) => self.pass_mv(&store, from, to, force),
+            ("ls" | "show" | "edit" | "insert" | "rm" | "mv", _) => Err(AgeError::from(
+                "usage: :Age pass [ls [dir] | show|edit|insert|rm <name> | mv <from> <to>]",
+            )),
+            (other, _) => Err(AgeError::from(format!("Unknown pass command: {other}"))),
+        }
+    }
+
+    fn pass_ls(&self, store: &Store, dir: &str) -> Result<(), AgeError> {
+        let entries = store.entries(dir)?;
+        if entries.is_empty() {
+            print!("No entries in {}", store.dir_path(dir)?.display());
+        } else {
+            print!("{}", entries.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Identities from the store's identities file, or the discovered one.
+    fn pass_decrypt(&self, store: &Store, path: &Path) -> Result<String, AgeError> {
+        let ciphertext = fs::read(path).map_err(|_| {
+            AgeError::from(format!(
+                "{} is not in the password store",
+                store.name_of(path).unwrap_or_default()
+            ))
+        })?;
+        let identities = if store.identities.is_file() {
+            crypt::load_identities(vec![store.identities.display().to_string()])?
+        } else {
+            self.identities(vec![])?
+        };
+        crypt::decrypt_bytes_with(&ciphertext, &identities)
+    }
+
+    /// Encrypts `plaintext` to the recipients of the entry at `path`.
+    fn pass_encrypt(&self, path: &Path, plaintext: &[u8]) -> Result<RecipientSet, AgeError> {
+        let set = self.recipient_set(&Flags::default(), path)?;
+        let recipients = set.load()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        crypt::encrypt_bytes_to_file_with(plaintext, path, &recipients)?;
+        Ok(set)
+    }
+
+    /// Opens an entry, new ones start out empty, in an `acwrite` buffer.
+    fn pass_edit(&self, store: &Store, name: &str, force: bool) -> Result<(), AgeError> {
+        let path = store.path(name)?;
+        let buffer_name = pass::buffer_name(name);
+        // already open, `bufnr()` takes a pattern
+        let existing: i64 = nvim_oxi::api::call_function("bufnr", (format!("^{buffer_name}$"),))?;
+        if existing > 0 {
+            nvim_oxi::api::command(&format!("buffer {existing}"))?;
+            return Ok(());
+        }
+
+        let exists = path.is_file();
+        if exists && !self.confirm_lock(&path, force)? {
+            return Ok(());
+        }
+        let plaintext = if exists {
+            self.pass_decrypt(store, &path)?
+        } else {
+            String::new()
+        };
+
+        // written by `save_pass`, never as plaintext
+        let mut buf = nvim_oxi::api::create_buf(true, false)?;
+        buf.set_name(&buffer_name)?;
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("buftype", "acwrite", &opts)?;
+        nvim_oxi::api::set_option_value("bufhidden", "wipe", &opts)?;
+        nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        let text = plaintext.strip_suffix('\n').unwrap_or(&plaintext);
+        buf.set_lines(.., false, text.split('\n'))?;
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+        nvim_oxi::api::set_current_buf(&buf)?;
+
+        if exists {
+            if let Err(err) = self.lock(&buf, &path) {
+                nvim_oxi::api::err_writeln(&format!("age.nvim: can't lock: {err}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// `:w` of an entry opened with `:Age pass edit`.
+    pub fn save_pass(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        let buffer_name = buf.get_name()?.display().to_string();
+        let name = pass::entry_of(&buffer_name)
+            .ok_or_else(|| AgeError::from(format!("{buffer_name} is not a password entry")))?;
+        let path = Store::from_env().path(name)?;
+
+        let data = EventData::default().path(&path).bufnr(buf.handle());
+        self.emit(Event::EncryptPre, &data)?;
+        let set = self.pass_encrypt(&path, &buffer_contents(buf)?)?;
+
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+        print!(
+            "Encrypted {name} for {} recipients ({})",
+            set.recipients.len(),
+            set.origin
+        );
+        self.emit(Event::EncryptPost, &data.recipients(set.recipients.len()))?;
+        Ok(())
+    }
+
+    fn pass_insert(&self, store: &Store, name: &str, force: bool) -> Result<(), AgeError> {
+        let path = store.path(name)?;
+        if path.exists()
+            && !force
+            && !confirm(&format!(
+                "An entry already exists for {name}. Overwrite it?"
+            ))?
+        {
+            return Ok(());
+        }
+
+        let password = prompt_passphrase(&format!("Enter password for {name}: "))?;
+        let retyped = prompt_passphrase(&format!("Retype password for {name}: "))?;
+        if password.expose_secret() != retyped.expose_secret() {
+            return Err(AgeError::from("the entered passwords do not match"));
+        }
+
+        let plaintext = format!("{}\n", password.expose_secret());
+        let set = self.pass_encrypt(&path, plaintext.as_bytes())?;
+        print!(
+            "Inserted {name} for {} recipients ({})",
+            set.recipients.len(),
+            set.origin
+        );
+        Ok(())
+    }
+
+    /// Moves entries, re-encrypting each to the recipients of its new place
+    /// like passage does.
+    fn pass_mv(&self, store: &Store, from: &str, to: &str, force: bool) -> Result<(), AgeError> {
+        let moves = store.moves(from, to)?;
+        if let Some((_, existing)) = moves.iter().find(|(_, to)| to.exists()) {
+            let name = store.name_of(existing).unwrap_or_default();
+            if !force
+                && !confirm(&format!(
+                    "An entry already exists for {name}. Overwrite it?"
+                ))?
+            {
+                return Ok(());
+            }
+        }
+
+        for (old, new) in &moves {
+            let plaintext = self.pass_decrypt(store, old)?;
+            self.pass_encrypt(new, plaintext.as_bytes())?;
+            fs::remove_file(old)?;
+            store.prune(old);
+        }
+        print!("Moved {from} to {to}");
+        Ok(())
+    }
+
+    /// Copies the plaintext of `file`, or of its line `yank.line`, to a
+    /// register without opening it.
+    pub fn copy_secret(&self, file: Option<String>, yank: Yank) -> Result<(), AgeError> {
+        let (name, plaintext) = self.secret(file)?;
+        let secret = clipboard::select(&plaintext, yank.line)?;
+        let register = yank
+            .register
+            .unwrap_or_else(|| clipboard::DEFAULT_REGISTER.to_owned());
+        let timeout = yank
+            .timeout
+            .map_or(self.config.clipboard_timeout, clipboard::timeout);
+        clipboard::copy(&register, &secret, timeout)?;
+
+        let what = match yank.line {
+            Some(line) => format!("line {line} of {name}"),
+            None => name,
+        };
+        match timeout {
+            Some(timeout) => print!(
+                "Copied {what} to register {register}, restoring it in {}s",
+                timeout.as_secs()
+            ),
+            None => print!("Copied {what} to register {register}"),
+        }
+        Ok(())
+    }
+
+    /// The name and plaintext of `file`, or of the current buffer's `.age`
+    /// file. Names that aren't files are looked up in the password store.
+    fn secret(&self, file: Option<String>) -> Result<(String, String), AgeError> {
+        let store = Store::from_env();
+        match file {
+            Some(file) => {
+                let path = expand_tilde(&file);
+                match store.path(&file) {
+                    Ok(entry) if !path.exists() && entry.is_file() => {
+                        let plaintext = self.pass_decrypt(&store, &entry)?;
+                        Ok((file, plaintext))
+                    }
+                    _ => self.decrypt_age_file(path),
+                }
+            }
+            None => {
+                let buf = nvim_oxi::api::get_current_buf();
+                let name = buf.get_name()?;
+                let tracked = self
+                    .buffers
+                    .borrow()
+                    .get(buf.handle())
+                    .map(|state| state.source.clone());
+                match pass::entry_of(&name.to_string_lossy()) {
+                    Some(entry) => {
+                        let plaintext = self.pass_decrypt(&store, &store.path(entry)?)?;
+                        Ok((entry.to_owned(), plaintext))
+                    }
+                    None => self.decrypt_age_file(tracked.unwrap_or(name)),
+                }
+            }
+        }
+    }
+
+    /// Shows the current TOTP code of `file`, or copies it to the clipboard
+    /// until it expires.
+    fn otp(&self, args: Vec<String>, yank: bool) -> Result<(), AgeError> {
+        let file = match args.as_slice() {
+            [] => None,
+            [file] => Some(file.clone()),
+            _ => return Err(AgeError::from("usage: :Age otp [file]")),
+        };
+        let (name, plaintext) = self.secret(file)?;
+        let totp =
+            Totp::find(&plaintext).map_err(|err| AgeError::from(format!("{name}: {err}")))?;
+        let (code, remaining) = totp.now()?;
+
+        if yank {
+            // a code is useless once expired, keep it only as long as needed
+            let timeout = self
+                .config
+                .clipboard_timeout
+                .map(|timeout| timeout.min(Duration::from_secs(remaining)));
+            clipboard::copy(clipboard::DEFAULT_REGISTER, &code, timeout)?;
+            print!(
+                "Copied the {} code to register {}, valid for {remaining}s",
+                totp.name(),
+                clipboard::DEFAULT_REGISTER
+            );
+        } else {
+            print!("{} {code}, valid for {remaining}s", totp.name());
+        }
+        Ok(())
+    }
+
+    /// The name and plaintext of an `.age` file.
+    fn decrypt_age_file(&self, path: PathBuf) -> Result<(String, String), AgeError> {
+        let file = ExistingAgeFile::try_from(path)?;
+        let ciphertext = fs::read(file.path())?;
+        let identities = self.identities_for(&ciphertext, &file.to_string(), vec![])?;
+        let plaintext = crypt::decrypt_bytes_with(&ciphertext, &identities)?;
+        Ok((file.to_string(), plaintext))
+    }
+
+    /// Decrypts the `ENC[age,...]` values of a YAML, JSON or TOML buffer
+    /// that was just read, `:w` then goes through `encrypt_values`.
+    pub fn open_values(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        self.values.borrow_mut().forget(buf.handle());
+        unwatch_values(buf)?;
+
+        let name = buf.get_name()?;
+        let Some(format) = Format::from_path(&name) else {
+            return Ok(());
+        };
+        let Ok(text) = String::from_utf8(buffer_contents(buf)?) else {
+            return Ok(());
+        };
+        if !text.contains(structured::PREFIX) {
+            return Ok(());
+        }
+
+        let identities = self.identities(vec![])?;
+        let (plaintext, secrets) = structured::decrypt_document(format, &text, &identities)?;
+        if secrets.is_empty() {
+            return Ok(());
+        }
+
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        // going back to the ciphertext isn't an undo step
+        let undolevels: i64 = nvim_oxi::api::get_option_value("undolevels", &opts)?;
+        nvim_oxi::api::set_option_value("undolevels", -1, &opts)?;
+        let text = plaintext.strip_suffix('\n').unwrap_or(&plaintext);
+        let result = buf.clone().set_lines(.., false, text.split('\n'));
+        nvim_oxi::api::set_option_value("undolevels", undolevels, &opts)?;
+        result?;
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+
+        let recipients = self.current_recipients(&name);
+        self.values
+            .borrow_mut()
+            .track(buf.handle(), &recipients, &secrets);
+        watch_values(buf)
+    }
+
+    pub fn forget_values(&self, bufnr: i32) {
+        self.values.borrow_mut().forget(bufnr);
+    }
+
+    /// Writes the current YAML, JSON or TOML buffer with the values that
+    /// were encrypted when it was read, and new ones matching
+    /// `encrypted_keys`, encrypted. Unchanged values keep their ciphertext.
+    fn encrypt_values(&self, flags: Flags) -> Result<(), AgeError> {
+        let buf = nvim_oxi::api::get_current_buf();
+        let name = buf.get_name()?;
+        let format = Format::from_path(&name)
+            .ok_or_else(|| AgeError::from("encrypt-values works on YAML, JSON and TOML files"))?;
+        let path = match &flags.output {
+            Some(output) => std::path::absolute(expand_tilde(output))?,
+            None => name.clone(),
+        };
+        let bufnr = buf.handle();
+        let tracked = self.values.borrow().is_tracked(bufnr);
+
+        // without `encrypted_keys` a first encrypt takes every string
+        let keys = encrypted_keys(&self.config.encrypted_keys)?;
+        let everything = !tracked && self.config.encrypted_keys.is_empty();
+
+        let set = self.recipient_set(&flags, &path)?;
+        let recipients = set.load()?;
+        let text = String::from_utf8(buffer_contents(&buf)?)?;
+
+        let data = EventData::default()
+            .path(&path)
+            .bufnr(bufnr)
+            .recipients(recipients.len());
+        self.emit(Event::EncryptPre, &data)?;
+        let mut fresh = 0;
+        let (encrypted, secrets) = {
+            let values = self.values.borrow();
+            structured::encrypt_document(format, &text, &recipients, |leaf| {
+                let choice = values
+                    .choice(bufnr, &set.recipients, leaf)
+                    .unwrap_or_else(|| {
+                        if everything || keys.is_match(&leaf.path) || keys.is_match(leaf.key()) {
+                            structured::Choice::Encrypt
+                        } else {
+                            structured::Choice::Plain
+                        }
+                    });
+                if choice == structured::Choice::Encrypt {
+                    fresh += 1;
+                }
+                choice
+            })?
+        };
+        if secrets.is_empty() && !tracked {
+            return Err(AgeError::from(format!(
+                "no values to encrypt in {}, see `encrypted_keys`",
+                name.display()
+            )));
+        }
+
+        if set.origin == Origin::Flags {
+            recipients::write_sidecar(&path, &set.recipients)?;
+        }
+        fs::write(&path, encrypted)?;
+
+        if path == name {
+            let opts = OptionOpts::builder().buffer(buf.clone()).build();
+            nvim_oxi::api::set_option_value("modified", false, &opts)?;
+            settle(&buf)?;
+            self.values
+                .borrow_mut()
+                .track(bufnr, &set.recipients, &secrets);
+            watch_values(&buf)?;
         }
+        print!(
+            "\"{}\" {} values encrypted for {} recipients ({}), {fresh} of them anew",
+            path.display(),
+            secrets.len(),
+            recipients.len(),
+            set.origin
+        );
+        self.emit(Event::EncryptPost, &data)?;
+        Ok(())
+    }
+
+    /// Encrypts the tagged sections of the current Markdown or Org buffer
+    /// that are in the clear.
+    fn encrypt_sections(&self, flags: Flags) -> Result<(), AgeError> {
+        let buf = nvim_oxi::api::get_current_buf();
+        let syntax = syntax(&buf)?;
+        let path = buf.get_name()?;
+        let lines = buffer_lines(&buf)?;
+
+        let set = self.recipient_set(&flags, &path)?;
+        let recipients = set.load()?;
+        let bufnr = buf.handle();
+        let (edits, bodies) = {
+            let values = self.values.borrow();
+            sections::encrypt(syntax, &lines, &recipients, |section, plaintext| {
+                values.unchanged(
+                    bufnr,
+                    &set.recipients,
+                    section.occurrence,
+                    &section.title,
+                    plaintext,
+                )
+            })?
+        };
+        if edits.is_empty() {
+            return Err(AgeError::from(
+                "No sections to encrypt, tag a heading `:crypt:` or `<!-- age -->`",
+            ));
+        }
+
+        if set.origin == Origin::Flags && !path.as_os_str().is_empty() {
+            recipients::write_sidecar(&path, &set.recipients)?;
+        }
+        apply_edits(&buf, &edits)?;
+        self.track_sections(bufnr, &set.recipients, bodies);
+        print!(
+            "Encrypted {} sections for {} recipients ({})",
+            edits.len(),
+            recipients.len(),
+            set.origin
+        );
+        Ok(())
+    }
+
+    /// Decrypts the encrypted sections of the current Markdown or Org buffer.
+    fn decrypt_sections(&self, flags: Flags) -> Result<(), AgeError> {
+        let buf = nvim_oxi::api::get_current_buf();
+        let syntax = syntax(&buf)?;
+        let lines = buffer_lines(&buf)?;
+
+        let identities = self.identities(flags.identities)?;
+        let (edits, bodies) = sections::decrypt(syntax, &lines, &identities)?;
+        if edits.is_empty() {
+            return Err(AgeError::from("No encrypted sections in this buffer"));
+        }
+
+        // the plaintext is in the buffer now, keep it off the disk
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        apply_edits(&buf, &edits)?;
+        let recipients = self.current_recipients(&buf.get_name()?);
+        self.track_sections(buf.handle(), &recipients, bodies);
+        // `:w` encrypts them again, with or without `auto_sections`
+        self.decrypted.borrow_mut().insert(buf.handle(), syntax);
+        print!("Decrypted {} sections", edits.len());
+        Ok(())
+    }
+
+    /// With `auto_sections`, decrypts the sections of a Markdown or Org
+    /// buffer that was just read.
+    pub fn open_sections(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        self.forget_sections(buf.handle());
+        if !self.config.auto_sections {
+            return Ok(());
+        }
+        let path = buf.get_name()?;
+        let Some(syntax) = Syntax::from_path(&path) else {
+            return Ok(());
+        };
+        let lines = buffer_lines(buf)?;
+        if armor::blocks(&lines).is_empty() {
+            return Ok(());
+        }
+
+        let identities = self.identities(vec![])?;
+        let (edits, bodies) = sections::decrypt(syntax, &lines, &identities)?;
+        if edits.is_empty() {
+            return Ok(());
+        }
+
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+        nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+        // going back to the ciphertext isn't an undo step
+        let undolevels: i64 = nvim_oxi::api::get_option_value("undolevels", &opts)?;
+        nvim_oxi::api::set_option_value("undolevels", -1, &opts)?;
+        let result = apply_edits(buf, &edits);
+        nvim_oxi::api::set_option_value("undolevels", undolevels, &opts)?;
+        result?;
+        nvim_oxi::api::set_option_value("modified", false, &opts)?;
+
+        let recipients = self.current_recipients(&path);
+        self.track_sections(buf.handle(), &recipients, bodies);
+        self.decrypted.borrow_mut().insert(buf.handle(), syntax);
+        Ok(())
+    }
+
+    /// Encrypts the tagged sections of a buffer about to be written, when
+    /// they were decrypted or `auto_sections` is on, `restore_sections`
+    /// brings them back afterwards.
+    ///
+    /// The write can't be stopped from here, so when encrypting fails the
+    /// sections are left out of the file, or keep their last ciphertext.
+    pub fn seal_sections(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        let bufnr = buf.handle();
+        let path = buf.get_name()?;
+        let decrypted = self.decrypted.borrow().get(&bufnr).copied();
+        let Some(syntax) = decrypted.or_else(|| {
+            self.config
+                .auto_sections
+                .then(|| Syntax::from_path(&path))
+                .flatten()
+        }) else {
+            return Ok(());
+        };
+        let lines = buffer_lines(buf)?;
+        let clear = sections::sections(syntax, &lines).iter().any(|section| {
+            let body = &lines[section.body.clone()];
+            !body.is_empty() && sections::armored(body).is_none()
+        });
+        if !clear {
+            return Ok(());
+        }
+
+        let (edits, bodies) = {
+            let values = self.values.borrow();
+            let sealed = self
+                .recipient_set(&Flags::default(), &path)
+                .and_then(|set| Ok((set.load()?, set.recipients)))
+                .and_then(|(recipients, tracked)| {
+                    let (edits, bodies) =
+                        sections::encrypt(syntax, &lines, &recipients, |section, plaintext| {
+                            values.unchanged(
+                                bufnr,
+                                &tracked,
+                                section.occurrence,
+                                &section.title,
+                                plaintext,
+                            )
+                        })?;
+                    Ok((edits, (tracked, bodies)))
+                });
+            match sealed {
+                Ok((edits, bodies)) => (edits, Some(bodies)),
+                Err(err) => {
+                    nvim_oxi::api::err_writeln(&format!(
+                        "age.nvim: {}: sections left out of the file: {err}",
+                        path.display()
+                    ));
+                    let edits = sections::withhold(syntax, &lines, |section| {
+                        values.last(bufnr, section.occurrence, &section.title)
+                    });
+                    (edits, None)
+                }
+            }
+        };
+
+        // encrypting and restoring together are no undo step
+        nvim_oxi::api::command("silent! undojoin")?;
+        apply_edits(buf, &edits)?;
+        let restore = sections::invert(&lines, &edits);
+        self.sealed
+            .borrow_mut()
+            .insert(bufnr, (restore, bodies.is_none()));
+        if let Some((recipients, bodies)) = bodies {
+            self.track_sections(bufnr, &recipients, bodies);
+        }
+        Ok(())
+    }
+
+    /// Puts back the sections `seal_sections` encrypted for a write.
+    pub fn restore_sections(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        let Some((restore, withheld)) = self.sealed.borrow_mut().remove(&buf.handle()) else {
+            return Ok(());
+        };
+        nvim_oxi::api::command("silent! undojoin")?;
+        apply_edits(buf, &restore)?;
+
+        // sections left out still have to be written
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        nvim_oxi::api::set_option_value("modified", withheld, &opts)?;
+        Ok(())
+    }
+
+    pub fn forget_sections(&self, bufnr: i32) {
+        self.values.borrow_mut().forget(bufnr);
+        self.decrypted.borrow_mut().remove(&bufnr);
+        self.sealed.borrow_mut().remove(&bufnr);
+    }
+
+    fn track_sections(&self, bufnr: i32, recipients: &[String], bodies: Vec<sections::Body>) {
+        let secrets = bodies
+            .into_iter()
+            .map(|body| Secret {
+                doc: body.occurrence,
+                path: body.title,
+                plaintext: body.plaintext,
+                ciphertext: body.armor,
+            })
+            .collect::<Vec<_>>();
+        self.values.borrow_mut().track(bufnr, recipients, &secrets);
+    }
+
+    /// The recipients `path` would be encrypted for now, taken as the ones
+    /// its values were encrypted for when read. Nothing when they can't be
+    /// resolved, so every value is encrypted anew.
+    fn current_recipients(&self, path: &Path) -> Vec<String> {
+        self.recipient_set(&Flags::default(), path)
+            .map(|set| set.recipients)
+            .unwrap_or_default()
+    }
+
+    /// Publishes `vim.diagnostic` entries for the armored blocks of `buf`:
+    /// errors for armor `age` would refuse, warnings for blocks none of the
+    /// configured identities can decrypt. Passphrase blocks aren't checked.
+    pub fn diagnose(&self, buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+        if !self.config.diagnostics {
+            return Ok(());
+        }
+        // most buffers have no block, don't copy their lines to find out
+        let found: i64 =
+            buf.call(|_| nvim_oxi::api::call_function::<_, i64>("search", (armor::BEGIN, "ncw")))?;
+        let lines = if found > 0 {
+            buffer_lines(buf)?
+        } else {
+            Vec::new()
+        };
+
+        let mut identities = None;
+        let mut diagnostics = Vec::new();
+        for found in armor::scan(&lines) {
+            let (start, end, severity, message) = match found {
+                armor::Found::Malformed { line, reason } => (line, line, Severity::Error, reason),
+                armor::Found::Block(block) => {
+                    let ciphertext = block.text.as_bytes();
+                    if crypt::is_passphrase_encrypted(ciphertext) {
+                        continue;
+                    }
+                    let known = self.unwrappable.borrow().get(&block.text).copied();
+                    let unwraps = match (known, &identities) {
+                        (Some(unwraps), _) => Ok(unwraps),
+                        (None, None) => {
+                            let loaded = self.quiet_identities();
+                            unwrap_block(ciphertext, identities.insert(loaded))
+                        }
+                        (None, Some(loaded)) => unwrap_block(ciphertext, loaded),
+                    };
+                    match unwraps {
+                        Ok(true) => {
+                            self.unwrappable.borrow_mut().insert(block.text, true);
+                            continue;
+                        }
+                        Ok(false) => {
+                            self.unwrappable.borrow_mut().insert(block.text, false);
+                            (
+                                block.start,
+                                block.end,
+                                Severity::Warn,
+                                "none of your identities can decrypt this block".to_owned(),
+                            )
+                        }
+                        Err((severity, message)) => (block.start, block.end, severity, message),
+                    }
+                }
+            };
+            diagnostics.push(diagnostic(&lines, start, end, severity, message));
+        }
+
+        let namespace = nvim_oxi::api::create_namespace("age");
+        let _: Object = nvim_oxi::api::call_function(
+            "luaeval",
+            (
+                "vim.diagnostic.set(_A[1], _A[2], _A[3])",
+                Array::from_iter([
+                    Object::from(namespace),
+                    Object::from(buf.handle()),
+                    Object::from(Array::from_iter(diagnostics)),
+                ]),
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// `stdpath('state')/age/recovery`
+    fn journal_dir(&self) -> Result<PathBuf, AgeError> {
+        Ok(recovery::journal_dir(&stdpath("state")?))
+    }
+
+    /// `stdpath('state')/age/undo`
+    fn undo_dir(&self) -> Result<PathBuf, AgeError> {
+        Ok(undo::undo_dir(&stdpath("state")?))
+    }
+
+    /// `:wundo` for `buf`, which has to be the current buffer, encrypted to
+    /// `recipients` as the undo history of `source`.
+    fn save_undo(
+        &self,
+        buf: &nvim_oxi::api::Buffer,
+        source: &Path,
+        recipients: &[BoxedRecipient],
+    ) -> Result<(), AgeError> {
+        if !self.config.undo {
+            return Ok(());
+        }
+        let scratch = undo::scratch_path(&stdpath("run")?, buf.handle());
+
+        nvim_oxi::api::command(&format!(
+            "silent wundo! {}",
+            scratch.display().to_string().replace(' ', "\\ ")
+        ))?;
+        let history = undo::take(&scratch)?;
+
+        let dir = self.undo_dir()?;
+        recovery::create_dir(&dir)?;
+        crypt::encrypt_bytes_to_file_with(&history, &undo::undo_path(&dir, source), recipients)
+    }
+
+    /// `:rundo` for the current buffer from the encrypted undo history of
+    /// `source`, when there is one.
+    fn restore_undo(&self, source: &Path, identities: &[BoxedIdentity]) -> Result<(), AgeError> {
+        if !self.config.undo {
+            return Ok(());
+        }
+        let path = undo::undo_path(&self.undo_dir()?, source);
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let history = crypt::decrypt_binary_with(&fs::read(&path)?, identities)?;
+        let scratch =
+            undo::scratch_path(&stdpath("run")?, nvim_oxi::api::get_current_buf().handle());
+        undo::write_private(&scratch, &history)?;
+
+        // `:rundo` refuses histories that don't match the text, eg: the
+        // file was changed elsewhere since
+        let result = nvim_oxi::api::command(&format!(
+            "silent! rundo {}",
+            scratch.display().to_string().replace(' ', "\\ ")
+        ));
+        undo::take(&scratch)?;
+        Ok(result?)
+    }
+
+    /// Encrypts the text of `bufnr` to its recovery journal when it has
+    /// unsaved changes that aren't journaled yet.
+    pub fn journal(&self, bufnr: i32) -> Result<(), AgeError> {
+        if !self.config.recovery {
+            return Ok(());
+        }
+        let buf = nvim_oxi::api::Buffer::from(bufnr);
+        let Some(source) = self
+            .buffers
+            .borrow()
+            .get(bufnr)
+            .map(|state| state.source.clone())
+        else {
+            return Ok(());
+        };
+
+        let tick = buf.get_var::<i64>("changedtick").unwrap_or_default();
+        if !self.diverged(&buf) || !self.buffers.borrow().journal_due(bufnr, tick) {
+            return Ok(());
+        }
+
+        // while idle nothing may prompt, try again on the next tick
+        let Ok(recipients) = self.quiet_recipient_set(&source).and_then(|set| set.load()) else {
+            return Ok(());
+        };
+
+        let dir = self.journal_dir()?;
+        recovery::create_dir(&dir)?;
+        crypt::encrypt_bytes_to_file_with(
+            &buffer_contents(&buf)?,
+            &recovery::journal_path(&dir, &source),
+            &recipients,
+        )?;
+        self.buffers.borrow_mut().journaled(bufnr, tick);
+        Ok(())
+    }
+
+    /// Tells about a recovery journal newer than `source`.
+    pub fn recovery_notice(&self, source: &Path) {
+        let Ok(dir) = self.journal_dir() else {
+            return;
+        };
+        if recovery::newer(&dir, source).is_some() {
+            nvim_oxi::api::err_writeln(&format!(
+                "age.nvim: {} has unsaved changes from a previous session, \
+                 `:Age recover` restores them",
+                source.display()
+            ));
+        }
+    }
+
+    /// Replaces the text of the current buffer with its recovery journal.
+    ///
+    /// The buffer has to hold the plaintext, `:Age decrypt` it first. The
+    /// restored text is left unsaved, `u` goes back.
+    fn recover(&self) -> Result<(), AgeError> {
+        let mut buf = nvim_oxi::api::get_current_buf();
+        let tracked = self
+            .buffers
+            .borrow()
+            .get(buf.handle())
+            .map(|state| state.source.clone());
+        let source = match tracked {
+            Some(source) => source,
+            None => {
+                let name = buf.get_name()?;
+                if name
+                    .extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("age"))
+                {
+                    return Err(AgeError::from(format!(
+                        "`:Age decrypt` {} first, then `:Age recover`",
+                        name.display()
+                    )));
+                }
+                name.with_added_extension("age")
+            }
+        };
+
+        let journal = recovery::journal_path(&self.journal_dir()?, &source);
+        if !journal.exists() {
+            return Err(AgeError::from(format!(
+                "no recovery journal for {}",
+                source.display()
+            )));
+        }
+
+        let ciphertext = fs::read(&journal)?;
+        let plaintext = crypt::decrypt_bytes_with(&ciphertext, &self.identities(vec![])?)?;
+        let text = plaintext.strip_suffix('\n').unwrap_or(&plaintext);
+        buf.set_lines(.., false, text.split('\n'))?;
+
+        print!("Recovered {}, `:Age encrypt` to save it", source.display());
+        Ok(())
+    }
+
+    fn track(
+        &self,
+        buf: &nvim_oxi::api::Buffer,
+        source: PathBuf,
+        plaintext: Option<PathBuf>,
+        identities: Vec<String>,
+        recipients: usize,
+        contents: &[u8],
+    ) {
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.retain(|bufnr| nvim_oxi::api::Buffer::from(bufnr).is_valid());
+        buffers.track(
+            buf.handle(),
+            source,
+            plaintext,
+            identities,
+            recipients,
+            contents,
+        );
+    }
+
+    /// What is known about `bufnr` (0 for the current buffer), empty when
+    /// it didn't come from an `.age` file.
+    pub fn status(&self, bufnr: i32) -> Dictionary {
+        let buf = buffer_or_current(bufnr);
+        let diverged = self.diverged(&buf);
+        self.buffers
+            .borrow()
+            .get(buf.handle())
+            .map(|state| state.to_dict(diverged))
+            .unwrap_or_default()
+    }
 
-        Err(AgeError::new(
-            "the field `key_file` in config is missing".to_owned(),
-        ))
+    /// `🔒 age:2 recipients` for `bufnr` (0 for the current buffer),
+    /// empty when it didn't come from an `.age` file.
+    pub fn statusline(&self, bufnr: i32) -> String {
+        let buf = buffer_or_current(bufnr);
+        let diverged = self.diverged(&buf);
+        self.buffers
+            .borrow()
+            .get(buf.handle())
+            .map(|state| state::statusline(state, diverged))
+            .unwrap_or_default()
+    }
+
+    fn diverged(&self, buf: &nvim_oxi::api::Buffer) -> bool {
+        let tick = buf.get_var::<i64>("changedtick").unwrap_or_default();
+        self.buffers.borrow_mut().diverged(buf.handle(), tick, || {
+            buffer_contents(buf).unwrap_or_default()
+        })
+    }
+
+    /// Fires the `User` autocmd for `event`, then the `on_decrypt` or
+    /// `on_encrypt` callback for the `Post` events.
+    fn emit(&self, event: Event, data: &EventData) -> Result<(), AgeError> {
+        events::fire(event, data)?;
+
+        let callback = match event {
+            Event::DecryptPost => self.config.on_decrypt.as_ref(),
+            Event::EncryptPost => self.config.on_encrypt.as_ref(),
+            Event::DecryptPre | Event::EncryptPre => None,
+        };
+        if let Some(callback) = callback {
+            callback
+                .call(data.to_dict())
+                .map_err(|err| AgeError::from(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub fn decrypt_to_string(&self, file_path: String) -> Result<String, AgeError> {
+        let file = ExistingNonAgeFile::try_from(file_path.as_str())?;
+        let data = EventData::default().path(file.path());
+
+        self.emit(Event::DecryptPre, &data)?;
+        let plaintext = decrypt_to_string_with(file.path(), &self.identities(vec![])?)?;
+        self.emit(Event::DecryptPost, &data)?;
+
+        Ok(plaintext)
+    }
+
+    pub fn decrypt_from_string(&self, encrypted: String) -> Result<String, AgeError> {
+        let data = EventData::default();
+
+        self.emit(Event::DecryptPre, &data)?;
+        let plaintext = crypt::decrypt_from_string_with(encrypted, &self.identities(vec![])?)?;
+        self.emit(Event::DecryptPost, &data)?;
+
+        Ok(plaintext)
     }
 
     pub fn decrypt_with_identities(
@@ -193,7 +2178,202 @@ impl App {
         key_files: Vec<String>,
     ) -> Result<String, AgeError> {
         let file = ExistingNonAgeFile::try_from(file_path.as_str())?;
+        let data = EventData::default().path(file.path());
+
+        self.emit(Event::DecryptPre, &data)?;
+        let plaintext = decrypt_to_string(file.path(), key_files)?;
+        self.emit(Event::DecryptPost, &data)?;
+
+        Ok(plaintext)
+    }
+}
+
+/// Asks for a passphrase without echoing it.
+fn prompt_passphrase(prompt: &str) -> Result<SecretString, AgeError> {
+    let passphrase: nvim_oxi::String =
+        nvim_oxi::api::call_function("inputsecret", (prompt.to_owned(),))?;
+    Ok(passphrase.to_string().into())
+}
+
+/// A yes/no `confirm()`, `true` for yes.
+fn confirm(message: &str) -> Result<bool, AgeError> {
+    let answer: i64 = nvim_oxi::api::call_function("confirm", (message, "&Yes\n&No", 2))?;
+    Ok(answer == 1)
+}
+
+/// The mtime of `path`, if it can be read.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// `stdpath(what)`, eg: `state` or `run`
+fn stdpath(what: &str) -> Result<PathBuf, AgeError> {
+    let path: nvim_oxi::String = nvim_oxi::api::call_function("stdpath", (what,))?;
+    Ok(PathBuf::from(path.to_string()))
+}
+
+fn buffer_or_current(bufnr: i32) -> nvim_oxi::api::Buffer {
+    match bufnr {
+        0 => nvim_oxi::api::get_current_buf(),
+        bufnr => nvim_oxi::api::Buffer::from(bufnr),
+    }
+}
+
+/// The text of `buf` as it would be written, with a final newline
+/// unless `'endofline'` and `'fixendofline'` are both off.
+fn buffer_contents(buf: &nvim_oxi::api::Buffer) -> Result<Vec<u8>, AgeError> {
+    let lines = buf
+        .get_lines(.., false)?
+        .map(|line| line.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+
+    // an empty buffer is an empty file
+    if lines.len() == 1 && lines[0].is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let opts = OptionOpts::builder().buffer(buf.clone()).build();
+    let eol: bool = nvim_oxi::api::get_option_value("endofline", &opts)?;
+    let fixeol: bool = nvim_oxi::api::get_option_value("fixendofline", &opts)?;
+
+    let mut contents = lines.join(&b'\n');
+    if eol || fixeol {
+        contents.push(b'\n');
+    }
+    Ok(contents)
+}
+
+/// `vim.diagnostic.severity`
+#[derive(Debug, Clone, Copy)]
+enum Severity {
+    Error = 1,
+    Warn = 2,
+}
+
+/// Whether `identities` unwrap `ciphertext`, a missing identity is a
+/// warning and a broken header an error.
+fn unwrap_block(
+    ciphertext: &[u8],
+    identities: &Result<Vec<BoxedIdentity>, AgeError>,
+) -> Result<bool, (Severity, String)> {
+    let identities = identities.as_ref().map_err(|err| {
+        (
+            Severity::Warn,
+            format!("no identity to check this block: {err}"),
+        )
+    })?;
+    crypt::can_unwrap(ciphertext, identities)
+        .map_err(|err| (Severity::Error, format!("not an age file: {err}")))
+}
+
+/// A `vim.diagnostic` entry for rows `start..=end` of `lines`.
+fn diagnostic(
+    lines: &[String],
+    start: usize,
+    end: usize,
+    severity: Severity,
+    message: String,
+) -> Dictionary {
+    let mut dict = Dictionary::new();
+    dict.insert("lnum", start as i64);
+    dict.insert("end_lnum", end as i64);
+    dict.insert("col", 0);
+    dict.insert("end_col", lines.get(end).map_or(0, String::len) as i64);
+    dict.insert("severity", severity as i64);
+    dict.insert("source", "age");
+    dict.insert("message", message);
+    dict
+}
+
+/// Sections of `buf` by its `'filetype'`, or its name.
+fn syntax(buf: &nvim_oxi::api::Buffer) -> Result<Syntax, AgeError> {
+    let opts = OptionOpts::builder().buffer(buf.clone()).build();
+    let filetype: String = nvim_oxi::api::get_option_value("filetype", &opts)?;
+    Syntax::from_filetype(&filetype)
+        .or_else(|| Syntax::from_path(&buf.get_name().ok()?))
+        .ok_or_else(|| AgeError::from("Sections are only found in Markdown and Org buffers"))
+}
+
+fn buffer_lines(buf: &nvim_oxi::api::Buffer) -> Result<Vec<String>, AgeError> {
+    Ok(buf
+        .get_lines(.., false)?
+        .map(|line| line.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Applies `edits` to `buf`, from the bottom up so the line numbers hold.
+fn apply_edits(buf: &nvim_oxi::api::Buffer, edits: &[sections::Edit]) -> Result<(), AgeError> {
+    let mut buf = buf.clone();
+    for edit in edits.iter().rev() {
+        buf.set_lines(
+            edit.lines.clone(),
+            true,
+            edit.text.iter().map(String::as_str),
+        )?;
+    }
+    Ok(())
+}
+
+/// Buffer-local autocmds of YAML, JSON and TOML buffers with encrypted
+/// values.
+const VALUES_GROUP: &str = "age_values";
+
+/// Makes `:w` of `buf` go through `:Age encrypt-values`.
+fn watch_values(buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+    unwatch_values(buf)?;
+    let opts = CreateAutocmdOpts::builder()
+        .group(values_group()?)
+        .buffer(buf.clone())
+        .nested(true)
+        .desc("age.nvim: encrypt ENC[age,...] values")
+        .command("execute 'Age encrypt-values -o' fnameescape(expand('<afile>:p'))")
+        .build();
+    nvim_oxi::api::create_autocmd(["BufWriteCmd"], &opts)?;
+    Ok(())
+}
+
+fn unwatch_values(buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+    let opts = ClearAutocmdsOpts::builder()
+        .group(values_group()?)
+        .buffer(buf.clone())
+        .build();
+    nvim_oxi::api::clear_autocmds(&opts)?;
+    Ok(())
+}
+
+fn values_group() -> Result<u32, AgeError> {
+    let opts = CreateAugroupOpts::builder().clear(false).build();
+    Ok(nvim_oxi::api::create_augroup(VALUES_GROUP, &opts)?)
+}
+
+/// Keeps nvim from offering to reload `buf` after its file was written
+/// without it.
+fn settle(buf: &nvim_oxi::api::Buffer) -> Result<(), AgeError> {
+    let group = values_group()?;
+    let opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .buffer(buf.clone())
+        .once(true)
+        .command("let v:fcs_choice = ''")
+        .build();
+    nvim_oxi::api::create_autocmd(["FileChangedShell"], &opts)?;
+    let result = nvim_oxi::api::command(&format!("checktime {}", buf.handle()));
+
+    // only this check, a later change on disk is reported as usual
+    let opts = ClearAutocmdsOpts::builder()
+        .group(group)
+        .buffer(buf.clone())
+        .events(["FileChangedShell"])
+        .build();
+    nvim_oxi::api::clear_autocmds(&opts)?;
+    Ok(result?)
+}
 
-        decrypt_to_string(file.path(), key_files)
+/// `encrypted_keys` from the config, matched against keys and paths.
+fn encrypted_keys(patterns: &[String]) -> Result<GlobSet, AgeError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
     }
+    Ok(builder.build()?)
 }
@@ -3,7 +3,7 @@
 // ## General note
 //
 // - Functions declared here are supose to be independent.
-// - Only decryption functions will form part of public api.
+// - Everything `pub` here is part of the library api (`age_nvim::crypt`).
 // - While providing `key_file` through cmd args. user is supose
 //   to provide full path like `/home/user/.config/age/keys.txt`
 //   yet we takes some efforts to convert other forms into full path
@@ -26,6 +26,12 @@ use std::path::Path;
 
 use crate::error::AgeError;
 
+/// a single loaded identity (private key)
+pub type BoxedIdentity = Box<dyn age::Identity + Send + Sync>;
+
+/// a single loaded recipient (public key)
+pub type BoxedRecipient = Box<dyn age::Recipient + Send + 'static>;
+
 /// encrypts the obtained plaintext `&[u8]` into ciphertext `Vec<u8>`.
 /// with many `Recipient` (not key file/files)
 fn encrypt<'a>(
@@ -46,32 +52,21 @@ fn encrypt<'a>(
 }
 
 /// encrypts the contents of obtained file `&Path` into ciphertext `Vec<u8>`
-/// Recipient's are taken from `key_files`
-fn encrypt_file(path: &Path, key_files: Vec<String>) -> Result<Vec<u8>, AgeError> {
+/// with already loaded `recipients`
+fn encrypt_file_with(path: &Path, recipients: &[BoxedRecipient]) -> Result<Vec<u8>, AgeError> {
     let mut file = std::fs::File::open(path)?;
     let mut plaintext = Vec::new();
     file.read_to_end(&mut plaintext)?;
 
-    let recipients = load_recipients(key_files)?;
-
     encrypt(
         recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
         &plaintext[..],
     )
 }
 
-/// encrypts the contents of obtained file `&Path` into ciphertext `String`
-/// Recipient's are taken from `key_files`
-///
-/// same as `encrypt_file` but returns `String`
-fn encrypt_path_to_string(path: &Path, key_files: Vec<String>) -> Result<String, AgeError> {
-    Ok(String::from_utf8(encrypt_file(path, key_files)?)?)
-}
-
 /// encrypts the `String` provided into ciphertext `String`
 /// Recipient's are taken from `key_files`
-#[allow(dead_code)]
-fn encrypt_to_string(plaintext: String, key_files: Vec<String>) -> Result<String, AgeError> {
+pub fn encrypt_to_string(plaintext: String, key_files: Vec<String>) -> Result<String, AgeError> {
     let binding = load_recipients(key_files)?;
     let keys = binding.iter().map(|f| f.as_ref() as &dyn age::Recipient);
 
@@ -82,20 +77,56 @@ fn encrypt_to_string(plaintext: String, key_files: Vec<String>) -> Result<String
 
 /// encrypts the contents of obtained file `&Path` into the output file pointed
 /// Recipient's are taken from `key_files`
-pub(super) fn encrypt_to_file(
+pub fn encrypt_to_file(
     plaintext: &Path,
     out_path: &Path,
     key_files: Vec<String>,
 ) -> Result<(), AgeError> {
-    let encrypted = encrypt_path_to_string(plaintext, key_files)?;
+    encrypt_to_file_with(plaintext, out_path, &load_recipients(key_files)?)
+}
+
+/// encrypts the contents of obtained file `&Path` into the output file pointed
+/// with already loaded `recipients`
+pub fn encrypt_to_file_with(
+    plaintext: &Path,
+    out_path: &Path,
+    recipients: &[BoxedRecipient],
+) -> Result<(), AgeError> {
+    let encrypted = encrypt_file_with(plaintext, recipients)?;
+
+    write_encrypted(out_path, &encrypted)
+}
+
+/// encrypts the `plaintext` bytes (eg: buffer contents) into the output file
+/// pointed with already loaded `recipients`, the plaintext never touches disk
+pub fn encrypt_bytes_to_file_with(
+    plaintext: &[u8],
+    out_path: &Path,
+    recipients: &[BoxedRecipient],
+) -> Result<(), AgeError> {
+    write_encrypted(out_path, &encrypt_bytes_with(plaintext, recipients)?)
+}
+
+/// encrypts the `plaintext` bytes into armored ciphertext `Vec<u8>`
+/// with already loaded `recipients`
+pub fn encrypt_bytes_with(
+    plaintext: &[u8],
+    recipients: &[BoxedRecipient],
+) -> Result<Vec<u8>, AgeError> {
+    encrypt(
+        recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+        plaintext,
+    )
+}
 
+fn write_encrypted(out_path: &Path, encrypted: &[u8]) -> Result<(), AgeError> {
     // Write encrypted content to the output file
     let mut output_file = OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
         .open(out_path)?;
-    output_file.write_all(encrypted.as_bytes())?;
+    output_file.write_all(encrypted)?;
 
     Ok(())
 }
@@ -119,49 +150,149 @@ fn decrypt<'a, R: std::io::Read>(
     Ok(decrypted_bytes)
 }
 
-/// decrypts the encrypted content of file provided into plaintext `Vec<u8>`
+/// decrypts the encrypted content of file provided into plaintext `String`
 /// Identity's are taken from `key_files`
-fn decrypt_files(path: &Path, filenames: Vec<String>) -> Result<Vec<u8>, AgeError> {
-    let file = std::fs::File::open(path)?;
-    let binding = load_identities(filenames)?;
-    let keys = binding.iter().map(|f| f.as_ref() as &dyn age::Identity);
-
-    decrypt(keys, file)
+pub fn decrypt_to_string(input_path: &Path, key_files: Vec<String>) -> Result<String, AgeError> {
+    decrypt_to_string_with(input_path, &load_identities(key_files)?)
 }
 
 /// decrypts the encrypted content of file provided into plaintext `String`
-/// Identity's are taken from `key_files`
-pub(super) fn decrypt_to_string(
+/// with already loaded `identities`
+pub fn decrypt_to_string_with(
     input_path: &Path,
-    key_files: Vec<String>,
+    identities: &[BoxedIdentity],
 ) -> Result<String, AgeError> {
-    let decrypted = decrypt_files(input_path, key_files)?;
+    let file = std::fs::File::open(input_path)?;
+    let keys = identities.iter().map(|f| f.as_ref() as &dyn age::Identity);
 
-    Ok(String::from_utf8(decrypted)?)
+    Ok(String::from_utf8(decrypt(keys, file)?)?)
 }
 
 /// decrypts the `String` provided into plaintext `String`
 /// Identity's are taken from `key_files`
-pub(super) fn decrypt_from_string(
+pub fn decrypt_from_string(encrypted: String, key_files: Vec<String>) -> Result<String, AgeError> {
+    decrypt_from_string_with(encrypted, &load_identities(key_files)?)
+}
+
+/// decrypts the `String` provided into plaintext `String`
+/// with already loaded `identities`
+pub fn decrypt_from_string_with(
     encrypted: String,
-    key_files: Vec<String>,
+    identities: &[BoxedIdentity],
+) -> Result<String, AgeError> {
+    decrypt_bytes_with(encrypted.as_bytes(), identities)
+}
+
+/// decrypts armored or binary ciphertext bytes into plaintext `String`
+/// with already loaded `identities`
+pub fn decrypt_bytes_with(
+    encrypted: &[u8],
+    identities: &[BoxedIdentity],
 ) -> Result<String, AgeError> {
-    let binding = load_identities(key_files)?;
-    let keys = binding.iter().map(|f| f.as_ref() as &dyn age::Identity);
+    Ok(String::from_utf8(decrypt_binary_with(
+        encrypted, identities,
+    )?)?)
+}
 
-    let decrypted = decrypt(keys, std::io::Cursor::new(encrypted.as_bytes()))?;
+/// decrypts armored or binary ciphertext bytes into plaintext bytes that
+/// need not be text (eg: an undo file) with already loaded `identities`
+pub fn decrypt_binary_with(
+    encrypted: &[u8],
+    identities: &[BoxedIdentity],
+) -> Result<Vec<u8>, AgeError> {
+    let keys = identities.iter().map(|f| f.as_ref() as &dyn age::Identity);
 
-    Ok(String::from_utf8(decrypted)?)
+    decrypt(keys, encrypted)
+}
+
+/// checks whether `encrypted` is an age file encrypted with a passphrase
+/// (eg: a passphrase-protected identity file made with `age -p`)
+pub fn is_passphrase_encrypted(encrypted: &[u8]) -> bool {
+    age::Decryptor::new(age::armor::ArmoredReader::new(encrypted))
+        .map(|decryptor| decryptor.is_scrypt())
+        .unwrap_or(false)
+}
+
+/// checks whether one of `identities` unwraps the file key of `encrypted`,
+/// armored or binary. The payload is not decrypted, a broken header is an
+/// error.
+pub fn can_unwrap(encrypted: &[u8], identities: &[BoxedIdentity]) -> Result<bool, AgeError> {
+    let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(encrypted))?;
+    let keys = identities.iter().map(|f| f.as_ref() as &dyn age::Identity);
+
+    match decryptor.decrypt(keys) {
+        Ok(_) => Ok(true),
+        Err(age::DecryptError::NoMatchingKeys) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// counts the recipients in the header of `encrypted`, armored or binary.
+///
+/// Every recipient has one stanza, grease stanzas (tags ending in `-grease`)
+/// are random padding and not counted.
+pub fn recipient_count(encrypted: &[u8]) -> Result<usize, AgeError> {
+    use std::io::BufRead;
+
+    let mut reader = std::io::BufReader::new(age::armor::ArmoredReader::new(encrypted));
+    let mut line = Vec::new();
+    let mut count = 0;
+
+    reader.read_until(b'\n', &mut line)?;
+    if line != b"age-encryption.org/v1\n" {
+        return Err(AgeError::from("not an age file"));
+    }
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(AgeError::from("age header is truncated"));
+        }
+        if line.starts_with(b"---") {
+            return Ok(count);
+        }
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let tag = stanza.split(|b| b.is_ascii_whitespace()).next();
+            if !tag.is_some_and(|tag| tag.ends_with(b"-grease")) {
+                count += 1;
+            }
+        }
+    }
+}
+
+/// decrypts a passphrase encrypted ciphertext into plaintext `Vec<u8>`
+pub fn decrypt_with_passphrase(
+    encrypted: &[u8],
+    passphrase: age::secrecy::SecretString,
+) -> Result<Vec<u8>, AgeError> {
+    let identity = passphrase_identity(passphrase);
+
+    decrypt(
+        std::iter::once(identity.as_ref() as &dyn age::Identity),
+        encrypted,
+    )
 }
 
 /// decrypts the contents of obtained file `&Path` into the output file pointed
 /// Identity's are taken from `key_files`
-pub(super) fn decrypt_to_file(
+pub fn decrypt_to_file(
     input_path: &Path,
     output_path: &Path,
     filenames: Vec<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let decrypted = decrypt_to_string(input_path, filenames)?;
+    let identities = load_identities(filenames)?;
+
+    decrypt_to_file_with(input_path, output_path, &identities)
+}
+
+/// decrypts the contents of obtained file `&Path` into the output file pointed
+/// with already loaded `identities`
+pub fn decrypt_to_file_with(
+    input_path: &Path,
+    output_path: &Path,
+    identities: &[BoxedIdentity],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let decrypted = decrypt_to_string_with(input_path, identities)?;
 
     // Write decrypted content to the output file
     let mut output_file = OpenOptions::new()
@@ -176,10 +307,8 @@ pub(super) fn decrypt_to_file(
 }
 
 /// get all Recipient's from provided `key_files`
-fn load_recipients(
-    key_files: Vec<String>,
-) -> Result<Vec<Box<dyn age::Recipient + Send + 'static>>, AgeError> {
-    let mut output: Vec<Box<dyn age::Recipient + Send + 'static>> = Vec::new();
+pub fn load_recipients(key_files: Vec<String>) -> Result<Vec<BoxedRecipient>, AgeError> {
+    let mut output: Vec<BoxedRecipient> = Vec::new();
     for path in key_files {
         let full_path = get_full_path(&path)?.to_string_lossy().to_string();
         output.extend(age::IdentityFile::from_file(full_path)?.to_recipients()?);
@@ -188,10 +317,10 @@ fn load_recipients(
 }
 
 /// get all Identity's from provided `key_files`
-fn load_identities(
-    filenames: Vec<String>,
-) -> Result<Vec<Box<dyn age::Identity + Send + Sync>>, Box<dyn std::error::Error>> {
-    let mut output: Vec<Box<dyn age::Identity + Send + Sync>> = Vec::new();
+///
+/// When `$AGE_NVIM_AGENT_SOCK` is set the agent is asked first.
+pub fn load_identities(filenames: Vec<String>) -> Result<Vec<BoxedIdentity>, AgeError> {
+    let mut output: Vec<BoxedIdentity> = agent_identity().into_iter().collect();
     for filename in filenames {
         let full_path = get_full_path(&filename)?.to_string_lossy().to_string();
         output.extend(age::IdentityFile::from_file(full_path)?.into_identities()?);
@@ -199,8 +328,74 @@ fn load_identities(
     Ok(output)
 }
 
+/// get all Identity's from the contents of an identity file
+/// (eg: the value of `$SOPS_AGE_KEY`)
+pub fn parse_identities(contents: &str) -> Result<Vec<BoxedIdentity>, AgeError> {
+    Ok(age::IdentityFile::from_buffer(contents.as_bytes())?.into_identities()?)
+}
+
+/// parses a single recipient, eg: `age1...`
+pub fn parse_recipient(recipient: &str) -> Result<BoxedRecipient, AgeError> {
+    recipient
+        .parse::<age::x25519::Recipient>()
+        .map(|recipient| Box::new(recipient) as BoxedRecipient)
+        .map_err(|err| AgeError::from(format!("invalid recipient `{recipient}`: {err}")))
+}
+
+/// get all Recipient's from recipients files (one recipient per line,
+/// `#` comments and empty lines are ignored), like `age -R`
+pub fn load_recipients_files(filenames: Vec<String>) -> Result<Vec<BoxedRecipient>, AgeError> {
+    let mut output: Vec<BoxedRecipient> = Vec::new();
+    for filename in filenames {
+        let contents = std::fs::read_to_string(get_full_path(&filename)?)?;
+        for line in recipient_lines(&contents) {
+            output.push(parse_recipient(&line)?);
+        }
+    }
+    Ok(output)
+}
+
+/// the recipients in a recipients file, `#` comments and empty lines
+/// are skipped
+pub fn recipient_lines(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// the public keys of the identities in an identity file, like `age-keygen -y`
+pub fn identity_public_keys(contents: &str) -> Result<Vec<String>, AgeError> {
+    let mut output = Vec::new();
+    age::IdentityFile::from_buffer(contents.as_bytes())?.write_recipients_file(&mut output)?;
+
+    Ok(recipient_lines(&String::from_utf8(output)?))
+}
+
+/// the recipient for `age --passphrase`
+pub fn passphrase_recipient(passphrase: age::secrecy::SecretString) -> BoxedRecipient {
+    Box::new(age::scrypt::Recipient::new(passphrase))
+}
+
+/// the identity for files made with `age --passphrase`
+pub fn passphrase_identity(passphrase: age::secrecy::SecretString) -> BoxedIdentity {
+    Box::new(age::scrypt::Identity::new(passphrase))
+}
+
+/// the identity agent at `$AGE_NVIM_AGENT_SOCK`, if set
+pub fn agent_identity() -> Option<BoxedIdentity> {
+    crate::agent::AgentIdentity::from_env().map(|agent| Box::new(agent) as BoxedIdentity)
+}
+
+/// get all Recipient's from the contents of an identity file
+pub fn parse_recipients(contents: &str) -> Result<Vec<BoxedRecipient>, AgeError> {
+    Ok(age::IdentityFile::from_buffer(contents.as_bytes())?.to_recipients()?)
+}
+
 /// tries to converts users input: ~/some/file.txt => /home/user/some/file.txt
-fn get_full_path(input: &str) -> Result<std::path::PathBuf, AgeError> {
+pub(crate) fn get_full_path(input: &str) -> Result<std::path::PathBuf, AgeError> {
     let mut path_buf = std::path::PathBuf::new();
 
     // 1. expand Tilde
@@ -237,8 +432,11 @@ mod test {
 
     use crate::{
         crypt::{
-            decrypt_from_string, decrypt_to_file, decrypt_to_string, encrypt_path_to_string,
-            encrypt_to_file, encrypt_to_string, get_full_path,
+            can_unwrap, decrypt_binary_with, decrypt_from_string, decrypt_to_file,
+            decrypt_to_string, decrypt_with_passphrase, encrypt, encrypt_bytes_to_file_with,
+            encrypt_to_file, encrypt_to_string, get_full_path, identity_public_keys,
+            is_passphrase_encrypted, load_identities, load_recipients, recipient_count,
+            recipient_lines,
         },
         error::AgeError,
     };
@@ -268,7 +466,7 @@ mod test {
         let encrypted = std::path::Path::new("tests/some/dir/file.txt.age");
         let original = std::fs::read_to_string(input)?;
 
-        let e = encrypt_path_to_string(input, key_files.clone())?;
+        let e = encrypt_to_string(original.clone(), key_files.clone())?;
         let df = decrypt_from_string(e, key_files.clone())?;
         assert_eq!(original, df);
 
@@ -372,6 +570,23 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn bytes_roundtrip_to_file() -> Result<(), AgeError> {
+        let f = Fixture::new();
+        let encrypted = f.path("buffer.txt.age");
+        let decrypted = f.path("buffer.txt");
+
+        let recipients = load_recipients(f.key_files())?;
+        encrypt_bytes_to_file_with(b"typed, never saved\n", &encrypted, &recipients)?;
+        decrypt_to_file(&encrypted, &decrypted, f.key_files())?;
+
+        assert_eq!(
+            std::fs::read_to_string(&decrypted).unwrap(),
+            "typed, never saved\n"
+        );
+        Ok(())
+    }
+
     #[test]
     fn file_roundtrip_multiline() -> Result<(), AgeError> {
         let f = Fixture::new();
@@ -480,7 +695,7 @@ mod test {
     }
 
     // ----------------------------------------------------------------
-    // encrypt_path_to_string / decrypt_from_string  (string roundtrip)
+    // decrypt_from_string  (string roundtrip)
     // ----------------------------------------------------------------
 
     #[test]
@@ -489,7 +704,10 @@ mod test {
         let input = f.path("plaintext.txt");
         let original = f.read("plaintext.txt");
 
-        let encrypted = encrypt_path_to_string(&input, f.key_files())?;
+        let output = f.path("plaintext.txt.age");
+
+        encrypt_to_file(&input, &output, f.key_files())?;
+        let encrypted = std::fs::read_to_string(&output)?;
         let decrypted = decrypt_from_string(encrypted, f.key_files())?;
 
         assert_eq!(original, decrypted);
@@ -625,6 +843,61 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn public_keys_of_identity_file() -> Result<(), AgeError> {
+        let f = Fixture::new();
+        let contents = std::fs::read_to_string(&f.key_path)?;
+        let public = contents
+            .lines()
+            .find_map(|line| line.strip_prefix("# public key: "))
+            .unwrap();
+
+        assert_eq!(identity_public_keys(&contents)?, [public]);
+        assert!(identity_public_keys("# only a comment\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn recipients_file_lines() {
+        let lines = recipient_lines("# team\nage1abc\n\n  age1def  \n");
+        assert_eq!(lines, ["age1abc", "age1def"]);
+    }
+
+    #[test]
+    fn counts_recipients() -> Result<(), AgeError> {
+        let alice = Fixture::new();
+        let bob = Fixture::new();
+
+        let one = encrypt_to_string("x".to_owned(), alice.key_files())?;
+        assert_eq!(recipient_count(one.as_bytes())?, 1);
+
+        let mut both = alice.key_files();
+        both.extend(bob.key_files());
+        let two = encrypt_to_string("x".to_owned(), both)?;
+        assert_eq!(recipient_count(two.as_bytes())?, 2);
+
+        assert_eq!(recipient_count(&encrypt_with_passphrase(b"x", "pw"))?, 1);
+        assert!(recipient_count(b"plain text").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn binary_roundtrip() -> Result<(), AgeError> {
+        let f = Fixture::new();
+        let plaintext = [0xff, 0x00, 0xfe, b'\n'];
+
+        let encrypted = encrypt(
+            load_recipients(f.key_files())?
+                .iter()
+                .map(|r| r.as_ref() as &dyn age::Recipient),
+            &plaintext,
+        )?;
+        let decrypted = decrypt_binary_with(&encrypted, &load_identities(f.key_files())?)?;
+
+        assert_eq!(decrypted, plaintext);
+        Ok(())
+    }
+
     #[test]
     fn third_party_cannot_decrypt_multi_recipient() -> Result<(), AgeError> {
         let alice = Fixture::new();
@@ -643,6 +916,68 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn unwraps_only_for_recipients() -> Result<(), AgeError> {
+        let alice = Fixture::new();
+        let eve = Fixture::new();
+        let encrypted = encrypt_to_string("secret".to_owned(), alice.key_files())?;
+
+        assert!(can_unwrap(
+            encrypted.as_bytes(),
+            &load_identities(alice.key_files())?
+        )?);
+        assert!(!can_unwrap(
+            encrypted.as_bytes(),
+            &load_identities(eve.key_files())?
+        )?);
+        assert!(can_unwrap(b"age-encryption.org/v1\nnot a header", &[]).is_err());
+        Ok(())
+    }
+
+    // ----------------------------------------------------------------
+    // Passphrase-protected files
+    // ----------------------------------------------------------------
+    //
+    // eg: identity files made with `age -p`. A low work factor keeps
+    // scrypt fast enough for debug builds.
+    //
+
+    fn encrypt_with_passphrase(plaintext: &[u8], passphrase: &str) -> Vec<u8> {
+        let mut recipient = age::scrypt::Recipient::new(passphrase.to_owned().into());
+        recipient.set_work_factor(2);
+        encrypt(
+            std::iter::once(&recipient as &dyn age::Recipient),
+            plaintext,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn passphrase_roundtrip() -> Result<(), AgeError> {
+        let encrypted = encrypt_with_passphrase(b"AGE-SECRET-KEY-1\n", "hunter2");
+
+        assert!(is_passphrase_encrypted(&encrypted));
+        let decrypted = decrypt_with_passphrase(&encrypted, "hunter2".to_owned().into())?;
+        assert_eq!(decrypted, b"AGE-SECRET-KEY-1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase_wrong_fails() {
+        let encrypted = encrypt_with_passphrase(b"secret", "hunter2");
+
+        assert!(decrypt_with_passphrase(&encrypted, "hunter3".to_owned().into()).is_err());
+    }
+
+    #[test]
+    fn recipient_encrypted_is_not_passphrase_encrypted() {
+        let f = Fixture::new();
+        let encrypted = encrypt_to_string("secret".to_owned(), f.key_files()).unwrap();
+
+        assert!(!is_passphrase_encrypted(encrypted.as_bytes()));
+        assert!(!is_passphrase_encrypted(f.read("key.txt").as_bytes()));
+    }
+
     // ----------------------------------------------------------------
     // get_full_path
     // ----------------------------------------------------------------
@@ -13,6 +13,7 @@ impl std::fmt::Display for AgeError {
     }
 }
 
+#[cfg(feature = "nvim")]
 impl From<AgeError> for nvim_oxi::Error {
     fn from(value: AgeError) -> Self {
         nvim_oxi::Error::Api(nvim_oxi::api::Error::Other(value.to_string()))
@@ -43,7 +44,6 @@ impl From<Box<dyn std::error::Error>> for AgeError {
 
 macro_rules! impl_age_err {
     ($($from:path),* $(,)?) => {
-        impl std::error::Error for AgeError {}
         $(
             impl From<$from> for AgeError {
                 fn from(err: $from) -> Self {
@@ -61,14 +61,18 @@ macro_rules! impl_age_err {
     }
 }
 
+impl std::error::Error for AgeError {}
+
+#[cfg(feature = "nvim")]
+impl_age_err![nvim_oxi::Error, nvim_oxi::api::Error];
+
 impl_age_err![
-    nvim_oxi::Error,
-    nvim_oxi::api::Error,
     std::io::Error,
     std::env::VarError,
     std::str::Utf8Error,
     std::string::FromUtf8Error,
     age::EncryptError,
     age::DecryptError,
-    walkdir::Error,
+    age::IdentityFileConvertError,
+    globset::Error,
 ];
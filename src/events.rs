@@ -0,0 +1,104 @@
+//! `User` autocmds fired around encryption and decryption.
+//!
+//! ```lua
+//!
+//! vim.api.nvim_create_autocmd("User", {
+//!   pattern = "AgeDecryptPost",
+//!   callback = function(ev)
+//!     -- ev.data = { path = "secret.txt.age", plaintext = "secret.txt", bufnr = 3 }
+//!   end,
+//! })
+//!
+//! ```
+//!
+//! `AgeEncryptPre/Post` also carry `recipients`, the number of recipients.
+//! The `on_decrypt` and `on_encrypt` callbacks from `setup()` get the same
+//! table right after the `Post` event.
+
+use std::path::{Path, PathBuf};
+
+use nvim_oxi::api::opts::ExecAutocmdsOpts;
+use nvim_oxi::{Dictionary, Object};
+
+use crate::error::AgeError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    DecryptPre,
+    DecryptPost,
+    EncryptPre,
+    EncryptPost,
+}
+
+impl Event {
+    pub(crate) fn pattern(self) -> &'static str {
+        match self {
+            Event::DecryptPre => "AgeDecryptPre",
+            Event::DecryptPost => "AgeDecryptPost",
+            Event::EncryptPre => "AgeEncryptPre",
+            Event::EncryptPost => "AgeEncryptPost",
+        }
+    }
+}
+
+/// What an event is about, unset fields are left out of `ev.data`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct EventData {
+    /// the `.age` file, read by decrypt or written by encrypt
+    pub(crate) path: Option<PathBuf>,
+    /// the plaintext file, written by decrypt or read by encrypt
+    pub(crate) plaintext: Option<PathBuf>,
+    pub(crate) bufnr: Option<i32>,
+    pub(crate) recipients: Option<usize>,
+}
+
+impl EventData {
+    pub(crate) fn path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    pub(crate) fn plaintext(mut self, plaintext: &Path) -> Self {
+        self.plaintext = Some(plaintext.to_path_buf());
+        self
+    }
+
+    pub(crate) fn bufnr(mut self, bufnr: i32) -> Self {
+        self.bufnr = Some(bufnr);
+        self
+    }
+
+    pub(crate) fn recipients(mut self, recipients: usize) -> Self {
+        self.recipients = Some(recipients);
+        self
+    }
+
+    pub(crate) fn to_dict(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        if let Some(path) = &self.path {
+            dict.insert("path", path.display().to_string());
+        }
+        if let Some(plaintext) = &self.plaintext {
+            dict.insert("plaintext", plaintext.display().to_string());
+        }
+        if let Some(bufnr) = self.bufnr {
+            dict.insert("bufnr", bufnr);
+        }
+        if let Some(recipients) = self.recipients {
+            dict.insert("recipients", recipients as i64);
+        }
+        dict
+    }
+}
+
+/// Runs the `User` autocmds listening for `event`.
+pub(crate) fn fire(event: Event, data: &EventData) -> Result<(), AgeError> {
+    let opts = ExecAutocmdsOpts::builder()
+        .patterns(event.pattern())
+        .modeline(false)
+        .data(Object::from(data.to_dict()))
+        .build();
+
+    nvim_oxi::api::exec_autocmds(["User"], &opts)?;
+    Ok(())
+}
@@ -0,0 +1,546 @@
+//! Parses the arguments of `:Age`, mirroring the `age` CLI.
+//!
+//! ```vim
+//!
+//! :Age encrypt -r age1... -R recipients.txt -i id.txt -a -o out.age
+//! :Age encrypt --passphrase
+//! :Age decrypt -i id.txt -o out.txt
+//! :Age peek secret.txt.age -i id.txt
+//! :Age genkey -o ~/.config/age/keys.txt
+//! :Age generate 32 --charset alnum -o new.age
+//!
+//! ```
+//!
+//! Plain arguments are key files, same as `-i`, so `:Age encrypt keys.txt`
+//! keeps working. For `decrypt` and `peek` a plain `.age` argument is the
+//! file to decrypt instead of the current buffer, like `age -d FILE`. Paths
+//! with spaces can be quoted or escaped with `\`.
+
+use crate::command::Command;
+use crate::error::AgeError;
+
+/// What the value of a flag is, used for completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Value {
+    /// `age1...`
+    Recipient,
+    /// a file to read
+    File,
+    /// a file to write
+    Output,
+    /// anything else, eg: a number
+    Text,
+}
+
+#[derive(Debug)]
+pub(crate) struct Flag {
+    pub(crate) short: &'static str,
+    pub(crate) long: &'static str,
+    pub(crate) value: Option<Value>,
+    encrypt: bool,
+    decrypt: bool,
+    genkey: bool,
+    peek: bool,
+    generate: bool,
+}
+
+impl Flag {
+    /// Whether `command` accepts this flag.
+    pub(crate) fn allowed(&self, command: &Command) -> bool {
+        match command {
+            Command::EncryptFile => self.encrypt,
+            Command::DecryptFile => self.decrypt,
+            Command::GenKey => self.genkey,
+            Command::Peek => self.peek,
+            Command::Generate => self.generate,
+            // the file keeps its own format, values are never armored
+            Command::EncryptValues => self.encrypt && !matches!(self.short, "-a" | "-p"),
+            // sections are edited in the buffer, always armored
+            Command::EncryptSections => self.encrypt && !matches!(self.short, "-a" | "-p" | "-o"),
+            Command::DecryptSections => self.decrypt && self.short != "-o",
+            _ => false,
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        name == self.short || name == self.long
+    }
+}
+
+pub(crate) const FLAGS: [Flag; 8] = [
+    Flag {
+        short: "-r",
+        long: "--recipient",
+        value: Some(Value::Recipient),
+        encrypt: true,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: true,
+    },
+    Flag {
+        short: "-R",
+        long: "--recipients-file",
+        value: Some(Value::File),
+        encrypt: true,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: true,
+    },
+    Flag {
+        short: "-i",
+        long: "--identity",
+        value: Some(Value::File),
+        encrypt: true,
+        decrypt: true,
+        genkey: false,
+        peek: true,
+        generate: false,
+    },
+    Flag {
+        short: "-a",
+        long: "--armor",
+        value: None,
+        encrypt: true,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: false,
+    },
+    Flag {
+        short: "-o",
+        long: "--output",
+        value: Some(Value::Output),
+        encrypt: true,
+        decrypt: true,
+        genkey: true,
+        peek: false,
+        generate: true,
+    },
+    Flag {
+        short: "-p",
+        long: "--passphrase",
+        value: None,
+        encrypt: true,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: false,
+    },
+    Flag {
+        short: "-w",
+        long: "--words",
+        value: Some(Value::Text),
+        encrypt: false,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: true,
+    },
+    Flag {
+        short: "-c",
+        long: "--charset",
+        value: Some(Value::Text),
+        encrypt: false,
+        decrypt: false,
+        genkey: false,
+        peek: false,
+        generate: true,
+    },
+];
+
+/// Looks up a flag by its short or long name.
+pub(crate) fn find(name: &str) -> Option<&'static Flag> {
+    FLAGS.iter().find(|flag| flag.matches(name))
+}
+
+/// Parsed arguments of `:Age encrypt`, `:Age decrypt` and `:Age genkey`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Flags {
+    /// `-r`, recipients given inline
+    pub(crate) recipients: Vec<String>,
+    /// `-R`, files with one recipient per line
+    pub(crate) recipient_files: Vec<String>,
+    /// `-i` and plain arguments
+    pub(crate) identities: Vec<String>,
+    /// `-a`, accepted for familiarity, the output is always armored
+    pub(crate) armor: bool,
+    /// `-o`
+    pub(crate) output: Option<String>,
+    /// `-p`
+    pub(crate) passphrase: bool,
+    /// `decrypt` and `peek`, a plain `.age` argument
+    pub(crate) input: Option<String>,
+    /// `generate`, a plain number
+    pub(crate) length: Option<usize>,
+    /// `-w`
+    pub(crate) words: Option<usize>,
+    /// `-c`
+    pub(crate) charset: Option<String>,
+    /// `:Age!`, not parsed from the arguments
+    pub(crate) force: bool,
+}
+
+impl Flags {
+    pub(crate) fn parse(command: &Command, args: Vec<String>) -> Result<Self, AgeError> {
+        let mut flags = Flags::default();
+        let mut args = args.into_iter();
+        let mut only_files = false;
+
+        while let Some(arg) = args.next() {
+            if only_files || !arg.starts_with('-') || arg == "-" {
+                match command {
+                    Command::GenKey => {
+                        return Err(AgeError::from(format!("unexpected argument `{arg}`")))
+                    }
+                    Command::Generate => {
+                        if flags.length.replace(count(&arg)?).is_some() {
+                            return Err(AgeError::from("the length is given more than once"));
+                        }
+                    }
+                    Command::DecryptFile | Command::Peek if is_age_file(&arg) => {
+                        if flags.input.replace(arg).is_some() {
+                            return Err(AgeError::from("only one file can be decrypted at a time"));
+                        }
+                    }
+                    _ => flags.identities.push(arg),
+                }
+                continue;
+            }
+
+            if arg == "--" {
+                only_files = true;
+                continue;
+            }
+
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_owned())),
+                _ => (arg.as_str(), None),
+            };
+
+            let flag = find(name).ok_or(AgeError::from(format!("unknown flag `{name}`")))?;
+            if !flag.allowed(command) {
+                return Err(AgeError::from(format!(
+                    "`{name}` can't be used with {}",
+                    command.name()
+                )));
+            }
+
+            let value = match flag.value {
+                Some(_) => Some(
+                    inline
+                        .or_else(|| args.next())
+                        .ok_or(AgeError::from(format!("`{name}` needs a value")))?,
+                ),
+                None if inline.is_some() => {
+                    return Err(AgeError::from(format!("`{name}` doesn't take a value")))
+                }
+                None => None,
+            };
+
+            match (flag.short, value) {
+                ("-r", Some(value)) => flags.recipients.push(value),
+                ("-R", Some(value)) => flags.recipient_files.push(value),
+                ("-i", Some(value)) => flags.identities.push(value),
+                ("-o", Some(value)) => {
+                    if flags.output.replace(value).is_some() {
+                        return Err(AgeError::from("`-o` given more than once"));
+                    }
+                }
+                ("-w", Some(value)) => flags.words = Some(count(&value)?),
+                ("-c", Some(value)) => flags.charset = Some(value),
+                ("-a", _) => flags.armor = true,
+                ("-p", _) => flags.passphrase = true,
+                _ => return Err(AgeError::from(format!("`{name}` is not handled"))),
+            }
+        }
+
+        if flags.passphrase
+            && !(flags.recipients.is_empty()
+                && flags.recipient_files.is_empty()
+                && flags.identities.is_empty())
+        {
+            return Err(AgeError::from(
+                "`--passphrase` can't be combined with `-r`, `-R` or `-i`",
+            ));
+        }
+
+        Ok(flags)
+    }
+}
+
+fn count(arg: &str) -> Result<usize, AgeError> {
+    arg.parse()
+        .map_err(|_| AgeError::from(format!("`{arg}` is not a number")))
+}
+
+fn is_age_file(arg: &str) -> bool {
+    std::path::Path::new(arg)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("age"))
+}
+
+/// Splits a command line into arguments.
+///
+/// Whitespace separates arguments unless it is escaped with `\` or inside
+/// single or double quotes.
+pub(crate) fn split(line: &str) -> Result<Vec<String>, AgeError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                current.push(chars.next().unwrap_or('\\'));
+                in_arg = true;
+            }
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(AgeError::from("unterminated quote"));
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use crate::command::Command;
+    use crate::flags::{split, Flags};
+
+    fn parse(command: Command, line: &str) -> Result<Flags, String> {
+        Flags::parse(&command, split(line).unwrap()).map_err(|err| err.to_string())
+    }
+
+    // ## split
+
+    #[test]
+    fn split_on_whitespace() {
+        assert_eq!(
+            split("  -i  a.txt\tb.txt ").unwrap(),
+            ["-i", "a.txt", "b.txt"]
+        );
+        assert!(split("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_keeps_escaped_spaces() {
+        assert_eq!(split(r"-i my\ keys.txt").unwrap(), ["-i", "my keys.txt"]);
+    }
+
+    #[test]
+    fn split_keeps_quoted_spaces() {
+        assert_eq!(
+            split(r#"-i "my keys.txt" 'other keys.txt'"#).unwrap(),
+            ["-i", "my keys.txt", "other keys.txt"]
+        );
+        assert_eq!(split(r#""""#).unwrap(), [""]);
+        assert_eq!(split(r#"a"b c"d"#).unwrap(), ["ab cd"]);
+    }
+
+    #[test]
+    fn split_rejects_unterminated_quote() {
+        assert!(split(r#"-i "my keys.txt"#).is_err());
+    }
+
+    // ## Flags
+
+    #[test]
+    fn encrypt_all_flags() {
+        let flags = parse(
+            Command::EncryptFile,
+            "-r age1abc -R recipients.txt -i id.txt -a -o out.age keys.txt",
+        )
+        .unwrap();
+
+        assert_eq!(flags.recipients, ["age1abc"]);
+        assert_eq!(flags.recipient_files, ["recipients.txt"]);
+        assert_eq!(flags.identities, ["id.txt", "keys.txt"]);
+        assert!(flags.armor);
+        assert_eq!(flags.output.as_deref(), Some("out.age"));
+        assert!(!flags.passphrase);
+    }
+
+    #[test]
+    fn long_flags_and_inline_values() {
+        let flags = parse(
+            Command::EncryptFile,
+            "--recipient age1abc --recipients-file=r.txt --identity=id.txt --output out.age",
+        )
+        .unwrap();
+
+        assert_eq!(flags.recipients, ["age1abc"]);
+        assert_eq!(flags.recipient_files, ["r.txt"]);
+        assert_eq!(flags.identities, ["id.txt"]);
+        assert_eq!(flags.output.as_deref(), Some("out.age"));
+    }
+
+    #[test]
+    fn repeated_flags_accumulate() {
+        let flags = parse(Command::EncryptFile, "-r age1a -r age1b -R a.txt -R b.txt").unwrap();
+
+        assert_eq!(flags.recipients, ["age1a", "age1b"]);
+        assert_eq!(flags.recipient_files, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn plain_arguments_are_identities() {
+        let flags = parse(Command::DecryptFile, "~/keys.txt other.txt").unwrap();
+        assert_eq!(flags.identities, ["~/keys.txt", "other.txt"]);
+    }
+
+    #[test]
+    fn decrypt_takes_an_age_file() {
+        let flags = parse(Command::DecryptFile, "secret.txt.age -i keys.txt").unwrap();
+        assert_eq!(flags.input.as_deref(), Some("secret.txt.age"));
+        assert_eq!(flags.identities, ["keys.txt"]);
+
+        assert!(parse(Command::DecryptFile, "a.age b.age").is_err());
+
+        let flags = parse(Command::DecryptFile, "-i keys.txt -- -odd.age").unwrap();
+        assert_eq!(flags.input.as_deref(), Some("-odd.age"));
+        assert_eq!(flags.identities, ["keys.txt"]);
+    }
+
+    #[test]
+    fn peek_takes_a_file_and_identities() {
+        let flags = parse(Command::Peek, "-i keys.txt secret.age").unwrap();
+        assert_eq!(flags.input.as_deref(), Some("secret.age"));
+        assert_eq!(flags.identities, ["keys.txt"]);
+
+        assert!(parse(Command::Peek, "-o out.txt").is_err());
+    }
+
+    #[test]
+    fn double_dash_ends_flags() {
+        let flags = parse(Command::DecryptFile, "-- -weird-name.txt").unwrap();
+        assert_eq!(flags.identities, ["-weird-name.txt"]);
+    }
+
+    #[test]
+    fn decrypt_rejects_encrypt_only_flags() {
+        for line in ["-r age1abc", "-R r.txt", "-a", "--passphrase"] {
+            let err = parse(Command::DecryptFile, line).unwrap_err();
+            assert!(err.contains("can't be used with decrypt"), "{line}: {err}");
+        }
+    }
+
+    #[test]
+    fn genkey_only_takes_output() {
+        let flags = parse(Command::GenKey, "-o keys.txt").unwrap();
+        assert_eq!(flags.output.as_deref(), Some("keys.txt"));
+
+        assert!(parse(Command::GenKey, "-i keys.txt").is_err());
+        assert!(parse(Command::GenKey, "keys.txt").is_err());
+    }
+
+    #[test]
+    fn generate_length_words_and_charset() {
+        let flags = parse(Command::Generate, "32 --charset alnum -o new.age").unwrap();
+        assert_eq!(flags.length, Some(32));
+        assert_eq!(flags.charset.as_deref(), Some("alnum"));
+        assert_eq!(flags.output.as_deref(), Some("new.age"));
+
+        let flags = parse(Command::Generate, "-w 6").unwrap();
+        assert_eq!(flags.words, Some(6));
+
+        assert!(parse(Command::Generate, "long").is_err());
+        assert!(parse(Command::Generate, "8 9").is_err());
+        assert!(parse(Command::Generate, "--words many").is_err());
+        assert!(parse(Command::EncryptFile, "--words 6").is_err());
+    }
+
+    #[test]
+    fn sections_stay_in_the_buffer() {
+        let flags = parse(Command::EncryptSections, "-R team.txt keys.txt").unwrap();
+        assert_eq!(flags.recipient_files, ["team.txt"]);
+        assert_eq!(flags.identities, ["keys.txt"]);
+        assert!(parse(Command::EncryptSections, "-o notes.md").is_err());
+
+        let flags = parse(Command::DecryptSections, "-i keys.txt").unwrap();
+        assert_eq!(flags.identities, ["keys.txt"]);
+        assert!(parse(Command::DecryptSections, "-r age1abc").is_err());
+    }
+
+    #[test]
+    fn encrypt_values_never_armors() {
+        let flags = parse(Command::EncryptValues, "-r age1abc -o 'my app.yaml'").unwrap();
+        assert_eq!(flags.recipients, ["age1abc"]);
+        assert_eq!(flags.output.as_deref(), Some("my app.yaml"));
+
+        assert!(parse(Command::EncryptValues, "--armor").is_err());
+        assert!(parse(Command::EncryptValues, "--passphrase").is_err());
+    }
+
+    #[test]
+    fn unknown_flag() {
+        let err = parse(Command::EncryptFile, "-x").unwrap_err();
+        assert!(err.contains("unknown flag `-x`"));
+    }
+
+    #[test]
+    fn missing_value() {
+        let err = parse(Command::EncryptFile, "-o").unwrap_err();
+        assert!(err.contains("`-o` needs a value"));
+    }
+
+    #[test]
+    fn switch_with_inline_value() {
+        let err = parse(Command::EncryptFile, "--armor=yes").unwrap_err();
+        assert!(err.contains("doesn't take a value"));
+    }
+
+    #[test]
+    fn output_only_once() {
+        assert!(parse(Command::EncryptFile, "-o a.age -o b.age").is_err());
+    }
+
+    #[test]
+    fn passphrase_alone() {
+        let flags = parse(Command::EncryptFile, "-p -o out.age").unwrap();
+        assert!(flags.passphrase);
+    }
+
+    #[test]
+    fn passphrase_with_recipients_fails() {
+        for line in ["-p -r age1abc", "--passphrase -R r.txt", "-p keys.txt"] {
+            let err = parse(Command::EncryptFile, line).unwrap_err();
+            assert!(err.contains("can't be combined"), "{line}: {err}");
+        }
+    }
+
+    #[test]
+    fn paths_with_spaces() {
+        let flags = parse(
+            Command::EncryptFile,
+            r#"-R "team recipients.txt" -o my\ file.age"#,
+        )
+        .unwrap();
+        assert_eq!(flags.recipient_files, ["team recipients.txt"]);
+        assert_eq!(flags.output.as_deref(), Some("my file.age"));
+    }
+}
@@ -0,0 +1,163 @@
+//! Random passwords and passphrases.
+//!
+//! ```vim
+//!
+//! :Age generate " 24 printable characters at the cursor
+//! :Age generate 32 --charset alnum
+//! :Age generate --charset 'abc123' 12
+//! :Age generate --words 6 " correct-horse-battery-...
+//! :Age generate -o ~/.passage/store/web/github.age " a new encrypted entry
+//!
+//! ```
+//!
+//! Everything comes from `OsRng`, the generator `age` itself uses for file
+//! keys and autogenerated passphrases. Passphrases are `age`'s too: words of
+//! the BIP39 english list joined by `-`.
+
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::OsRng;
+
+use crate::error::AgeError;
+
+/// Characters of a password when no length is given.
+pub const DEFAULT_LENGTH: usize = 24;
+
+/// Words of a passphrase, as many as `age --passphrase` generates.
+pub const DEFAULT_WORDS: usize = 10;
+
+/// Most characters of a password or words of a passphrase, a typo like
+/// `-n 10000000000` shouldn't exhaust memory.
+pub const MAX_COUNT: usize = 1024;
+
+/// Printable ASCII without space, like pass' `[:punct:][:alnum:]`.
+pub const DEFAULT_CHARSET: &str = "print";
+
+const WORDLIST: &str = include_str!("../assets/bip39-english.txt");
+
+/// The characters named by `spec`: `print`, `alnum`, `alpha`, `lower`,
+/// `upper`, `digits`, `hex` or `punct`, several separated by `,`. Anything
+/// else is taken as the characters themselves.
+pub fn charset(spec: &str) -> Result<Vec<char>, AgeError> {
+    let named = spec
+        .split(',')
+        .map(named_charset)
+        .collect::<Option<Vec<_>>>();
+
+    let mut chars = match named {
+        Some(sets) => sets.concat(),
+        None => spec.chars().collect(),
+    };
+    chars.sort_unstable();
+    chars.dedup();
+
+    if chars.is_empty() {
+        return Err(AgeError::from("the charset is empty"));
+    }
+    Ok(chars)
+}
+
+fn named_charset(name: &str) -> Option<Vec<char>> {
+    let range = |from: char, to: char| (from..=to).collect::<Vec<_>>();
+    Some(match name.trim() {
+        "print" => range('!', '~'),
+        "alnum" => [range('a', 'z'), range('A', 'Z'), range('0', '9')].concat(),
+        "alpha" => [range('a', 'z'), range('A', 'Z')].concat(),
+        "lower" => range('a', 'z'),
+        "upper" => range('A', 'Z'),
+        "digits" => range('0', '9'),
+        "hex" => [range('0', '9'), range('a', 'f')].concat(),
+        "punct" => range('!', '~')
+            .into_iter()
+            .filter(char::is_ascii_punctuation)
+            .collect(),
+        _ => return None,
+    })
+}
+
+/// `length` characters drawn uniformly from `charset`.
+pub fn password(length: usize, charset: &[char]) -> Result<String, AgeError> {
+    if length == 0 {
+        return Err(AgeError::from("the length has to be at least 1"));
+    }
+    if length > MAX_COUNT {
+        return Err(AgeError::from(format!(
+            "the length can't be more than {MAX_COUNT}"
+        )));
+    }
+    if charset.is_empty() {
+        return Err(AgeError::from("the charset is empty"));
+    }
+
+    let between = Uniform::from(0..charset.len());
+    Ok((0..length)
+        .map(|_| charset[between.sample(&mut OsRng)])
+        .collect())
+}
+
+/// `words` BIP39 words joined by `-`, like `age --passphrase` generates.
+pub fn passphrase(words: usize) -> Result<String, AgeError> {
+    if words == 0 {
+        return Err(AgeError::from("a passphrase needs at least 1 word"));
+    }
+    if words > MAX_COUNT {
+        return Err(AgeError::from(format!(
+            "a passphrase can't have more than {MAX_COUNT} words"
+        )));
+    }
+
+    let list = WORDLIST.lines().collect::<Vec<_>>();
+    let between = Uniform::from(0..list.len());
+    Ok((0..words)
+        .map(|_| list[between.sample(&mut OsRng)])
+        .collect::<Vec<_>>()
+        .join("-"))
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use crate::generate::{charset, passphrase, password, DEFAULT_CHARSET, MAX_COUNT, WORDLIST};
+
+    #[test]
+    fn named_and_literal_charsets() {
+        assert_eq!(charset(DEFAULT_CHARSET).unwrap().len(), 94);
+        assert_eq!(charset("alnum").unwrap().len(), 62);
+        assert_eq!(charset("punct").unwrap().len(), 32);
+        assert_eq!(charset("digits,hex").unwrap().len(), 16);
+        assert_eq!(charset("lower, upper").unwrap(), charset("alpha").unwrap());
+        assert_eq!(charset("aabc").unwrap(), ['a', 'b', 'c']);
+        // not all names, so literal
+        assert_eq!(charset("hex,z").unwrap().len(), 5);
+        assert!(charset("").is_err());
+    }
+
+    #[test]
+    fn passwords_use_the_charset() {
+        let chars = charset("hex").unwrap();
+        let generated = password(64, &chars).unwrap();
+        assert_eq!(generated.chars().count(), 64);
+        assert!(generated.chars().all(|c| chars.contains(&c)));
+        assert_ne!(generated, password(64, &chars).unwrap());
+
+        assert!(password(0, &chars).is_err());
+        assert_eq!(password(MAX_COUNT, &chars).unwrap().len(), MAX_COUNT);
+        assert!(password(MAX_COUNT + 1, &chars).is_err());
+        assert!(password(8, &[]).is_err());
+        assert_eq!(password(3, &['x']).unwrap(), "xxx");
+    }
+
+    #[test]
+    fn passphrases_like_age() {
+        assert_eq!(WORDLIST.lines().count(), 2048);
+
+        let generated = passphrase(10).unwrap();
+        let words = generated.split('-').collect::<Vec<_>>();
+        assert_eq!(words.len(), 10);
+        assert!(words
+            .iter()
+            .all(|word| WORDLIST.lines().any(|w| w == *word)));
+        assert_ne!(generated, passphrase(10).unwrap());
+        assert!(passphrase(0).is_err());
+        assert!(passphrase(MAX_COUNT + 1).is_err());
+    }
+}
@@ -0,0 +1,605 @@
+//! Locates the identity (private key) to use when no key files are given
+//! explicitly.
+//!
+//! Sources are tried in this order, the first one found wins:
+//!
+//! 1. `key_file` from `setup()`
+//! 2. `key_cmd` from `setup()` (identity read from the command's stdout)
+//! 3. `$AGE_IDENTITY`, then `$SOPS_AGE_KEY_FILE` (path to a key file)
+//! 4. `$SOPS_AGE_KEY` (key file contents, nothing on disk)
+//! 5. `$XDG_CONFIG_HOME/sops/age/keys.txt`, then `$XDG_CONFIG_HOME/age/keys.txt`
+//!    (`XDG_CONFIG_HOME` defaults to `~/.config`)
+//!
+//! The same variables are used by `sops`, so CI containers and dotfiles
+//! that are already set up for it work without any `setup()` call.
+
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use age::secrecy::{ExposeSecret, SecretString};
+
+use crate::crypt::{self, BoxedIdentity, BoxedRecipient};
+use crate::error::AgeError;
+use crate::recipients::{self, Origin, RecipientSet};
+
+/// Environment variables holding a path to an identity file.
+const FILE_VARS: [&str; 2] = ["AGE_IDENTITY", "SOPS_AGE_KEY_FILE"];
+
+/// Environment variable holding the identity file contents.
+const INLINE_VAR: &str = "SOPS_AGE_KEY";
+
+/// Default key file locations, relative to `$XDG_CONFIG_HOME`.
+const DEFAULT_PATHS: [&str; 2] = ["sops/age/keys.txt", "age/keys.txt"];
+
+/// Where the identity came from.
+pub enum IdentitySource {
+    /// `key_file` from `setup()`
+    Config(PathBuf),
+    /// output of `key_cmd` from `setup()`
+    Command {
+        argv: Vec<String>,
+        contents: SecretString,
+    },
+    /// a path taken from an environment variable
+    EnvFile { var: &'static str, path: PathBuf },
+    /// identity file contents taken from an environment variable
+    EnvInline {
+        var: &'static str,
+        contents: SecretString,
+    },
+    /// one of the standard locations
+    Default(PathBuf),
+}
+
+impl IdentitySource {
+    /// Finds the identity source, see module docs for the lookup order.
+    pub fn discover(key_file: &str, key_cmd: &KeyCmd) -> Result<Self, AgeError> {
+        Self::discover_with(key_file, key_cmd, |var| std::env::var(var).ok())
+    }
+
+    /// Same as `discover`, but only takes `key_cmd`'s cached output and
+    /// never runs it, for background checks that mustn't prompt.
+    pub fn discover_cached(key_file: &str, key_cmd: &KeyCmd) -> Result<Self, AgeError> {
+        if key_file.is_empty() && !key_cmd.is_empty() {
+            let contents = key_cmd
+                .output
+                .borrow()
+                .clone()
+                .ok_or_else(|| AgeError::from("`key_cmd` hasn't run yet"))?;
+            return Ok(Self::Command {
+                argv: key_cmd.argv.clone(),
+                contents,
+            });
+        }
+        Self::discover(key_file, key_cmd)
+    }
+
+    /// Same as `discover` but reads the environment through `env`.
+    fn discover_with(
+        key_file: &str,
+        key_cmd: &KeyCmd,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, AgeError> {
+        let env = |var: &str| env(var).filter(|value| !value.trim().is_empty());
+
+        if !key_file.is_empty() {
+            return Ok(Self::Config(key_file.into()));
+        }
+
+        if !key_cmd.is_empty() {
+            return Ok(Self::Command {
+                argv: key_cmd.argv.clone(),
+                contents: key_cmd.output()?,
+            });
+        }
+
+        for var in FILE_VARS {
+            if let Some(path) = env(var) {
+                return Ok(Self::EnvFile {
+                    var,
+                    path: path.into(),
+                });
+            }
+        }
+
+        if let Some(contents) = env(INLINE_VAR) {
+            return Ok(Self::EnvInline {
+                var: INLINE_VAR,
+                contents: contents.into(),
+            });
+        }
+
+        let config_home = env("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+        if let Some(config_home) = config_home {
+            for path in DEFAULT_PATHS {
+                let path = config_home.join(path);
+                if path.is_file() {
+                    return Ok(Self::Default(path));
+                }
+            }
+        }
+
+        Err(AgeError::new(format!(
+            "no identity found: set `key_file` or `key_cmd` in setup(), ${}, ${} or ${}, \
+             or create ~/.config/{}",
+            FILE_VARS[0], FILE_VARS[1], INLINE_VAR, DEFAULT_PATHS[0]
+        )))
+    }
+
+    /// Loads the identities (private keys).
+    pub fn identities(&self) -> Result<Vec<BoxedIdentity>, AgeError> {
+        match self {
+            Self::Command { contents, .. } | Self::EnvInline { contents, .. } => {
+                let mut output: Vec<BoxedIdentity> = crypt::agent_identity().into_iter().collect();
+                output.extend(crypt::parse_identities(contents.expose_secret())?);
+                Ok(output)
+            }
+            _ => crypt::load_identities(vec![self.path_string()]),
+        }
+    }
+
+    /// Loads the recipients (public keys) matching the identities.
+    pub fn recipients(&self) -> Result<Vec<BoxedRecipient>, AgeError> {
+        match self {
+            Self::Command { contents, .. } | Self::EnvInline { contents, .. } => {
+                crypt::parse_recipients(contents.expose_secret())
+            }
+            _ => crypt::load_recipients(vec![self.path_string()]),
+        }
+    }
+
+    /// The public keys (`age1...`) matching the identities.
+    pub fn public_keys(&self) -> Result<Vec<String>, AgeError> {
+        crypt::identity_public_keys(&String::from_utf8(self.raw()?)?)
+    }
+
+    /// Reads the identity file as is, it may be passphrase-protected.
+    pub fn raw(&self) -> Result<Vec<u8>, AgeError> {
+        match self {
+            Self::Command { contents, .. } | Self::EnvInline { contents, .. } => {
+                Ok(contents.expose_secret().as_bytes().to_vec())
+            }
+            _ => Ok(std::fs::read(crypt::get_full_path(&self.path_string())?)?),
+        }
+    }
+
+    fn path_string(&self) -> String {
+        match self {
+            Self::Config(path) | Self::EnvFile { path, .. } | Self::Default(path) => {
+                path.to_string_lossy().to_string()
+            }
+            Self::Command { .. } | Self::EnvInline { .. } => String::new(),
+        }
+    }
+}
+
+/// `key_cmd` from `setup()`, eg: `{ "pass", "show", "age/key" }`
+///
+/// The command is run without a shell and its stdout is parsed as an
+/// identity file. The output only lives in memory, when `cache` is set it
+/// is kept for the rest of the session (until `setup()` is called again).
+#[derive(Debug, Default)]
+pub struct KeyCmd {
+    argv: Vec<String>,
+    cache: bool,
+    output: RefCell<Option<SecretString>>,
+}
+
+impl KeyCmd {
+    pub fn new(argv: Vec<String>, cache: bool) -> Self {
+        Self {
+            argv,
+            cache,
+            output: RefCell::new(None),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argv.is_empty()
+    }
+
+    /// stdout of the command, from the cache if possible
+    fn output(&self) -> Result<SecretString, AgeError> {
+        if let Some(output) = self.output.borrow().as_ref() {
+            return Ok(output.clone());
+        }
+
+        let output = self.run()?;
+        if self.cache {
+            self.output.replace(Some(output.clone()));
+        }
+        Ok(output)
+    }
+
+    fn run(&self) -> Result<SecretString, AgeError> {
+        let (program, args) = self
+            .argv
+            .split_first()
+            .ok_or(AgeError::new("`key_cmd` is empty".to_owned()))?;
+
+        let output = std::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|err| AgeError::from(format!("`key_cmd` failed to start: {err}")))?;
+
+        if !output.status.success() {
+            return Err(AgeError::new(format!(
+                "Error: `key_cmd` exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(String::from_utf8(output.stdout)?.into())
+    }
+}
+
+/// The recipients to encrypt `age_file` to without Neovim: `explicit` ones,
+/// else its sidecar or the nearest `.age-recipients`, else the public key of
+/// the discovered identity. `recipient_rules` only exist in `setup()`.
+pub fn recipients_for(
+    age_file: Option<&Path>,
+    explicit: Vec<String>,
+) -> Result<Vec<BoxedRecipient>, AgeError> {
+    let fallback = || {
+        let source = IdentitySource::discover("", &KeyCmd::default())?;
+        Ok(RecipientSet::new(
+            source.public_keys()?,
+            Origin::Identity(source.to_string()),
+        ))
+    };
+    let set = match age_file {
+        Some(age_file) => recipients::resolve(age_file, explicit, &[], fallback)?,
+        None if !explicit.is_empty() => RecipientSet::new(explicit, Origin::Flags),
+        None => fallback()?,
+    };
+    set.load()
+}
+
+/// Human readable description, used by `:checkhealth`.
+impl Display for IdentitySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(path) => write!(f, "`key_file` from setup(): {}", path.display()),
+            Self::Command { argv, .. } => write!(f, "`key_cmd` from setup(): {}", argv.join(" ")),
+            Self::EnvFile { var, path } => write!(f, "${}: {}", var, path.display()),
+            Self::EnvInline { var, .. } => write!(f, "${} (inline key)", var),
+            Self::Default(path) => write!(f, "default location: {}", path.display()),
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use age::secrecy::ExposeSecret;
+    use tempfile::TempDir;
+
+    use crate::identity::{recipients_for, IdentitySource, KeyCmd};
+    use crate::recipients;
+
+    // Layout:
+    //   <tmp>/
+    //     key.txt                      <- generated age identity file
+    //     home/.config/...             <- created on demand by `default`
+    struct Fixture {
+        dir: TempDir,
+        key: String,
+        env: HashMap<&'static str, String>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().expect("failed to create temp dir");
+            let identity = age::x25519::Identity::generate();
+            let key = identity.to_string().expose_secret().to_owned();
+            std::fs::write(dir.path().join("key.txt"), &key).unwrap();
+
+            let mut env = HashMap::new();
+            env.insert(
+                "HOME",
+                dir.path().join("home").to_string_lossy().to_string(),
+            );
+
+            Self { dir, key, env }
+        }
+
+        fn key_path(&self) -> String {
+            self.dir
+                .path()
+                .join("key.txt")
+                .to_string_lossy()
+                .to_string()
+        }
+
+        fn set(&mut self, var: &'static str, value: &str) {
+            self.env.insert(var, value.to_owned());
+        }
+
+        fn default(&self, relative: &str) -> std::path::PathBuf {
+            let path = self.dir.path().join("home/.config").join(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &self.key).unwrap();
+            path
+        }
+
+        fn discover(&self, key_file: &str) -> Result<IdentitySource, crate::error::AgeError> {
+            self.discover_cmd(key_file, &KeyCmd::default())
+        }
+
+        fn discover_cmd(
+            &self,
+            key_file: &str,
+            key_cmd: &KeyCmd,
+        ) -> Result<IdentitySource, crate::error::AgeError> {
+            IdentitySource::discover_with(key_file, key_cmd, |var| self.env.get(var).cloned())
+        }
+
+        /// a stand-in for `pass show ...`, prints the key and counts its runs
+        fn script(&self, body: &str) -> Vec<String> {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = self.dir.path().join("key_cmd.sh");
+            std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            vec![path.to_string_lossy().to_string()]
+        }
+
+        fn runs(&self) -> usize {
+            std::fs::read_to_string(self.dir.path().join("runs"))
+                .map(|s| s.lines().count())
+                .unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn config_key_file_wins() {
+        let mut f = Fixture::new();
+        f.set("AGE_IDENTITY", "/somewhere/else.txt");
+        f.set("SOPS_AGE_KEY", &f.key.clone());
+
+        let source = f.discover(&f.key_path()).unwrap();
+        assert!(matches!(source, IdentitySource::Config(_)));
+        assert_eq!(source.identities().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn age_identity_before_sops_key_file() {
+        let mut f = Fixture::new();
+        f.set("AGE_IDENTITY", &f.key_path());
+        f.set("SOPS_AGE_KEY_FILE", "/somewhere/else.txt");
+
+        let source = f.discover("").unwrap();
+        assert!(matches!(
+            source,
+            IdentitySource::EnvFile {
+                var: "AGE_IDENTITY",
+                ..
+            }
+        ));
+        assert_eq!(source.identities().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sops_key_file_before_inline_key() {
+        let mut f = Fixture::new();
+        f.set("SOPS_AGE_KEY_FILE", &f.key_path());
+        f.set("SOPS_AGE_KEY", &f.key.clone());
+
+        let source = f.discover("").unwrap();
+        assert!(matches!(
+            source,
+            IdentitySource::EnvFile {
+                var: "SOPS_AGE_KEY_FILE",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn empty_variables_are_ignored() {
+        let mut f = Fixture::new();
+        f.set("AGE_IDENTITY", "");
+        f.set("SOPS_AGE_KEY_FILE", "  ");
+        f.set("SOPS_AGE_KEY", &f.key.clone());
+
+        let source = f.discover("").unwrap();
+        assert!(matches!(source, IdentitySource::EnvInline { .. }));
+    }
+
+    #[test]
+    fn inline_key_loads_identities_and_recipients() {
+        let mut f = Fixture::new();
+        f.set("SOPS_AGE_KEY", &format!("# comment\n{}\n", f.key));
+
+        let source = f.discover("").unwrap();
+        assert_eq!(source.identities().unwrap().len(), 1);
+        assert_eq!(source.recipients().unwrap().len(), 1);
+        assert_eq!(source.to_string(), "$SOPS_AGE_KEY (inline key)");
+    }
+
+    #[test]
+    fn inline_key_garbage_fails_to_load() {
+        let mut f = Fixture::new();
+        f.set("SOPS_AGE_KEY", "not a key");
+
+        let source = f.discover("").unwrap();
+        assert!(source.identities().is_err());
+    }
+
+    #[test]
+    fn sops_default_before_age_default() {
+        let f = Fixture::new();
+        let sops = f.default("sops/age/keys.txt");
+        f.default("age/keys.txt");
+
+        match f.discover("").unwrap() {
+            IdentitySource::Default(path) => assert_eq!(path, sops),
+            _ => panic!("expected default location"),
+        }
+    }
+
+    #[test]
+    fn age_default_is_used() {
+        let f = Fixture::new();
+        let age = f.default("age/keys.txt");
+
+        match f.discover("").unwrap() {
+            IdentitySource::Default(path) => assert_eq!(path, age),
+            _ => panic!("expected default location"),
+        }
+    }
+
+    #[test]
+    fn xdg_config_home_overrides_home() {
+        let mut f = Fixture::new();
+        let xdg = f.dir.path().join("xdg");
+        std::fs::create_dir_all(xdg.join("age")).unwrap();
+        std::fs::write(xdg.join("age/keys.txt"), &f.key).unwrap();
+        f.default("sops/age/keys.txt"); // under $HOME, must not be picked
+        f.set("XDG_CONFIG_HOME", &xdg.to_string_lossy());
+
+        match f.discover("").unwrap() {
+            IdentitySource::Default(path) => assert_eq!(path, xdg.join("age/keys.txt")),
+            _ => panic!("expected default location"),
+        }
+    }
+
+    #[test]
+    fn nothing_found_is_an_error() {
+        let f = Fixture::new();
+        let err = f.discover("").err().unwrap();
+        assert!(err.to_string().contains("no identity found"));
+    }
+
+    #[test]
+    fn key_cmd_before_environment() {
+        let mut f = Fixture::new();
+        f.set("AGE_IDENTITY", &f.key_path());
+        let argv = f.script(&format!("cat '{}'", f.key_path()));
+
+        let source = f.discover_cmd("", &KeyCmd::new(argv, true)).unwrap();
+        assert!(matches!(source, IdentitySource::Command { .. }));
+        assert_eq!(source.identities().unwrap().len(), 1);
+        assert_eq!(source.recipients().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn key_file_before_key_cmd() {
+        let f = Fixture::new();
+        let argv = f.script("exit 1");
+
+        let source = f.discover_cmd(&f.key_path(), &KeyCmd::new(argv, true));
+        assert!(matches!(source.unwrap(), IdentitySource::Config(_)));
+        assert_eq!(f.runs(), 0);
+    }
+
+    #[test]
+    fn key_cmd_output_is_cached() {
+        let f = Fixture::new();
+        let runs = f.dir.path().join("runs");
+        let argv = f.script(&format!(
+            "echo run >> '{}'\ncat '{}'",
+            runs.display(),
+            f.key_path()
+        ));
+        let key_cmd = KeyCmd::new(argv, true);
+
+        f.discover_cmd("", &key_cmd).unwrap();
+        f.discover_cmd("", &key_cmd).unwrap();
+        assert_eq!(f.runs(), 1);
+    }
+
+    #[test]
+    fn discover_cached_never_runs_key_cmd() {
+        let f = Fixture::new();
+        let runs = f.dir.path().join("runs");
+        let argv = f.script(&format!(
+            "echo run >> '{}'\ncat '{}'",
+            runs.display(),
+            f.key_path()
+        ));
+        let key_cmd = KeyCmd::new(argv, true);
+
+        assert!(IdentitySource::discover_cached("", &key_cmd).is_err());
+        assert_eq!(f.runs(), 0);
+
+        f.discover_cmd("", &key_cmd).unwrap();
+        let source = IdentitySource::discover_cached("", &key_cmd).unwrap();
+        assert!(matches!(source, IdentitySource::Command { .. }));
+        assert_eq!(f.runs(), 1);
+    }
+
+    #[test]
+    fn key_cmd_without_cache_runs_every_time() {
+        let f = Fixture::new();
+        let runs = f.dir.path().join("runs");
+        let argv = f.script(&format!(
+            "echo run >> '{}'\ncat '{}'",
+            runs.display(),
+            f.key_path()
+        ));
+        let key_cmd = KeyCmd::new(argv, false);
+
+        f.discover_cmd("", &key_cmd).unwrap();
+        f.discover_cmd("", &key_cmd).unwrap();
+        assert_eq!(f.runs(), 2);
+    }
+
+    #[test]
+    fn key_cmd_failure_reports_stderr() {
+        let f = Fixture::new();
+        let argv = f.script("echo 'gpg: decryption failed' >&2\nexit 2");
+
+        let err = f.discover_cmd("", &KeyCmd::new(argv, true)).err().unwrap();
+        assert!(err.to_string().contains("gpg: decryption failed"));
+    }
+
+    #[test]
+    fn key_cmd_failure_is_not_cached() {
+        let f = Fixture::new();
+        let argv = f.script("exit 1");
+        let key_cmd = KeyCmd::new(argv, true);
+
+        assert!(f.discover_cmd("", &key_cmd).is_err());
+        assert!(key_cmd.output.borrow().is_none());
+    }
+
+    #[test]
+    fn key_cmd_missing_program_fails() {
+        let f = Fixture::new();
+        let argv = vec![f.dir.path().join("nope").to_string_lossy().to_string()];
+
+        assert!(f.discover_cmd("", &KeyCmd::new(argv, true)).is_err());
+    }
+
+    #[test]
+    fn recipients_for_a_file() {
+        let f = Fixture::new();
+        let age_file = f.dir.path().join("secret.txt.age");
+        let public = |f: &Fixture| crate::crypt::identity_public_keys(&f.key).unwrap();
+
+        recipients::write_sidecar(&age_file, &public(&f)).unwrap();
+        assert_eq!(
+            recipients_for(Some(&age_file), Vec::new()).unwrap().len(),
+            1
+        );
+
+        let other = Fixture::new();
+        let both = [public(&f), public(&other)].concat();
+        assert_eq!(
+            recipients_for(Some(&age_file), both.clone()).unwrap().len(),
+            2
+        );
+        assert_eq!(recipients_for(None, both).unwrap().len(), 2);
+    }
+}
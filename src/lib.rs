@@ -6,27 +6,107 @@
 //!
 //! It provides APIs for decryption,
 //! configuration handling, and command execution.
+//!
+//! ## As a library
+//!
+//! The encryption core works without Neovim, turn off the default `nvim`
+//! feature to leave the plugin (and `nvim-oxi`) out:
+//!
+//! ```toml
+//! age_nvim = { version = "2", default-features = false }
+//! ```
+//!
+//! ```no_run
+//! use age_nvim::{crypt, identity::{IdentitySource, KeyCmd}};
+//!
+//! # fn main() -> Result<(), age_nvim::error::AgeError> {
+//! // the same identity `:Age` would use
+//! let source = IdentitySource::discover("", &KeyCmd::default())?;
+//! let recipients = source.recipients()?;
+//!
+//! let ciphertext = crypt::encrypt_bytes_with(b"secret", &recipients)?;
+//! let plaintext = crypt::decrypt_binary_with(&ciphertext, &source.identities()?)?;
+//! # Ok(())
+//! # }
+//! ```
 
+#[cfg(feature = "nvim")]
 use std::{cell::RefCell, rc::Rc};
 
+#[cfg(feature = "nvim")]
 use nvim_oxi::{
-    api::{create_user_command, err_writeln, opts::CreateCommandOpts, types::*},
+    api::{
+        create_augroup, create_autocmd, create_user_command, err_writeln,
+        opts::{CreateAugroupOpts, CreateAutocmdOpts, CreateCommandOpts},
+        types::*,
+    },
     Dictionary, Function, Object,
 };
 
+#[cfg(feature = "nvim")]
 use self::{
+    clipboard::Yank,
     command::{completion, Command},
     config::Config,
     core::App,
+    error::AgeError,
 };
 
+pub mod crypt;
+pub mod error;
+pub mod identity;
+pub mod types;
+
+// sidecars and `.age-recipients` are shared with `identity::recipients_for`
+#[cfg_attr(not(feature = "nvim"), allow(dead_code))]
+mod recipients;
+
+// the client is used by `crypt`, the agent itself by the plugin
+#[cfg_attr(not(feature = "nvim"), allow(dead_code))]
+mod agent;
+// blocks are found by `sections`, the one under the cursor by `:Age peek`
+#[cfg_attr(not(feature = "nvim"), allow(dead_code))]
+mod armor;
+#[cfg(feature = "nvim")]
+mod clipboard;
+#[cfg(feature = "nvim")]
 mod command;
+#[cfg(feature = "nvim")]
+mod complete;
+#[cfg(feature = "nvim")]
 mod config;
+#[cfg(feature = "nvim")]
+mod conflict;
+#[cfg(feature = "nvim")]
 mod core;
-mod crypt;
-mod error;
-mod types;
+#[cfg(feature = "nvim")]
+mod events;
+#[cfg(feature = "nvim")]
+mod flags;
+#[cfg(feature = "nvim")]
+mod generate;
+#[cfg(feature = "nvim")]
+mod lock;
+#[cfg(feature = "nvim")]
+mod merge;
+#[cfg(feature = "nvim")]
+mod otp;
+#[cfg(feature = "nvim")]
+mod pass;
+#[cfg(feature = "nvim")]
+mod peek;
+#[cfg(feature = "nvim")]
+mod recovery;
+#[cfg(feature = "nvim")]
+mod sections;
+#[cfg(feature = "nvim")]
+mod state;
+#[cfg(feature = "nvim")]
+mod structured;
+#[cfg(feature = "nvim")]
+mod undo;
 
+#[cfg(feature = "nvim")]
 #[nvim_oxi::plugin]
 fn age() -> Result<Dictionary, nvim_oxi::Error> {
     let config = Config::default();
@@ -38,17 +118,24 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
 
         move |args: CommandArgs| -> Result<(), nvim_oxi::Error> {
             let binding = args.args.unwrap_or_default();
-            let mut parts = binding.split_whitespace();
+            let mut parts = match flags::split(&binding) {
+                Ok(parts) => parts.into_iter(),
+                Err(err) => {
+                    err_writeln(&err.to_string());
+                    return Ok(());
+                }
+            };
 
             let action = parts.next().unwrap_or_default();
-            let command = Command::from_str(action);
-            let raw_args = parts.map(|s| s.to_owned()).collect::<Vec<String>>();
+            let command = Command::from_str(&action);
+            let raw_args = parts.collect::<Vec<String>>();
 
             match command {
                 Some(command) => {
                     app_handle_cmd
-                        .borrow_mut()
-                        .handle_command(command, raw_args)?;
+                        .try_borrow()
+                        .map_err(busy)?
+                        .handle_command(command, raw_args, args.bang)?;
                 }
                 None => err_writeln(&format!("Unknown command: {action}")),
             };
@@ -60,10 +147,218 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
         .desc("Age command")
         .complete(CommandComplete::CustomList(completion()))
         .nargs(CommandNArgs::Any)
+        .bang(true)
         .build();
 
     create_user_command("Age", age_cmd, &opts)?;
 
+    // -- recovery journals
+    //
+    // unsaved edits of decrypted buffers are journaled when idle, like swap
+    // files, and opening an `.age` file tells about a newer journal
+    let group = create_augroup(
+        "age_recovery",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let app_journal = Rc::clone(&app);
+    let journal_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .desc("age.nvim: journal unsaved edits of decrypted buffers")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let result = app_journal
+                .try_borrow()
+                .map_or(Ok(()), |app| app.journal(args.buffer.handle()));
+            if let Err(err) = result {
+                err_writeln(&format!("age.nvim: recovery journal: {err}"));
+            }
+            false
+        })
+        .build();
+    create_autocmd(
+        ["CursorHold", "CursorHoldI", "FocusLost", "BufLeave"],
+        &journal_opts,
+    )?;
+
+    let app_notice = Rc::clone(&app);
+    let notice_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .patterns(["*.age"])
+        .desc("age.nvim: tell about newer recovery journals")
+        .callback(move |args: AutocmdCallbackArgs| {
+            if let Ok(app) = app_notice.try_borrow() {
+                app.recovery_notice(&args.file);
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufReadPost"], &notice_opts)?;
+
+    // -- lock files
+    //
+    // held while a decrypted buffer is loaded
+    let group = create_augroup(
+        "age_lock",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let app_lock = Rc::clone(&app);
+    let lock_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .desc("age.nvim: release locks of decrypted buffers")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let result = app_lock.try_borrow().map_or(Ok(()), |app| {
+                if args.event == "VimLeavePre" {
+                    app.release_locks()
+                } else {
+                    app.release_lock(args.buffer.handle())
+                }
+            });
+            if let Err(err) = result {
+                err_writeln(&format!("age.nvim: can't release lock: {err}"));
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufUnload", "VimLeavePre"], &lock_opts)?;
+
+    // -- `:w` in an `:Age merge` result
+    let app_merge = Rc::clone(&app);
+    let merge_opts = CreateAutocmdOpts::builder()
+        .patterns([format!("{}*", merge::SCHEME).as_str()])
+        .desc("age.nvim: encrypt and stage a merge result")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let result = app_merge
+                .try_borrow()
+                .map_err(|_| AgeError::from("age.nvim is busy with another operation"))
+                .and_then(|app| app.finish_merge(&args.buffer));
+            if let Err(err) = result {
+                err_writeln(&err.to_string());
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufWriteCmd"], &merge_opts)?;
+
+    // -- `:w` in an `:Age pass edit` buffer
+    let app_pass = Rc::clone(&app);
+    let pass_opts = CreateAutocmdOpts::builder()
+        .patterns([format!("{}*", pass::SCHEME).as_str()])
+        .desc("age.nvim: encrypt a password store entry")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let result = app_pass
+                .try_borrow()
+                .map_err(|_| AgeError::from("age.nvim is busy with another operation"))
+                .and_then(|app| app.save_pass(&args.buffer));
+            if let Err(err) = result {
+                err_writeln(&err.to_string());
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufWriteCmd"], &pass_opts)?;
+
+    // -- `ENC[age,...]` values of YAML, JSON and TOML files
+    //
+    // decrypted on read, `:w` of such a buffer encrypts them again
+    let group = create_augroup(
+        "age_values",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let app_values = Rc::clone(&app);
+    let values_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .patterns(["*.yaml", "*.yml", "*.json", "*.toml"])
+        .desc("age.nvim: decrypt ENC[age,...] values")
+        .callback(move |args: AutocmdCallbackArgs| {
+            let result = app_values
+                .try_borrow()
+                .map_err(|_| AgeError::from("age.nvim is busy with another operation"))
+                .and_then(|app| {
+                    if args.event == "BufUnload" {
+                        app.forget_values(args.buffer.handle());
+                        Ok(())
+                    } else {
+                        app.open_values(&args.buffer)
+                    }
+                });
+            if let Err(err) = result {
+                err_writeln(&format!("age.nvim: {}: {err}", args.file.display()));
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufReadPost", "BufUnload"], &values_opts)?;
+
+    // -- sections of Markdown and Org files tagged `:crypt:` or `<!-- age -->`
+    //
+    // decrypted ones stay in the clear in the buffer and are encrypted on disk
+    let group = create_augroup(
+        "age_sections",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let app_sections = Rc::clone(&app);
+    let sections = move |args: AutocmdCallbackArgs| {
+        let result = app_sections
+            .try_borrow()
+            .map_err(|_| AgeError::from("age.nvim is busy with another operation"))
+            .and_then(|app| match args.event.as_str() {
+                "BufWritePre" => app.seal_sections(&args.buffer),
+                "BufWritePost" => app.restore_sections(&args.buffer),
+                "BufUnload" => {
+                    app.forget_sections(args.buffer.handle());
+                    Ok(())
+                }
+                _ => app.open_sections(&args.buffer),
+            });
+        if let Err(err) = result {
+            err_writeln(&format!("age.nvim: {}: {err}", args.file.display()));
+        }
+        false
+    };
+    let sections_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .patterns(["*.md", "*.markdown", "*.org"])
+        .desc("age.nvim: decrypt tagged sections")
+        .callback(sections.clone())
+        .build();
+    create_autocmd(["BufReadPost"], &sections_opts)?;
+
+    // any buffer, sections decrypted by `:Age decrypt-sections` are sealed
+    // whatever its name
+    let sections_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .desc("age.nvim: encrypt tagged sections on disk")
+        .callback(sections)
+        .build();
+    create_autocmd(["BufWritePre", "BufWritePost", "BufUnload"], &sections_opts)?;
+
+    // -- diagnostics for armored blocks
+    //
+    // broken armor, or blocks none of the identities can decrypt
+    let group = create_augroup(
+        "age_diagnostics",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    let app_diagnostics = Rc::clone(&app);
+    let diagnostics_opts = CreateAutocmdOpts::builder()
+        .group(group)
+        .desc("age.nvim: check armored age blocks")
+        .callback(move |args: AutocmdCallbackArgs| {
+            // busy means a command is running, the next write checks again
+            if let Ok(app) = app_diagnostics.try_borrow() {
+                if let Err(err) = app.diagnose(&args.buffer) {
+                    err_writeln(&format!("age.nvim: diagnostics: {err}"));
+                }
+            }
+            false
+        })
+        .build();
+    create_autocmd(["BufReadPost", "BufWritePost"], &diagnostics_opts)?;
+
     // -- setup function for config
     //
     // ```lua
@@ -82,7 +377,7 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
     >([(
         "setup",
         Function::from_fn(move |dict: Dictionary| -> Result<(), nvim_oxi::Error> {
-            app_setup.borrow_mut().setup(dict)
+            app_setup.try_borrow_mut().map_err(busy)?.setup(dict)
         }),
     )]);
 
@@ -113,7 +408,8 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
             Function::<String, Result<String, nvim_oxi::Error>>::from_fn(
                 move |file_path: String| {
                     age_api_01
-                        .borrow()
+                        .try_borrow()
+                        .map_err(busy)?
                         .decrypt_to_string(file_path)
                         .map_err(|err| err.into()) // AgeError into nvim_oxi::Error
                 },
@@ -152,7 +448,8 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
             Result<String, nvim_oxi::Error>,
         >::from_fn(move |(file_path, identity_paths)| {
             age_api_02
-                .borrow()
+                .try_borrow()
+                .map_err(busy)?
                 .decrypt_with_identities(file_path, identity_paths)
                 .map_err(|err| err.into()) // AgeError into nvim_oxi::Error
         })),
@@ -183,12 +480,104 @@ fn age() -> Result<Dictionary, nvim_oxi::Error> {
         Object::from(
             Function::<String, Result<String, nvim_oxi::Error>>::from_fn(move |ctx| {
                 age_api_03
-                    .borrow()
+                    .try_borrow()
+                    .map_err(busy)?
                     .decrypt_from_string(ctx)
                     .map_err(|err| err.into()) // AgeError into nvim_oxi::Error
             }),
         ),
     );
 
+    // # Copy secret
+    //
+    // ```lua
+    //
+    // local age = require("age")
+    //
+    // -- the first line in the clipboard, restored after `clipboard_timeout`
+    // age.copy_secret(vim.fn.expand("~/secrets/token.age"), { line = 1 })
+    //
+    // -- a password store entry, another register, cleared after 10 seconds
+    // age.copy_secret("web/github", { register = "*", timeout = 10 })
+    //
+    // ```
+    //
+    let age_copy = Rc::clone(&app);
+    exports.insert(
+        "copy_secret",
+        Object::from(Function::<
+            (String, Option<Dictionary>),
+            Result<(), nvim_oxi::Error>,
+        >::from_fn(move |(path, opts)| {
+            let yank = opts.as_ref().map(Yank::from_dict).unwrap_or_default();
+            age_copy
+                .try_borrow()
+                .map_err(busy)?
+                .copy_secret(Some(path), yank)
+                .map_err(|err| err.into()) // AgeError into nvim_oxi::Error
+        })),
+    );
+
+    // # Status
+    //
+    // ```lua
+    //
+    // local age = require("age")
+    //
+    // age.status()   -- { source = "secret.txt.age", recipients = 2, diverged = false, ... }
+    // age.status(5)  -- buffer 5
+    //
+    // -- "🔒 age:2 recipients", "" for buffers that aren't secrets
+    // vim.o.statusline = "%f %{v:lua.require('age').statusline()}"
+    //
+    // ```
+    //
+    let age_status = Rc::clone(&app);
+    exports.insert(
+        "status",
+        Object::from(Function::<Option<i32>, Dictionary>::from_fn(
+            move |bufnr: Option<i32>| {
+                age_status
+                    .try_borrow()
+                    .map(|app| app.status(bufnr.unwrap_or_default()))
+                    .unwrap_or_default()
+            },
+        )),
+    );
+
+    let age_statusline = Rc::clone(&app);
+    exports.insert(
+        "statusline",
+        Object::from(Function::<Option<i32>, String>::from_fn(
+            move |bufnr: Option<i32>| {
+                age_statusline
+                    .try_borrow()
+                    .map(|app| app.statusline(bufnr.unwrap_or_default()))
+                    .unwrap_or_default()
+            },
+        )),
+    );
+
+    // # Health
+    //
+    // used by `lua/age/health.lua` for `:checkhealth age`
+    let age_health = Rc::clone(&app);
+    exports.insert(
+        "health",
+        Object::from(Function::<(), Dictionary>::from_fn(move |()| {
+            age_health
+                .try_borrow()
+                .map(|app| app.health())
+                .unwrap_or_default()
+        })),
+    );
+
     Ok(exports)
 }
+
+/// `App` is being reconfigured, eg: an `AgeDecryptPost` autocmd calling
+/// `setup()`. Erroring beats a panic, which aborts Neovim.
+#[cfg(feature = "nvim")]
+fn busy<E>(_: E) -> nvim_oxi::Error {
+    AgeError::from("age.nvim is busy with another operation").into()
+}
@@ -0,0 +1,262 @@
+//! Advisory locks for `.age` files open in a decrypted buffer.
+//!
+//! Decrypted buffers have no swap file, so Neovim's "already being edited"
+//! warning can't fire. While one is open, `secret.age` is locked with
+//! `.secret.age.lock` next to it, holding the PID and host of the owner:
+//!
+//! ```text
+//! pid=4242
+//! host=laptop
+//! ```
+//!
+//! `:Age decrypt` asks before opening a file locked by another instance,
+//! `:Age! decrypt` opens it anyway, the lock stays with its owner. Locks of
+//! processes that are gone from this host are removed, locks from other
+//! hosts can't be checked and always count.
+
+use std::fmt::Display;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::AgeError;
+
+/// Who holds a lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Owner {
+    pub(crate) pid: u32,
+    pub(crate) host: String,
+}
+
+impl Owner {
+    /// This Neovim instance.
+    pub(crate) fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: gethostname::gethostname().to_string_lossy().into_owned(),
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut host = None;
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("pid", value)) => pid = value.trim().parse().ok(),
+                Some(("host", value)) => host = Some(value.trim().to_owned()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            pid: pid?,
+            host: host?,
+        })
+    }
+
+    /// Whether the owner may still be running, only processes on this host
+    /// can be looked at.
+    fn is_alive(&self, current: &Owner) -> bool {
+        self.host != current.host || pid_alive(self.pid)
+    }
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PID {} on {}", self.pid, self.host)
+    }
+}
+
+/// What was found when looking at a lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Status {
+    Free,
+    Ours,
+    /// held by a process that is gone, the lock was removed
+    Stale,
+    Held(Owner),
+}
+
+/// `dir/secret.age` is locked by `dir/.secret.age.lock`.
+pub(crate) fn lock_path(age_file: &Path) -> PathBuf {
+    let name = age_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    age_file.with_file_name(format!(".{name}.lock"))
+}
+
+/// Looks at the lock of `age_file` as `current`, removing it when stale.
+/// Unreadable locks are treated as stale.
+pub(crate) fn check(age_file: &Path, current: &Owner) -> Result<Status, AgeError> {
+    let path = lock_path(age_file);
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Ok(Status::Free);
+    };
+
+    match Owner::parse(&contents) {
+        Some(owner) if owner == *current => Ok(Status::Ours),
+        Some(owner) if owner.is_alive(current) => Ok(Status::Held(owner)),
+        _ => {
+            fs::remove_file(&path)?;
+            Ok(Status::Stale)
+        }
+    }
+}
+
+/// Locks `age_file` for `owner`, `None` when someone else holds it.
+///
+/// The lock is created atomically, an existing one is only replaced after
+/// `check` found it stale, so two instances can't both take it.
+pub(crate) fn acquire(age_file: &Path, owner: &Owner) -> Result<Option<PathBuf>, AgeError> {
+    let path = lock_path(age_file);
+    // once more after removing a stale lock, a third try would be a race
+    for _ in 0..2 {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                file.write_all(format!("pid={}\nhost={}\n", owner.pid, owner.host).as_bytes())?;
+                return Ok(Some(path));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                match check(age_file, owner)? {
+                    Status::Ours => return Ok(Some(path)),
+                    Status::Held(_) => return Ok(None),
+                    Status::Free | Status::Stale => {}
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(None)
+}
+
+/// Removes the lock at `path` if `owner` holds it.
+pub(crate) fn release(path: &Path, owner: &Owner) -> Result<(), AgeError> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Ok(());
+    };
+    if Owner::parse(&contents).as_ref() == Some(owner) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pid_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn pid_alive(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_or(true, |status| status.success())
+}
+
+/// No cheap way to ask, assume it runs.
+#[cfg(not(unix))]
+fn pid_alive(_pid: u32) -> bool {
+    true
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::lock::{acquire, check, lock_path, release, Owner, Status};
+
+    fn owner(pid: u32, host: &str) -> Owner {
+        Owner {
+            pid,
+            host: host.to_owned(),
+        }
+    }
+
+    /// The PID of a process that already exited.
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn lock_next_to_file() {
+        assert_eq!(
+            lock_path(std::path::Path::new("/notes/secret.age")),
+            std::path::Path::new("/notes/.secret.age.lock")
+        );
+    }
+
+    #[test]
+    fn acquire_check_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret.age");
+        let me = Owner::current();
+
+        assert_eq!(check(&file, &me).unwrap(), Status::Free);
+        let path = acquire(&file, &me).unwrap().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("pid={}\nhost={}\n", me.pid, me.host)
+        );
+        assert_eq!(check(&file, &me).unwrap(), Status::Ours);
+        assert_eq!(acquire(&file, &me).unwrap(), Some(path.clone()));
+
+        release(&path, &me).unwrap();
+        assert!(!path.exists());
+        assert_eq!(check(&file, &me).unwrap(), Status::Free);
+    }
+
+    #[test]
+    fn held_by_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret.age");
+        let me = Owner::current();
+
+        // pid 1 always runs
+        let other = owner(1, &me.host);
+        let path = acquire(&file, &other).unwrap().unwrap();
+        assert_eq!(check(&file, &me).unwrap(), Status::Held(other.clone()));
+
+        // not ours to take or release
+        assert_eq!(acquire(&file, &me).unwrap(), None);
+        release(&path, &me).unwrap();
+        assert_eq!(check(&file, &me).unwrap(), Status::Held(other.clone()));
+
+        // another host can't be checked
+        release(&path, &other).unwrap();
+        let remote = owner(dead_pid(), "elsewhere");
+        acquire(&file, &remote).unwrap().unwrap();
+        assert_eq!(acquire(&file, &me).unwrap(), None);
+        assert_eq!(check(&file, &me).unwrap(), Status::Held(remote));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_locks_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret.age");
+        let me = Owner::current();
+
+        let gone = owner(dead_pid(), &me.host);
+        let path = acquire(&file, &gone).unwrap().unwrap();
+        assert_eq!(check(&file, &me).unwrap(), Status::Stale);
+        assert!(!path.exists());
+
+        fs::write(&path, "garbage").unwrap();
+        assert_eq!(check(&file, &me).unwrap(), Status::Stale);
+        assert!(!path.exists());
+
+        // taken over only once found stale
+        acquire(&file, &gone).unwrap().unwrap();
+        assert_eq!(acquire(&file, &me).unwrap(), Some(path.clone()));
+        assert_eq!(check(&file, &me).unwrap(), Status::Ours);
+    }
+}
@@ -0,0 +1,258 @@
+//! Three-way merges of conflicted `.age` files in a git work tree.
+//!
+//! ```vim
+//!
+//! :Age merge " the current file
+//! :Age merge secrets/db.env.age
+//!
+//! ```
+//!
+//! The base, ours and theirs versions are read from the git index
+//! (stages 1, 2 and 3), decrypted in memory and opened side by side in a
+//! new tab as `base | result | theirs`. The result starts out as ours,
+//! `:diffget` what you need and `:w` it: the result is encrypted to the
+//! file's recipients, written over the conflicted file and staged.
+//!
+//! Only the local `git` binary is used.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use crate::error::AgeError;
+
+/// Prefix of the buffer names holding merge results, `:w` on them is
+/// handled by age.nvim.
+pub(crate) const SCHEME: &str = "age-merge://";
+
+/// A version of a conflicted file in the git index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Base = 1,
+    Ours = 2,
+    Theirs = 3,
+}
+
+impl Stage {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Stage::Base => "base",
+            Stage::Ours => "ours",
+            Stage::Theirs => "theirs",
+        }
+    }
+}
+
+/// The versions of a conflicted file, `base` is missing when both sides
+/// added the file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Versions {
+    pub(crate) base: Option<Vec<u8>>,
+    pub(crate) ours: Vec<u8>,
+    pub(crate) theirs: Vec<u8>,
+}
+
+/// Reads the index stages of `path`, which has to be conflicted.
+pub(crate) fn versions(path: &Path) -> Result<Versions, AgeError> {
+    let (dir, name) = split(path)?;
+    let output = git(&dir, &["ls-files", "-u", "-z", "--", &name])?;
+    let stages = parse_unmerged(&String::from_utf8_lossy(&output.stdout));
+
+    let blob = |stage: Stage| -> Result<Option<Vec<u8>>, AgeError> {
+        match stages.iter().find(|(s, _)| *s == stage) {
+            Some((_, sha)) => Ok(Some(git(&dir, &["cat-file", "blob", sha])?.stdout)),
+            None => Ok(None),
+        }
+    };
+    let missing = |stage: Stage| {
+        AgeError::from(format!(
+            "{} has no {} version, is it in a merge conflict?",
+            path.display(),
+            stage.name()
+        ))
+    };
+
+    Ok(Versions {
+        base: blob(Stage::Base)?,
+        ours: blob(Stage::Ours)?.ok_or_else(|| missing(Stage::Ours))?,
+        theirs: blob(Stage::Theirs)?.ok_or_else(|| missing(Stage::Theirs))?,
+    })
+}
+
+/// `git add`s `path`, marking the conflict resolved.
+pub(crate) fn stage(path: &Path) -> Result<(), AgeError> {
+    let (dir, name) = split(path)?;
+    git(&dir, &["add", "--", &name])?;
+    Ok(())
+}
+
+/// `age-merge:///abs/path/secret.age`
+pub(crate) fn result_name(path: &Path) -> String {
+    format!("{SCHEME}{}", path.display())
+}
+
+/// The `.age` file a merge result buffer belongs to.
+pub(crate) fn source_of(name: &str) -> Option<PathBuf> {
+    name.strip_prefix(SCHEME)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Stage and blob id of each `<mode> <sha> <stage>\t<path>` entry printed
+/// by `git ls-files -u -z`.
+fn parse_unmerged(output: &str) -> Vec<(Stage, String)> {
+    output
+        .split('\0')
+        .filter_map(|entry| {
+            let (info, _path) = entry.split_once('\t')?;
+            let mut fields = info.split(' ');
+            let (_mode, sha, stage) = (fields.next()?, fields.next()?, fields.next()?);
+            let stage = match stage {
+                "1" => Stage::Base,
+                "2" => Stage::Ours,
+                "3" => Stage::Theirs,
+                _ => return None,
+            };
+            Some((stage, sha.to_owned()))
+        })
+        .collect()
+}
+
+/// The directory to run git in and the file name relative to it.
+fn split(path: &Path) -> Result<(PathBuf, String), AgeError> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| AgeError::from(format!("{} is not a file", path.display())))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    Ok((dir, name.to_string_lossy().to_string()))
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Output, AgeError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|err| AgeError::from(format!("can't run git: {err}")))?;
+
+    if !output.status.success() {
+        return Err(AgeError::from(format!(
+            "git {}: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::merge::{
+        git, parse_unmerged, result_name, source_of, stage, versions, Stage, Versions,
+    };
+
+    fn run(dir: &Path, args: &[&str]) {
+        let mut full = vec![
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "-c",
+            "commit.gpgsign=false",
+        ];
+        full.extend(args);
+        // a merge with conflicts exits non-zero
+        let _ = git(dir, &full);
+    }
+
+    /// A repository with `file` conflicting between `main` and `other`.
+    fn conflicted(base: Option<&str>) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let file = root.join("secret.age");
+
+        run(root, &["init", "-q", "-b", "main"]);
+        fs::write(root.join("README"), "repo\n").unwrap();
+        if let Some(base) = base {
+            fs::write(&file, base).unwrap();
+        }
+        run(root, &["add", "."]);
+        run(root, &["commit", "-q", "-m", "base"]);
+
+        run(root, &["checkout", "-q", "-b", "other"]);
+        fs::write(&file, "theirs\n").unwrap();
+        run(root, &["add", "."]);
+        run(root, &["commit", "-q", "-m", "theirs"]);
+
+        run(root, &["checkout", "-q", "main"]);
+        fs::write(&file, "ours\n").unwrap();
+        run(root, &["add", "."]);
+        run(root, &["commit", "-q", "-m", "ours"]);
+
+        run(root, &["merge", "-q", "other"]);
+        (dir, file)
+    }
+
+    #[test]
+    fn parses_unmerged_entries() {
+        let output =
+            "100644 aaa 1\tsecret.age\x00100644 bbb 2\tsecret.age\x00100644 ccc 3\tsecret.age\x00";
+        assert_eq!(
+            parse_unmerged(output),
+            [
+                (Stage::Base, "aaa".to_owned()),
+                (Stage::Ours, "bbb".to_owned()),
+                (Stage::Theirs, "ccc".to_owned()),
+            ]
+        );
+        assert!(parse_unmerged("").is_empty());
+    }
+
+    #[test]
+    fn result_names() {
+        let path = Path::new("/repo/secret.age");
+        assert_eq!(result_name(path), "age-merge:///repo/secret.age");
+        assert_eq!(source_of(&result_name(path)), Some(path.to_path_buf()));
+        assert_eq!(source_of("age-merge://"), None);
+        assert_eq!(source_of("/repo/secret.age"), None);
+    }
+
+    #[test]
+    fn reads_all_three_versions_and_stages() {
+        let (_dir, file) = conflicted(Some("base\n"));
+
+        assert_eq!(
+            versions(&file).unwrap(),
+            Versions {
+                base: Some(b"base\n".to_vec()),
+                ours: b"ours\n".to_vec(),
+                theirs: b"theirs\n".to_vec(),
+            }
+        );
+
+        fs::write(&file, "merged\n").unwrap();
+        stage(&file).unwrap();
+        assert!(versions(&file).is_err());
+    }
+
+    #[test]
+    fn added_on_both_sides_has_no_base() {
+        let (_dir, file) = conflicted(None);
+        let versions = versions(&file).unwrap();
+
+        assert_eq!(versions.base, None);
+        assert_eq!(versions.ours, b"ours\n");
+    }
+
+    #[test]
+    fn outside_a_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(versions(&dir.path().join("secret.age")).is_err());
+    }
+}
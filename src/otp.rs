@@ -0,0 +1,346 @@
+//! Time-based one-time passwords (RFC 6238) from `otpauth://` URIs.
+//!
+//! ```vim
+//!
+//! :Age otp " the current file
+//! :Age otp accounts/github.otp.age
+//! :Age! otp web/github " copied to the clipboard instead of shown
+//!
+//! ```
+//!
+//! The file holds a URI as written by authenticator apps and `pass-otp`,
+//! on a line of its own:
+//!
+//! ```text
+//!
+//! otpauth://totp/GitHub:me?secret=JBSWY3DPEHPK3PXP&issuer=GitHub&algorithm=SHA1&digits=6&period=30
+//!
+//! ```
+//!
+//! Codes are computed locally, the secret never leaves memory.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::error::AgeError;
+
+/// The HMAC hash of a TOTP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "SHA1" => Some(Algorithm::Sha1),
+            "SHA256" => Some(Algorithm::Sha256),
+            "SHA512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+/// A TOTP generator, the secret is kept out of `Debug`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Totp {
+    /// `Issuer:account` or just the account, as in the URI
+    pub label: String,
+    pub issuer: Option<String>,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    /// seconds a code is valid
+    pub period: u64,
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for Totp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Totp")
+            .field("label", &self.label)
+            .field("issuer", &self.issuer)
+            .field("algorithm", &self.algorithm)
+            .field("digits", &self.digits)
+            .field("period", &self.period)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Totp {
+    /// Parses `otpauth://totp/LABEL?secret=...`, `algorithm`, `digits` and
+    /// `period` default to `SHA1`, `6` and `30` like in authenticator apps.
+    pub fn from_uri(uri: &str) -> Result<Self, AgeError> {
+        let uri = uri.trim();
+        let rest = uri
+            .get(..10)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("otpauth://"))
+            .map(|_| &uri[10..])
+            .ok_or_else(|| AgeError::from("not an otpauth:// URI"))?;
+        let (kind, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        if !kind.eq_ignore_ascii_case("totp") {
+            return Err(AgeError::from(format!(
+                "only TOTP is supported, not {kind}"
+            )));
+        }
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut totp = Totp {
+            label: percent_decode(label),
+            issuer: None,
+            algorithm: Algorithm::default(),
+            digits: 6,
+            period: 30,
+            secret: Vec::new(),
+        };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = percent_decode(value);
+            let invalid = || AgeError::from(format!("invalid {key} in the otpauth:// URI"));
+            match key.to_ascii_lowercase().as_str() {
+                "secret" => totp.secret = base32_decode(&value).ok_or_else(invalid)?,
+                "issuer" => totp.issuer = Some(value).filter(|issuer| !issuer.is_empty()),
+                "algorithm" => {
+                    totp.algorithm = Algorithm::from_name(&value).ok_or_else(invalid)?;
+                }
+                "digits" => {
+                    totp.digits = value
+                        .parse()
+                        .ok()
+                        .filter(|digits| (1..=10).contains(digits))
+                        .ok_or_else(invalid)?;
+                }
+                "period" => {
+                    totp.period = value
+                        .parse()
+                        .ok()
+                        .filter(|period| *period > 0)
+                        .ok_or_else(invalid)?;
+                }
+                _ => {}
+            }
+        }
+        if totp.secret.is_empty() {
+            return Err(AgeError::from("the otpauth:// URI has no secret"));
+        }
+        if totp.issuer.is_none() {
+            totp.issuer = totp
+                .label
+                .split_once(':')
+                .map(|(issuer, _)| issuer.trim().to_owned());
+        }
+        Ok(totp)
+    }
+
+    /// The first line of `plaintext` that is an `otpauth://` URI.
+    pub fn find(plaintext: &str) -> Result<Self, AgeError> {
+        plaintext
+            .lines()
+            .map(str::trim)
+            .find(|line| {
+                line.get(..10)
+                    .is_some_and(|scheme| scheme.eq_ignore_ascii_case("otpauth://"))
+            })
+            .ok_or_else(|| AgeError::from("no otpauth:// URI in the file"))
+            .and_then(Totp::from_uri)
+    }
+
+    /// The code valid at `time`, seconds since the epoch.
+    pub fn code_at(&self, time: u64) -> Result<String, AgeError> {
+        let counter = (time / self.period).to_be_bytes();
+        let hash = match self.algorithm {
+            Algorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.secret, &counter)?,
+            Algorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.secret, &counter)?,
+            Algorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.secret, &counter)?,
+        };
+
+        // dynamic truncation, RFC 4226 section 5.3
+        let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+        let binary = u32::from_be_bytes([
+            hash[offset],
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]) & 0x7fff_ffff;
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        Ok(format!("{code:0width$}", width = self.digits as usize))
+    }
+
+    /// Seconds the code at `time` stays valid.
+    pub fn remaining_at(&self, time: u64) -> u64 {
+        self.period - time % self.period
+    }
+
+    /// The current code and the seconds it stays valid.
+    pub fn now(&self) -> Result<(String, u64), AgeError> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| AgeError::from(err.to_string()))?
+            .as_secs();
+        Ok((self.code_at(time)?, self.remaining_at(time)))
+    }
+
+    /// `Issuer:account` for messages.
+    pub fn name(&self) -> String {
+        match &self.issuer {
+            Some(issuer) if self.label.is_empty() => issuer.clone(),
+            Some(issuer) if !self.label.starts_with(issuer.as_str()) => {
+                format!("{issuer}:{}", self.label)
+            }
+            _ => self.label.clone(),
+        }
+    }
+}
+
+fn hmac<M: Mac + KeyInit>(key: &[u8], message: &[u8]) -> Result<Vec<u8>, AgeError> {
+    let mut mac = <M as Mac>::new_from_slice(key).map_err(|err| AgeError::from(err.to_string()))?;
+    mac.update(message);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// RFC 4648 base32, case-insensitive, padding and spaces are ignored.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in encoded.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// `%XX` escapes, invalid ones are kept as they are.
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| encoded.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use crate::otp::{base32_decode, percent_decode, Algorithm, Totp};
+
+    fn rfc6238(algorithm: Algorithm, seed: &str) -> Totp {
+        Totp {
+            label: String::new(),
+            issuer: None,
+            algorithm,
+            digits: 8,
+            period: 30,
+            secret: seed.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn rfc6238_test_vectors() {
+        let sha1 = rfc6238(Algorithm::Sha1, "12345678901234567890");
+        let sha256 = rfc6238(Algorithm::Sha256, "12345678901234567890123456789012");
+        let sha512 = rfc6238(
+            Algorithm::Sha512,
+            "1234567890123456789012345678901234567890123456789012345678901234",
+        );
+
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        for (time, expected_sha1, expected_sha256, expected_sha512) in vectors {
+            assert_eq!(sha1.code_at(time).unwrap(), expected_sha1);
+            assert_eq!(sha256.code_at(time).unwrap(), expected_sha256);
+            assert_eq!(sha512.code_at(time).unwrap(), expected_sha512);
+        }
+    }
+
+    #[test]
+    fn parses_otpauth_uris() {
+        // base32 of the RFC 6238 SHA1 seed
+        let totp = Totp::from_uri(
+            "otpauth://totp/ACME%20Co:john@example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=ACME%20Co&algorithm=SHA1&digits=8&period=30",
+        )
+        .unwrap();
+        assert_eq!(totp.label, "ACME Co:john@example.com");
+        assert_eq!(totp.issuer.as_deref(), Some("ACME Co"));
+        assert_eq!(totp.name(), "ACME Co:john@example.com");
+        assert_eq!(totp.code_at(59).unwrap(), "94287082");
+
+        let defaults = Totp::from_uri("otpauth://totp/GitHub:me?secret=jbswy3dpehpk3pxp").unwrap();
+        assert_eq!(defaults.algorithm, Algorithm::Sha1);
+        assert_eq!((defaults.digits, defaults.period), (6, 30));
+        // issuer from the label
+        assert_eq!(defaults.issuer.as_deref(), Some("GitHub"));
+        assert_eq!(defaults.code_at(59).unwrap().len(), 6);
+
+        let sha512 =
+            Totp::from_uri("otpauth://totp/x?secret=JBSWY3DP&algorithm=sha512&period=60").unwrap();
+        assert_eq!(sha512.algorithm, Algorithm::Sha512);
+        assert_eq!(sha512.remaining_at(90), 30);
+        assert_eq!(sha512.code_at(0).unwrap(), sha512.code_at(59).unwrap());
+
+        assert!(Totp::from_uri("otpauth://hotp/x?secret=JBSWY3DP&counter=1").is_err());
+        assert!(Totp::from_uri("otpauth://totp/x?issuer=me").is_err());
+        assert!(Totp::from_uri("otpauth://totp/x?secret=JBSWY3DP&digits=0").is_err());
+        assert!(Totp::from_uri("otpauth://totp/x?secret=JBSWY3DP&algorithm=MD5").is_err());
+        assert!(Totp::from_uri("otpauth://totp/x?secret=not-base32").is_err());
+        assert!(Totp::from_uri("https://example.com").is_err());
+    }
+
+    #[test]
+    fn finds_the_uri_among_other_lines() {
+        let plaintext = "hunter2\nuser: me\n  otpauth://totp/x?secret=JBSWY3DP\n";
+        assert_eq!(Totp::find(plaintext).unwrap().label, "x");
+        assert!(Totp::find("hunter2\n").is_err());
+    }
+
+    #[test]
+    fn secrets_stay_out_of_debug() {
+        let totp = Totp::from_uri("otpauth://totp/x?secret=JBSWY3DP").unwrap();
+        assert!(!format!("{totp:?}").contains("secret"));
+    }
+
+    #[test]
+    fn decodes_base32_and_percent_escapes() {
+        assert_eq!(base32_decode("JBSWY3DPEE======").unwrap(), b"Hello!");
+        assert_eq!(base32_decode("jbsw y3dp ee").unwrap(), b"Hello!");
+        assert_eq!(base32_decode("JBSWY1"), None);
+        assert_eq!(percent_decode("a%20b%3Ac"), "a b:c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
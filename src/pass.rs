@@ -0,0 +1,477 @@
+//! A passage compatible password store.
+//!
+//! ```vim
+//!
+//! :Age pass " same as ls
+//! :Age pass ls [dir]
+//! :Age pass show email/work
+//! :Age pass edit email/work " `:w` encrypts it back
+//! :Age pass insert email/work
+//! :Age pass rm email/work
+//! :Age pass mv email/work email/old
+//!
+//! ```
+//!
+//! The layout and environment are passage's: entries are
+//! `$PASSAGE_DIR/<name>.age` (`~/.passage/store`), decrypted with
+//! `$PASSAGE_IDENTITIES_FILE` (`~/.passage/identities`). An entry is
+//! encrypted to the first of
+//!
+//! 1. `$PASSAGE_RECIPIENTS_FILE`
+//! 2. `$PASSAGE_RECIPIENTS`, separated by whitespace
+//! 3. the nearest `.age-recipients` from its directory up to the store root
+//! 4. the public keys of the identities file
+//!
+//! When there is no identities file, the identity age.nvim discovered is
+//! used instead. Edited entries only live in an `acwrite` buffer, the
+//! plaintext is never written to disk.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::crypt;
+use crate::error::AgeError;
+use crate::recipients::{self, Origin, RecipientSet};
+
+/// Prefix of the buffer names holding entries, `:w` on them is handled by
+/// age.nvim.
+pub(crate) const SCHEME: &str = "age-pass://";
+
+const DIR_VAR: &str = "PASSAGE_DIR";
+const IDENTITIES_VAR: &str = "PASSAGE_IDENTITIES_FILE";
+const RECIPIENTS_FILE_VAR: &str = "PASSAGE_RECIPIENTS_FILE";
+const RECIPIENTS_VAR: &str = "PASSAGE_RECIPIENTS";
+
+/// The store and how its entries are encrypted, read from the environment
+/// like passage does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Store {
+    pub(crate) dir: PathBuf,
+    pub(crate) identities: PathBuf,
+    pub(crate) recipients_file: Option<PathBuf>,
+    pub(crate) recipients: Option<String>,
+}
+
+impl Store {
+    pub(crate) fn from_env() -> Self {
+        Self::from_env_with(|var| std::env::var(var).ok())
+    }
+
+    /// Same as `from_env` but reads the environment through `env`.
+    fn from_env_with(env: impl Fn(&str) -> Option<String>) -> Self {
+        let env = |var: &str| env(var).filter(|value| !value.trim().is_empty());
+        let home = env("HOME")
+            .map(|home| PathBuf::from(home).join(".passage"))
+            .unwrap_or_else(|| PathBuf::from(".passage"));
+        let absolute = |path: PathBuf| std::path::absolute(&path).unwrap_or(path);
+
+        Self {
+            dir: absolute(env(DIR_VAR).map_or_else(|| home.join("store"), PathBuf::from)),
+            identities: absolute(
+                env(IDENTITIES_VAR).map_or_else(|| home.join("identities"), PathBuf::from),
+            ),
+            recipients_file: env(RECIPIENTS_FILE_VAR).map(PathBuf::from),
+            recipients: env(RECIPIENTS_VAR),
+        }
+    }
+
+    /// `email/work` => `<store>/email/work.age`, names can't leave the
+    /// store.
+    pub(crate) fn path(&self, name: &str) -> Result<PathBuf, AgeError> {
+        let name = clean_name(name)?;
+        if name.as_os_str().is_empty() {
+            return Err(AgeError::from("an entry needs a name"));
+        }
+        Ok(self.dir.join(name).with_added_extension("age"))
+    }
+
+    /// A directory of the store, the root for an empty `name`.
+    pub(crate) fn dir_path(&self, name: &str) -> Result<PathBuf, AgeError> {
+        Ok(self.dir.join(clean_name(name)?))
+    }
+
+    /// Whether `path` is inside the store.
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        std::path::absolute(path).is_ok_and(|path| path.starts_with(&self.dir))
+    }
+
+    /// `<store>/email/work.age` => `email/work`
+    pub(crate) fn name_of(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        let name = relative.to_string_lossy();
+        name.strip_suffix(".age").map(str::to_owned)
+    }
+
+    /// The names of all entries below `dir` (the whole store when empty),
+    /// sorted. Hidden files and directories, eg: `.git`, are skipped.
+    pub(crate) fn entries(&self, dir: &str) -> Result<Vec<String>, AgeError> {
+        let root = self.dir_path(dir)?;
+        let mut names = WalkDir::new(&root)
+            .follow_links(true)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || !is_hidden(entry.path()))
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| self.name_of(entry.path()))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    /// The recipients of the entry at `path`, see module docs for the
+    /// order. `fallback` is used when the store has no identities file.
+    pub(crate) fn recipients(
+        &self,
+        path: &Path,
+        fallback: impl FnOnce() -> Result<RecipientSet, AgeError>,
+    ) -> Result<RecipientSet, AgeError> {
+        if let Some(file) = &self.recipients_file {
+            return Ok(RecipientSet::new(
+                recipients::read_recipients_file(file)?,
+                Origin::Env(RECIPIENTS_FILE_VAR),
+            ));
+        }
+
+        if let Some(list) = &self.recipients {
+            return Ok(RecipientSet::new(
+                list.split_whitespace().map(str::to_owned).collect(),
+                Origin::Env(RECIPIENTS_VAR),
+            ));
+        }
+
+        // passage stops at the store root, unlike `recipients::find_dir_file`
+        let path = std::path::absolute(path)?;
+        for dir in path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.dir))
+        {
+            let file = dir.join(recipients::DIR_FILE);
+            if file.is_file() {
+                return Ok(RecipientSet::new(
+                    recipients::read_recipients_file(&file)?,
+                    Origin::DirFile(file),
+                ));
+            }
+        }
+
+        if self.identities.is_file() {
+            return Ok(RecipientSet::new(
+                crypt::identity_public_keys(&fs::read_to_string(&self.identities)?)?,
+                Origin::Identity(self.identities.display().to_string()),
+            ));
+        }
+
+        fallback()
+    }
+
+    /// The files moved by `mv from to`, like `mv(1)`: into `to` when it is
+    /// an existing directory or ends with `/`, entries of a directory keep
+    /// their place below it.
+    pub(crate) fn moves(&self, from: &str, to: &str) -> Result<Vec<(PathBuf, PathBuf)>, AgeError> {
+        let into_dir = to.ends_with('/') || self.dir_path(to)?.is_dir();
+        let target = |base: &Path| -> Result<PathBuf, AgeError> {
+            let to = self.dir_path(to)?;
+            Ok(match (into_dir, base.file_name()) {
+                (true, Some(name)) => to.join(name),
+                _ => to,
+            })
+        };
+
+        let file = self.path(from)?;
+        if file.is_file() {
+            let name = clean_name(from)?;
+            let to = target(&name)?;
+            return Ok(vec![(file, to.with_added_extension("age"))]);
+        }
+
+        let dir = self.dir_path(from)?;
+        if from.is_empty() || !dir.is_dir() {
+            return Err(AgeError::from(format!(
+                "{from} is not in the password store"
+            )));
+        }
+        let to = target(&dir)?;
+        Ok(self
+            .entries(from)?
+            .into_iter()
+            .filter_map(|name| {
+                let file = self.dir.join(format!("{name}.age"));
+                let relative = file.strip_prefix(&dir).ok()?.to_path_buf();
+                Some((file, to.join(relative)))
+            })
+            .collect())
+    }
+
+    /// Deletes the entry or directory `name`, and the directories left
+    /// empty by it.
+    pub(crate) fn remove(&self, name: &str) -> Result<PathBuf, AgeError> {
+        let file = self.path(name)?;
+        let removed = if file.is_file() {
+            fs::remove_file(&file)?;
+            file
+        } else {
+            let dir = self.dir_path(name)?;
+            if name.is_empty() || !dir.is_dir() {
+                return Err(AgeError::from(format!(
+                    "{name} is not in the password store"
+                )));
+            }
+            fs::remove_dir_all(&dir)?;
+            dir
+        };
+        self.prune(&removed);
+        Ok(removed)
+    }
+
+    /// Removes the empty directories above `path`, up to the store root.
+    pub(crate) fn prune(&self, path: &Path) {
+        for dir in path.ancestors().skip(1) {
+            if dir == self.dir || !dir.starts_with(&self.dir) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// `age-pass://email/work`
+pub(crate) fn buffer_name(name: &str) -> String {
+    format!("{SCHEME}{name}")
+}
+
+/// The entry a buffer belongs to.
+pub(crate) fn entry_of(buffer_name: &str) -> Option<&str> {
+    buffer_name
+        .strip_prefix(SCHEME)
+        .filter(|name| !name.is_empty())
+}
+
+/// Normalizes `name`, refusing `..` like passage's `check_sneaky_paths`.
+fn clean_name(name: &str) -> Result<PathBuf, AgeError> {
+    let mut clean = PathBuf::new();
+    for component in Path::new(name.trim_end_matches('/')).components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(AgeError::from(format!(
+                    "{name} is outside of the password store"
+                )));
+            }
+        }
+    }
+    Ok(clean)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::pass::{buffer_name, entry_of, Store};
+    use crate::recipients::{Origin, RecipientSet};
+
+    const ALICE: &str = "age1q2adsrv2cz09uh4fs3u40kj5pq9nqh6gskumfmlavp4amzu6ydxqwuueye";
+    const BOB: &str = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+
+    /// A store in a temporary directory holding `entries`.
+    fn store(entries: &[&str]) -> (tempfile::TempDir, Store) {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = Store {
+            dir: tmp.path().join("store"),
+            identities: tmp.path().join("identities"),
+            ..Store::default()
+        };
+        for entry in entries {
+            let path = store.dir.join(entry);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "ciphertext").unwrap();
+        }
+        (tmp, store)
+    }
+
+    fn fallback() -> Result<RecipientSet, crate::error::AgeError> {
+        Ok(RecipientSet::new(
+            vec![BOB.to_owned()],
+            Origin::Identity("keys.txt".to_owned()),
+        ))
+    }
+
+    #[test]
+    fn environment_like_passage() {
+        let mut env = HashMap::new();
+        env.insert("HOME", "/home/me".to_owned());
+        let store = Store::from_env_with(|var| env.get(var).cloned());
+        assert_eq!(store.dir, Path::new("/home/me/.passage/store"));
+        assert_eq!(store.identities, Path::new("/home/me/.passage/identities"));
+        assert_eq!(store.recipients, None);
+
+        env.insert("PASSAGE_DIR", "/secrets".to_owned());
+        env.insert("PASSAGE_IDENTITIES_FILE", "/keys/me.txt".to_owned());
+        env.insert("PASSAGE_RECIPIENTS", " ".to_owned());
+        let store = Store::from_env_with(|var| env.get(var).cloned());
+        assert_eq!(store.dir, Path::new("/secrets"));
+        assert_eq!(store.identities, Path::new("/keys/me.txt"));
+        assert_eq!(store.recipients, None);
+    }
+
+    #[test]
+    fn names_stay_in_the_store() {
+        let store = Store {
+            dir: PathBuf::from("/store"),
+            ..Store::default()
+        };
+        assert_eq!(
+            store.path("email/work").unwrap(),
+            Path::new("/store/email/work.age")
+        );
+        assert_eq!(
+            store.path("/email/./work").unwrap(),
+            Path::new("/store/email/work.age")
+        );
+        assert!(store.path("../etc/passwd").is_err());
+        assert!(store.path("email/../../x").is_err());
+
+        let path = store.path("email/work").unwrap();
+        assert_eq!(store.name_of(&path).as_deref(), Some("email/work"));
+        assert!(store.contains(&path));
+        assert!(!store.contains(Path::new("/elsewhere/work.age")));
+
+        assert_eq!(entry_of(&buffer_name("email/work")), Some("email/work"));
+        assert_eq!(entry_of("age-pass://"), None);
+    }
+
+    #[test]
+    fn lists_entries() {
+        let (_tmp, store) = store(&[
+            "web/github.age",
+            "email/work.age",
+            "email/home.age",
+            ".git/config",
+            ".hidden/key.age",
+            "email/.age-recipients",
+            "notes.txt",
+        ]);
+        assert_eq!(
+            store.entries("").unwrap(),
+            ["email/home", "email/work", "web/github"]
+        );
+        assert_eq!(
+            store.entries("email").unwrap(),
+            ["email/home", "email/work"]
+        );
+        assert!(store.entries("nothing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn nearest_recipients_file_up_to_the_root() {
+        let (tmp, store) = store(&[]);
+        fs::create_dir_all(store.dir.join("team/infra")).unwrap();
+        // above the store, passage never looks there
+        fs::write(tmp.path().join(".age-recipients"), BOB).unwrap();
+
+        let entry = store.path("team/infra/db").unwrap();
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.origin, Origin::Identity("keys.txt".to_owned()));
+
+        fs::write(store.dir.join(".age-recipients"), format!("{ALICE}\n")).unwrap();
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.recipients, [ALICE]);
+        assert_eq!(
+            set.origin,
+            Origin::DirFile(store.dir.join(".age-recipients"))
+        );
+
+        let team = store.dir.join("team/.age-recipients");
+        fs::write(&team, format!("# team\n{ALICE}\n{BOB}\n")).unwrap();
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.recipients, [ALICE, BOB]);
+        assert_eq!(set.origin, Origin::DirFile(team));
+    }
+
+    #[test]
+    fn environment_and_identities_file() {
+        let (tmp, mut store) = store(&[]);
+        let entry = store.path("db").unwrap();
+        fs::write(
+            &store.identities,
+            fs::read_to_string("./tests/test_key.txt").unwrap(),
+        )
+        .unwrap();
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.recipients, [ALICE]);
+
+        store.recipients = Some(format!("{BOB}  {ALICE}\n"));
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.recipients, [BOB, ALICE]);
+        assert_eq!(set.origin, Origin::Env("PASSAGE_RECIPIENTS"));
+
+        let file = tmp.path().join("recipients.txt");
+        fs::write(&file, BOB).unwrap();
+        store.recipients_file = Some(file);
+        let set = store.recipients(&entry, fallback).unwrap();
+        assert_eq!(set.recipients, [BOB]);
+        assert_eq!(set.origin, Origin::Env("PASSAGE_RECIPIENTS_FILE"));
+    }
+
+    #[test]
+    fn moves_like_mv() {
+        let (_tmp, store) = store(&["email/work.age", "email/old/home.age", "web/x.age"]);
+        let at = |name: &str| store.dir.join(name);
+
+        assert_eq!(
+            store.moves("email/work", "email/job").unwrap(),
+            [(at("email/work.age"), at("email/job.age"))]
+        );
+        // into an existing directory
+        assert_eq!(
+            store.moves("email/work", "web").unwrap(),
+            [(at("email/work.age"), at("web/work.age"))]
+        );
+        assert_eq!(
+            store.moves("email/work", "new/").unwrap(),
+            [(at("email/work.age"), at("new/work.age"))]
+        );
+        assert_eq!(
+            store.moves("email", "mail").unwrap(),
+            [
+                (at("email/old/home.age"), at("mail/old/home.age")),
+                (at("email/work.age"), at("mail/work.age")),
+            ]
+        );
+        assert_eq!(
+            store.moves("email", "web").unwrap(),
+            [
+                (at("email/old/home.age"), at("web/email/old/home.age")),
+                (at("email/work.age"), at("web/email/work.age")),
+            ]
+        );
+        assert!(store.moves("missing", "x").is_err());
+        assert!(store.moves("", "x").is_err());
+    }
+
+    #[test]
+    fn remove_prunes_empty_directories() {
+        let (_tmp, store) = store(&["a/b/c.age", "a/d.age"]);
+
+        store.remove("a/b/c").unwrap();
+        assert!(!store.dir.join("a/b").exists());
+        assert!(store.dir.join("a/d.age").exists());
+
+        store.remove("a").unwrap();
+        assert!(!store.dir.join("a").exists());
+        assert!(store.dir.exists());
+
+        assert!(store.remove("a").is_err());
+        assert!(store.remove("").is_err());
+    }
+}
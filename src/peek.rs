@@ -0,0 +1,105 @@
+//! Read-only view of decrypted text, used by `:Age peek`.
+//!
+//! The text lives only in a `nofile` buffer that is wiped when its window
+//! closes, no plaintext file, swap file or undo file is ever written.
+
+use nvim_oxi::api::opts::{OptionOpts, SetKeymapOpts};
+use nvim_oxi::api::types::{
+    Mode, WindowBorder, WindowConfig, WindowRelativeTo, WindowStyle, WindowTitle,
+};
+use nvim_oxi::api::Buffer;
+
+use crate::error::AgeError;
+
+/// Where `:Age peek` shows the plaintext.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeekStyle {
+    /// a floating window over the editor
+    #[default]
+    Float,
+    /// a split below the current window
+    Split,
+}
+
+impl PeekStyle {
+    pub fn from_str(style: &str) -> Option<Self> {
+        match style {
+            "float" => Some(PeekStyle::Float),
+            "split" => Some(PeekStyle::Split),
+            _ => None,
+        }
+    }
+}
+
+/// Shows `plaintext` in a throwaway buffer, `q` closes it.
+pub(crate) fn open(title: &str, plaintext: &str, style: PeekStyle) -> Result<(), AgeError> {
+    let buf = scratch(plaintext)?;
+
+    match style {
+        PeekStyle::Float => open_float(&buf, title, plaintext)?,
+        PeekStyle::Split => {
+            nvim_oxi::api::command("botright split")?;
+            nvim_oxi::api::get_current_win().set_buf(&buf)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A read-only buffer holding `plaintext` that is wiped once hidden.
+pub(crate) fn scratch(plaintext: &str) -> Result<Buffer, AgeError> {
+    let mut buf = nvim_oxi::api::create_buf(false, true)?;
+    let lines = plaintext.strip_suffix('\n').unwrap_or(plaintext).lines();
+    buf.set_lines(.., false, lines)?;
+
+    let opts = OptionOpts::builder().buffer(buf.clone()).build();
+    nvim_oxi::api::set_option_value("bufhidden", "wipe", &opts)?;
+    nvim_oxi::api::set_option_value("swapfile", false, &opts)?;
+    nvim_oxi::api::set_option_value("undofile", false, &opts)?;
+    nvim_oxi::api::set_option_value("modifiable", false, &opts)?;
+    nvim_oxi::api::set_option_value("readonly", true, &opts)?;
+
+    let keymap = SetKeymapOpts::builder().nowait(true).silent(true).build();
+    buf.set_keymap(Mode::Normal, "q", "<cmd>close<cr>", &keymap)?;
+
+    Ok(buf)
+}
+
+fn open_float(buf: &Buffer, title: &str, plaintext: &str) -> Result<(), AgeError> {
+    let global = OptionOpts::builder().build();
+    let columns: u32 = nvim_oxi::api::get_option_value("columns", &global)?;
+    let rows: u32 = nvim_oxi::api::get_option_value("lines", &global)?;
+
+    // fit the text, up to 80% of the editor
+    let longest = plaintext
+        .lines()
+        .map(|l| l.chars().count())
+        .max()
+        .unwrap_or(0);
+    // tiny editors can't fit the minimums, never ask clamp for min > max
+    let (max_width, max_height) = ((columns * 4 / 5).max(1), (rows * 4 / 5).max(1));
+    let width = (longest.max(title.chars().count() + 2) as u32).clamp(20.min(max_width), max_width);
+    let height = (plaintext.lines().count() as u32).clamp(1, max_height);
+
+    let config = WindowConfig::builder()
+        .relative(WindowRelativeTo::Editor)
+        .width(width)
+        .height(height)
+        .row((rows.saturating_sub(height) / 2) as f64)
+        .col((columns.saturating_sub(width) / 2) as f64)
+        .style(WindowStyle::Minimal)
+        .border(WindowBorder::Rounded)
+        .title(WindowTitle::SimpleString(format!(" {title} ").into()))
+        .build();
+
+    let win = nvim_oxi::api::open_win(buf, true, &config)?;
+
+    // leaving the float closes it, the text shouldn't linger on screen
+    nvim_oxi::api::command(&format!(
+        "autocmd WinLeave <buffer={}> ++once call nvim_win_close({}, v:true)",
+        buf.handle(),
+        win.handle()
+    ))?;
+
+    Ok(())
+}
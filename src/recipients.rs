@@ -0,0 +1,346 @@
+//! Which recipients a file is encrypted to, kept stable across saves.
+//!
+//! The first of these that applies wins:
+//!
+//! 1. `-r`, `-R` or `-i` given to `:Age encrypt`, remembered in a sidecar
+//! 2. the sidecar `<file>.age.recipients` written by an earlier encrypt
+//! 3. `recipient_rules` from `setup()`, the first matching pattern
+//! 4. `.age-recipients` in the file's directory or a parent (like passage)
+//! 5. the public key of the discovered identity
+//!
+//! ```lua
+//!
+//!  require('age').setup({
+//!    recipient_rules = {
+//!      { pattern = "secrets/prod/*", recipients_file = "~/team/prod.txt" },
+//!      { pattern = "*.env.age", recipients = { "age1...", "age1..." } },
+//!    },
+//!  })
+//!
+//! ```
+//!
+//! Sidecars only hold public keys and are meant to be committed next to
+//! the `.age` file.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::GlobBuilder;
+
+use crate::crypt;
+use crate::error::AgeError;
+use crate::types::expand_tilde;
+
+/// Appended to the `.age` file name for its sidecar.
+const SIDECAR_EXT: &str = ".recipients";
+
+/// Recipients file shared by a directory tree, same name as passage uses.
+pub const DIR_FILE: &str = ".age-recipients";
+
+/// Where a recipient set came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// `-r`, `-R` or `-i` on the command line
+    Flags,
+    Sidecar(PathBuf),
+    /// the pattern of a `recipient_rules` entry
+    Rule(String),
+    DirFile(PathBuf),
+    /// the discovered identity, as shown by `:checkhealth age`
+    Identity(String),
+    /// an environment variable, eg: `$PASSAGE_RECIPIENTS`
+    Env(&'static str),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Flags => write!(f, "command line"),
+            Origin::Sidecar(path) | Origin::DirFile(path) => write!(f, "{}", path.display()),
+            Origin::Rule(pattern) => write!(f, "rule `{pattern}`"),
+            Origin::Identity(source) => write!(f, "identity {source}"),
+            Origin::Env(var) => write!(f, "${var}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientSet {
+    pub recipients: Vec<String>,
+    pub origin: Origin,
+}
+
+impl RecipientSet {
+    pub fn new(recipients: Vec<String>, origin: Origin) -> Self {
+        let mut unique: Vec<String> = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            if !unique.contains(&recipient) {
+                unique.push(recipient);
+            }
+        }
+        Self {
+            recipients: unique,
+            origin,
+        }
+    }
+
+    /// Parses the recipients for encrypting.
+    pub fn load(&self) -> Result<Vec<crypt::BoxedRecipient>, AgeError> {
+        self.recipients
+            .iter()
+            .map(|recipient| crypt::parse_recipient(recipient))
+            .collect()
+    }
+}
+
+/// A `recipient_rules` entry from `setup()`.
+///
+/// Patterns without a leading `/` or `~` match anywhere below, `*` doesn't
+/// cross directories, use `**` for that.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rule {
+    pub pattern: String,
+    pub recipients: Vec<String>,
+    pub recipients_file: Option<String>,
+}
+
+impl Rule {
+    pub fn matches(&self, path: &Path) -> Result<bool, AgeError> {
+        let pattern = if self.pattern.starts_with('/') || self.pattern.starts_with('~') {
+            expand_tilde(&self.pattern).display().to_string()
+        } else {
+            format!("**/{}", self.pattern)
+        };
+        let glob = GlobBuilder::new(&pattern).literal_separator(true).build()?;
+
+        Ok(glob.compile_matcher().is_match(path))
+    }
+
+    fn recipients(&self) -> Result<Vec<String>, AgeError> {
+        let mut recipients = self.recipients.clone();
+        if let Some(file) = &self.recipients_file {
+            recipients.extend(read_recipients_file(&expand_tilde(file))?);
+        }
+        Ok(recipients)
+    }
+}
+
+/// `secret.txt.age` keeps its recipients in `secret.txt.age.recipients`.
+pub fn sidecar_path(age_file: &Path) -> PathBuf {
+    let mut name = age_file.as_os_str().to_owned();
+    name.push(SIDECAR_EXT);
+    PathBuf::from(name)
+}
+
+pub fn read_recipients_file(path: &Path) -> Result<Vec<String>, AgeError> {
+    Ok(crypt::recipient_lines(&fs::read_to_string(path)?))
+}
+
+/// Remembers `recipients` for the next encryption of `age_file`.
+pub fn write_sidecar(age_file: &Path, recipients: &[String]) -> Result<PathBuf, AgeError> {
+    let path = sidecar_path(age_file);
+    let name = age_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut contents = format!("# recipients of {name}, used again when it is re-encrypted\n");
+    for recipient in recipients {
+        contents.push_str(recipient);
+        contents.push('\n');
+    }
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// The closest `.age-recipients` in the directory of `path` or above.
+pub fn find_dir_file(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .map(|dir| dir.join(DIR_FILE))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Recipients in `old` that are not in `new`.
+pub fn missing(old: &[String], new: &[String]) -> Vec<String> {
+    old.iter()
+        .filter(|recipient| !new.contains(recipient))
+        .cloned()
+        .collect()
+}
+
+/// The recipients `target` should be encrypted to, see the module docs
+/// for the order. `fallback` is only called when nothing else applies.
+pub fn resolve(
+    target: &Path,
+    explicit: Vec<String>,
+    rules: &[Rule],
+    fallback: impl FnOnce() -> Result<RecipientSet, AgeError>,
+) -> Result<RecipientSet, AgeError> {
+    if !explicit.is_empty() {
+        return Ok(RecipientSet::new(explicit, Origin::Flags));
+    }
+
+    let sidecar = sidecar_path(target);
+    if sidecar.is_file() {
+        let recipients = read_recipients_file(&sidecar)?;
+        if !recipients.is_empty() {
+            return Ok(RecipientSet::new(recipients, Origin::Sidecar(sidecar)));
+        }
+    }
+
+    for rule in rules {
+        if rule.matches(target)? {
+            return Ok(RecipientSet::new(
+                rule.recipients()?,
+                Origin::Rule(rule.pattern.clone()),
+            ));
+        }
+    }
+
+    if let Some(dir_file) = find_dir_file(target) {
+        let recipients = read_recipients_file(&dir_file)?;
+        if !recipients.is_empty() {
+            return Ok(RecipientSet::new(recipients, Origin::DirFile(dir_file)));
+        }
+    }
+
+    fallback()
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::error::AgeError;
+    use crate::recipients::{
+        find_dir_file, missing, resolve, sidecar_path, write_sidecar, Origin, RecipientSet, Rule,
+    };
+
+    fn identity() -> Result<RecipientSet, AgeError> {
+        Ok(RecipientSet::new(
+            vec!["age1me".to_owned()],
+            Origin::Identity("keys.txt".to_owned()),
+        ))
+    }
+
+    fn rule(pattern: &str, recipients: &[&str]) -> Rule {
+        Rule {
+            pattern: pattern.to_owned(),
+            recipients: recipients.iter().map(|r| (*r).to_owned()).collect(),
+            recipients_file: None,
+        }
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("secret.txt.age");
+
+        let path = write_sidecar(&target, &["age1a".to_owned(), "age1b".to_owned()]).unwrap();
+        assert_eq!(path, dir.path().join("secret.txt.age.recipients"));
+        assert_eq!(path, sidecar_path(&target));
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("# recipients of secret.txt.age"));
+
+        let set = resolve(&target, vec![], &[], identity).unwrap();
+        assert_eq!(set.recipients, ["age1a", "age1b"]);
+        assert_eq!(set.origin, Origin::Sidecar(path));
+    }
+
+    #[test]
+    fn explicit_wins_and_dedups() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("secret.txt.age");
+        write_sidecar(&target, &["age1a".to_owned()]).unwrap();
+
+        let set = resolve(
+            &target,
+            vec!["age1x".to_owned(), "age1x".to_owned()],
+            &[],
+            identity,
+        )
+        .unwrap();
+        assert_eq!(set.recipients, ["age1x"]);
+        assert_eq!(set.origin, Origin::Flags);
+    }
+
+    #[test]
+    fn first_matching_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("prod").join("db.env.age");
+        let rules = [
+            rule("staging/*", &["age1s"]),
+            rule("prod/*.env.age", &["age1p"]),
+        ];
+
+        let set = resolve(&target, vec![], &rules, identity).unwrap();
+        assert_eq!(set.recipients, ["age1p"]);
+        assert_eq!(set.origin, Origin::Rule("prod/*.env.age".to_owned()));
+    }
+
+    #[test]
+    fn rule_star_stays_in_directory() {
+        let rule = rule("prod/*", &[]);
+        assert!(rule.matches(Path::new("/repo/prod/a.age")).unwrap());
+        assert!(!rule.matches(Path::new("/repo/prod/nested/a.age")).unwrap());
+
+        let absolute = Rule {
+            pattern: "/repo/**".to_owned(),
+            ..Rule::default()
+        };
+        assert!(absolute
+            .matches(Path::new("/repo/prod/nested/a.age"))
+            .unwrap());
+        assert!(!absolute.matches(Path::new("/other/repo/a.age")).unwrap());
+    }
+
+    #[test]
+    fn rule_with_recipients_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("team.txt");
+        fs::write(&file, "# team\nage1t\n").unwrap();
+
+        let rules = [Rule {
+            pattern: "*.age".to_owned(),
+            recipients: vec!["age1r".to_owned()],
+            recipients_file: Some(file.display().to_string()),
+        }];
+        let set = resolve(&dir.path().join("a.age"), vec![], &rules, identity).unwrap();
+        assert_eq!(set.recipients, ["age1r", "age1t"]);
+    }
+
+    #[test]
+    fn dir_file_from_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("store").join("web");
+        fs::create_dir_all(&nested).unwrap();
+        let dir_file = dir.path().join("store").join(".age-recipients");
+        fs::write(&dir_file, "age1store\n").unwrap();
+
+        let target = nested.join("login.age");
+        assert_eq!(find_dir_file(&target), Some(dir_file.clone()));
+
+        let set = resolve(&target, vec![], &[], identity).unwrap();
+        assert_eq!(set.recipients, ["age1store"]);
+        assert_eq!(set.origin, Origin::DirFile(dir_file));
+    }
+
+    #[test]
+    fn falls_back_to_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let set = resolve(&dir.path().join("a.age"), vec![], &[], identity).unwrap();
+        assert_eq!(set, identity().unwrap());
+    }
+
+    #[test]
+    fn missing_recipients() {
+        let old = ["age1a".to_owned(), "age1b".to_owned()];
+        assert_eq!(missing(&old, &["age1b".to_owned()]), ["age1a"]);
+        assert!(missing(&old, &["age1b".to_owned(), "age1a".to_owned()]).is_empty());
+    }
+}
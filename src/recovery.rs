@@ -0,0 +1,142 @@
+//! Encrypted recovery journals, standing in for swap files.
+//!
+//! Buffers decrypted by age.nvim have `'swapfile'` off, their plaintext
+//! would otherwise end up in the swap directory. Instead, whenever such a
+//! buffer has unsaved changes and Neovim is idle (`CursorHold`, like swap
+//! files) or loses focus, its text is encrypted to the file's recipients
+//! into `stdpath('state')/age/recovery/`.
+//!
+//! ```vim
+//!
+//! :Age recover " restore the journal of the current file after a crash
+//!
+//! ```
+//!
+//! A journal is deleted once the buffer is encrypted again. Set
+//! `recovery = false` in `setup()` to keep swap files instead.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::AgeError;
+
+/// Below `stdpath('state')`.
+const JOURNAL_DIR: &str = "age/recovery";
+
+/// The journal directory below `state` (`stdpath('state')`).
+pub(crate) fn journal_dir(state: &Path) -> PathBuf {
+    state.join(JOURNAL_DIR)
+}
+
+/// The journal of `source` in `dir`, named after the full path with `/`
+/// replaced by `%`, like `'undodir'` files.
+pub(crate) fn journal_path(dir: &Path, source: &Path) -> PathBuf {
+    let source = std::path::absolute(source).unwrap_or_else(|_| source.to_path_buf());
+    dir.join(
+        source
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "%"),
+    )
+}
+
+/// Creates `dir`, only readable by the user.
+pub(crate) fn create_dir(dir: &Path) -> Result<(), AgeError> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// The journal of `source` if it was written after `source` was, a
+/// missing `source` counts as older.
+pub(crate) fn newer(dir: &Path, source: &Path) -> Option<PathBuf> {
+    let journal = journal_path(dir, source);
+    let written = fs::metadata(&journal).and_then(|m| m.modified()).ok()?;
+
+    match fs::metadata(source).and_then(|m| m.modified()) {
+        Ok(saved) if saved >= written => None,
+        _ => Some(journal),
+    }
+}
+
+/// Deletes the journal of `source`, if any.
+pub(crate) fn remove(dir: &Path, source: &Path) -> Result<(), AgeError> {
+    let journal = journal_path(dir, source);
+    if journal.exists() {
+        fs::remove_file(journal)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use crate::recovery::{create_dir, journal_dir, journal_path, newer, remove};
+
+    fn touch(path: &std::path::Path, at: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(at)
+            .unwrap();
+    }
+
+    #[test]
+    fn journal_named_after_full_path() {
+        let dir = journal_dir(std::path::Path::new("/state"));
+        assert_eq!(dir, std::path::Path::new("/state/age/recovery"));
+
+        let journal = journal_path(&dir, std::path::Path::new("/home/me/secret.txt.age"));
+        assert_eq!(journal, dir.join("%home%me%secret.txt.age"));
+    }
+
+    #[test]
+    fn only_newer_journals_are_offered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = journal_dir(tmp.path());
+        create_dir(&dir).unwrap();
+
+        let source = tmp.path().join("secret.txt.age");
+        fs::write(&source, "ciphertext").unwrap();
+        assert_eq!(newer(&dir, &source), None);
+
+        let journal = journal_path(&dir, &source);
+        fs::write(&journal, "journal").unwrap();
+        let now = SystemTime::now();
+        touch(&source, now);
+        touch(&journal, now + Duration::from_secs(5));
+        assert_eq!(newer(&dir, &source), Some(journal.clone()));
+
+        // saved after the journal was written
+        touch(&source, now + Duration::from_secs(10));
+        assert_eq!(newer(&dir, &source), None);
+
+        // the `.age` file is gone, the journal is all there is
+        fs::remove_file(&source).unwrap();
+        assert_eq!(newer(&dir, &source), Some(journal.clone()));
+
+        remove(&dir, &source).unwrap();
+        assert!(!journal.exists());
+        remove(&dir, &source).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn journal_dir_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = journal_dir(tmp.path());
+        create_dir(&dir).unwrap();
+
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
@@ -0,0 +1,554 @@
+//! Markdown and Org documents with some sections encrypted, like org-crypt.
+//!
+//! ```markdown
+//!
+//! # Notes
+//!
+//! ## Bank :crypt:
+//!
+//! -----BEGIN AGE ENCRYPTED FILE-----
+//! YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSB...
+//! -----END AGE ENCRYPTED FILE-----
+//!
+//! ## Groceries
+//!
+//! ```
+//!
+//! A heading tagged `:crypt:`, or marked with `<!-- age -->`, has its body
+//! stored as an armored age block: everything up to the next heading of the
+//! same or a higher level, subheadings included. The heading and the blank
+//! lines around the body stay in the clear. Org headlines take tags the
+//! usual way, `** Bank :finance:crypt:`.
+//!
+//! Nothing here touches a buffer, the changes come back as [`Edit`]s of
+//! whole lines so the caller can apply them where they belong.
+
+use std::ops::Range;
+use std::path::Path;
+
+use crate::armor;
+use crate::crypt::{self, BoxedIdentity, BoxedRecipient};
+use crate::error::AgeError;
+
+/// The tag of sections to encrypt, same as org-crypt's.
+pub const TAG: &str = "crypt";
+
+/// The document formats with sections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Markdown,
+    Org,
+}
+
+impl Syntax {
+    /// From the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match ext.as_str() {
+            "md" | "markdown" => Some(Syntax::Markdown),
+            "org" => Some(Syntax::Org),
+            _ => None,
+        }
+    }
+
+    /// From `'filetype'`.
+    pub fn from_filetype(filetype: &str) -> Option<Self> {
+        match filetype {
+            "markdown" => Some(Syntax::Markdown),
+            "org" => Some(Syntax::Org),
+            _ => None,
+        }
+    }
+
+    /// The level of the heading on `line`, if it is one.
+    fn level(self, line: &str) -> Option<usize> {
+        let (mark, deepest) = match self {
+            Syntax::Markdown => ('#', 6),
+            Syntax::Org => ('*', usize::MAX),
+        };
+        let level = line.chars().take_while(|c| *c == mark).count();
+        let rest = &line[level..];
+        let spaced = rest.is_empty() || rest.starts_with([' ', '\t']);
+        (level > 0 && level <= deepest && spaced).then_some(level)
+    }
+}
+
+/// A tagged section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// the heading line, 0-based
+    pub heading: usize,
+    /// the heading line itself, follows the section around the document
+    pub title: String,
+    /// how many tagged sections before this one have the same `title`, the
+    /// two together tell sections apart
+    pub occurrence: usize,
+    /// lines of the body, without the blank lines around it
+    pub body: Range<usize>,
+}
+
+/// `lines` replaced by `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub lines: Range<usize>,
+    pub text: Vec<String>,
+}
+
+/// A section body in the clear and armored.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Body {
+    pub title: String,
+    pub occurrence: usize,
+    pub plaintext: String,
+    pub armor: String,
+}
+
+/// Whether the heading `line` is tagged `:crypt:` or marked `<!-- age -->`.
+pub fn is_tagged(line: &str) -> bool {
+    let marked = line.match_indices("<!--").any(|(at, _)| {
+        line[at + 4..]
+            .split_once("-->")
+            .is_some_and(|(inner, _)| inner.trim() == "age")
+    });
+    let tagged = line
+        .split_whitespace()
+        .last()
+        .filter(|tags| tags.len() > 2 && tags.starts_with(':') && tags.ends_with(':'))
+        .is_some_and(|tags| tags.split(':').any(|tag| tag == TAG));
+    marked || tagged
+}
+
+/// The tagged sections of `lines`, in order. Tagged sections within one
+/// are part of its body.
+pub fn sections<S: AsRef<str>>(syntax: Syntax, lines: &[S]) -> Vec<Section> {
+    let blank = |row: usize| lines[row].as_ref().trim().is_empty();
+
+    // (row, level), `#` lines in fenced code aren't headings
+    let mut headings = Vec::new();
+    let mut fence: Option<&str> = None;
+    for (row, line) in lines.iter().enumerate() {
+        let line = line.as_ref();
+        if syntax == Syntax::Markdown {
+            let trimmed = line.trim_start();
+            if let Some(open) = fence {
+                if trimmed.starts_with(open) {
+                    fence = None;
+                }
+                continue;
+            }
+            fence = ["```", "~~~"]
+                .into_iter()
+                .find(|open| trimmed.starts_with(open));
+            if fence.is_some() {
+                continue;
+            }
+        }
+        if let Some(level) = syntax.level(line) {
+            headings.push((row, level));
+        }
+    }
+
+    let mut sections = Vec::new();
+    let mut covered = 0;
+    for (index, &(row, level)) in headings.iter().enumerate() {
+        if row < covered || !is_tagged(lines[row].as_ref()) {
+            continue;
+        }
+        let next = headings[index + 1..]
+            .iter()
+            .find(|(_, other)| *other <= level)
+            .map_or(lines.len(), |(next, _)| *next);
+
+        let (mut start, mut end) = (row + 1, next);
+        while end > start && blank(end - 1) {
+            end -= 1;
+        }
+        while start < end && blank(start) {
+            start += 1;
+        }
+        covered = next;
+        let title = lines[row].as_ref().trim().to_owned();
+        let occurrence = sections
+            .iter()
+            .filter(|section: &&Section| section.title == title)
+            .count();
+        sections.push(Section {
+            heading: row,
+            title,
+            occurrence,
+            body: start..end,
+        });
+    }
+    sections
+}
+
+/// The armor of a body that is nothing but an armored block.
+pub fn armored<S: AsRef<str>>(body: &[S]) -> Option<String> {
+    match armor::blocks(body).as_slice() {
+        [block] if block.start == 0 && block.end + 1 == body.len() => Some(block.text.clone()),
+        _ => None,
+    }
+}
+
+/// Edits that encrypt the tagged sections of `lines` still in the clear.
+/// `reuse` can give the armor of a body that didn't change since it was
+/// last encrypted, so it isn't encrypted anew.
+pub fn encrypt<S: AsRef<str>>(
+    syntax: Syntax,
+    lines: &[S],
+    recipients: &[BoxedRecipient],
+    mut reuse: impl FnMut(&Section, &str) -> Option<String>,
+) -> Result<(Vec<Edit>, Vec<Body>), AgeError> {
+    let mut edits = Vec::new();
+    let mut bodies = Vec::new();
+    for section in sections(syntax, lines) {
+        let body = &lines[section.body.clone()];
+        if body.is_empty() || armored(body).is_some() {
+            continue;
+        }
+        let plaintext = join(body);
+        let armor = match reuse(&section, &plaintext) {
+            Some(armor) => armor,
+            None => {
+                String::from_utf8(crypt::encrypt_bytes_with(plaintext.as_bytes(), recipients)?)?
+            }
+        };
+        edits.push(Edit {
+            lines: section.body,
+            text: armor.lines().map(str::to_owned).collect(),
+        });
+        bodies.push(Body {
+            title: section.title,
+            occurrence: section.occurrence,
+            plaintext,
+            armor,
+        });
+    }
+    Ok((edits, bodies))
+}
+
+/// Edits that decrypt the armored bodies of tagged sections of `lines`.
+pub fn decrypt<S: AsRef<str>>(
+    syntax: Syntax,
+    lines: &[S],
+    identities: &[BoxedIdentity],
+) -> Result<(Vec<Edit>, Vec<Body>), AgeError> {
+    let mut edits = Vec::new();
+    let mut bodies = Vec::new();
+    for section in sections(syntax, lines) {
+        let Some(armor) = armored(&lines[section.body.clone()]) else {
+            continue;
+        };
+        let plaintext = crypt::decrypt_from_string_with(armor.clone(), identities)
+            .map_err(|err| AgeError::from(format!("{}: {err}", section.title)))?;
+        edits.push(Edit {
+            lines: section.body,
+            text: plaintext
+                .strip_suffix('\n')
+                .unwrap_or(&plaintext)
+                .split('\n')
+                .map(str::to_owned)
+                .collect(),
+        });
+        bodies.push(Body {
+            title: section.title,
+            occurrence: section.occurrence,
+            plaintext,
+            armor,
+        });
+    }
+    Ok((edits, bodies))
+}
+
+/// Edits that keep the tagged sections of `lines` still in the clear out,
+/// with the `last` armor of a section in their place when there is one.
+pub fn withhold<S: AsRef<str>>(
+    syntax: Syntax,
+    lines: &[S],
+    mut last: impl FnMut(&Section) -> Option<String>,
+) -> Vec<Edit> {
+    sections(syntax, lines)
+        .into_iter()
+        .filter(|section| {
+            let body = &lines[section.body.clone()];
+            !body.is_empty() && armored(body).is_none()
+        })
+        .map(|section| Edit {
+            text: last(&section)
+                .map(|armor| armor.lines().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            lines: section.body,
+        })
+        .collect()
+}
+
+/// Applies `edits` to `lines`, the buffer does this in `core`.
+#[cfg(test)]
+pub fn apply(lines: &mut Vec<String>, edits: &[Edit]) {
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.lines.start));
+    for edit in edits {
+        lines.splice(edit.lines.clone(), edit.text.iter().cloned());
+    }
+}
+
+/// Edits that take `lines` back from `edits`, once those are applied.
+pub fn invert<S: AsRef<str>>(lines: &[S], edits: &[Edit]) -> Vec<Edit> {
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    edits.sort_by_key(|edit| edit.lines.start);
+
+    // how much the edits before moved the lines
+    let mut shift = 0isize;
+    edits
+        .into_iter()
+        .map(|edit| {
+            let start = edit.lines.start.saturating_add_signed(shift);
+            shift += edit.text.len() as isize - edit.lines.len() as isize;
+            Edit {
+                lines: start..start + edit.text.len(),
+                text: lines[edit.lines.clone()]
+                    .iter()
+                    .map(|line| line.as_ref().to_owned())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn join<S: AsRef<str>>(lines: &[S]) -> String {
+    let mut text = lines
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join("\n");
+    text.push('\n');
+    text
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::crypt::{self, BoxedIdentity, BoxedRecipient};
+    use crate::sections::{
+        apply, armored, decrypt, encrypt, invert, is_tagged, sections, withhold, Edit, Syntax,
+    };
+
+    const KEY: &str = include_str!("../tests/test_key.txt");
+
+    fn keys() -> (Vec<BoxedIdentity>, Vec<BoxedRecipient>) {
+        let identities = crypt::parse_identities(KEY).unwrap();
+        let recipients = crypt::identity_public_keys(KEY)
+            .unwrap()
+            .iter()
+            .map(|key| crypt::parse_recipient(key).unwrap())
+            .collect();
+        (identities, recipients)
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_owned).collect()
+    }
+
+    const NOTES: &str = "\
+# Notes
+
+## Bank :crypt:
+
+iban: DE00 1234
+pin: 0000
+
+### Cards
+
+visa: 4111
+
+## Groceries
+
+```sh
+# not a heading :crypt:
+```
+";
+
+    #[test]
+    fn syntax_from_path_and_filetype() {
+        assert_eq!(
+            Syntax::from_path(Path::new("notes.md")),
+            Some(Syntax::Markdown)
+        );
+        assert_eq!(Syntax::from_path(Path::new("todo.org")), Some(Syntax::Org));
+        assert_eq!(Syntax::from_path(Path::new("notes.txt")), None);
+        assert_eq!(Syntax::from_filetype("markdown"), Some(Syntax::Markdown));
+        assert_eq!(Syntax::from_filetype("text"), None);
+    }
+
+    #[test]
+    fn tags_and_markers() {
+        assert!(is_tagged("## Bank :crypt:"));
+        assert!(is_tagged("** Bank   :finance:crypt:"));
+        assert!(is_tagged("## Bank <!-- age -->"));
+        assert!(is_tagged("## Bank <!--age-->"));
+        assert!(!is_tagged("## Bank"));
+        assert!(!is_tagged("## :crypt: of the matter"));
+        assert!(!is_tagged("## Bank :cryptography:"));
+        assert!(!is_tagged("## Bank <!-- aged -->"));
+    }
+
+    #[test]
+    fn markdown_sections_take_their_subheadings() {
+        let found = sections(Syntax::Markdown, &lines(NOTES));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].heading, 2);
+        assert_eq!(found[0].title, "## Bank :crypt:");
+        // from `iban` to `visa`, the blank lines around stay out
+        assert_eq!(found[0].body, 4..10);
+    }
+
+    #[test]
+    fn org_subtrees() {
+        let text = "\
+* Work
+** Passwords :crypt:
+*** VPN
+hunter2
+** Meetings
+* Home <!-- age -->
+";
+        let found = sections(Syntax::Org, &lines(text));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].body, 2..4);
+        // to the end of the document, an empty body
+        assert_eq!(found[1].heading, 5);
+        assert!(found[1].body.is_empty());
+    }
+
+    #[test]
+    fn roundtrip_keeps_the_rest() {
+        let (identities, recipients) = keys();
+        let original = lines(NOTES);
+
+        let (edits, bodies) =
+            encrypt(Syntax::Markdown, &original, &recipients, |_, _| None).unwrap();
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(
+            bodies[0].plaintext,
+            "iban: DE00 1234\npin: 0000\n\n### Cards\n\nvisa: 4111\n"
+        );
+
+        let mut encrypted = original.clone();
+        apply(&mut encrypted, &edits);
+        assert_eq!(encrypted[..4], original[..4]);
+        assert!(armored(&encrypted[4..encrypted.len() - 6]).is_some());
+        assert_eq!(
+            encrypted[encrypted.len() - 6..],
+            original[original.len() - 6..]
+        );
+
+        // encrypted sections are left alone
+        let (again, _) = encrypt(Syntax::Markdown, &encrypted, &recipients, |_, _| None).unwrap();
+        assert!(again.is_empty());
+
+        let (edits, bodies) = decrypt(Syntax::Markdown, &encrypted, &identities).unwrap();
+        assert_eq!(
+            bodies[0].plaintext,
+            "iban: DE00 1234\npin: 0000\n\n### Cards\n\nvisa: 4111\n"
+        );
+        let mut decrypted = encrypted.clone();
+        apply(&mut decrypted, &edits);
+        assert_eq!(decrypted, original);
+    }
+
+    #[test]
+    fn unchanged_bodies_reuse_their_armor() {
+        let (_, recipients) = keys();
+        let original = lines(NOTES);
+
+        let (edits, bodies) = encrypt(
+            Syntax::Markdown,
+            &original,
+            &recipients,
+            |section, plaintext| {
+                assert_eq!(section.title, "## Bank :crypt:");
+                assert!(plaintext.starts_with("iban"));
+                Some(
+                    "-----BEGIN AGE ENCRYPTED FILE-----\nAAAA\n-----END AGE ENCRYPTED FILE-----\n"
+                        .to_owned(),
+                )
+            },
+        )
+        .unwrap();
+        assert_eq!(edits[0].text.len(), 3);
+        assert_eq!(edits[0].text[1], "AAAA");
+        assert_eq!(bodies[0].armor.lines().count(), 3);
+    }
+
+    #[test]
+    fn withheld_sections_never_stay_in_the_clear() {
+        let original = lines(NOTES);
+
+        let edits = withhold(Syntax::Markdown, &original, |_| None);
+        assert_eq!(edits.len(), 1);
+        assert!(edits[0].text.is_empty());
+
+        let armor = "-----BEGIN AGE ENCRYPTED FILE-----\nAAAA\n-----END AGE ENCRYPTED FILE-----";
+        let edits = withhold(Syntax::Markdown, &original, |_| Some(armor.to_owned()));
+        let mut withheld = original.clone();
+        apply(&mut withheld, &edits);
+        assert!(!withheld.iter().any(|line| line.contains("iban")));
+        assert!(withheld.iter().any(|line| line == "AAAA"));
+    }
+
+    #[test]
+    fn same_titles_stay_apart() {
+        let original = lines(
+            "\
+# Work
+## Notes :crypt:
+work secret
+# Home
+## Notes :crypt:
+home secret
+",
+        );
+        let found = sections(Syntax::Markdown, &original);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].title, found[1].title);
+        assert_eq!((found[0].occurrence, found[1].occurrence), (0, 1));
+
+        // only the home section has armor to fall back on
+        let armor = "-----BEGIN AGE ENCRYPTED FILE-----\nHOME\n-----END AGE ENCRYPTED FILE-----";
+        let edits = withhold(Syntax::Markdown, &original, |section| {
+            (section.occurrence == 1).then(|| armor.to_owned())
+        });
+        let mut withheld = original.clone();
+        apply(&mut withheld, &edits);
+        assert_eq!(
+            withheld,
+            lines(&format!(
+                "# Work\n## Notes :crypt:\n# Home\n## Notes :crypt:\n{armor}\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn inverted_edits_restore_the_lines() {
+        let original = lines("a\nb\nc\nd\ne\n");
+        let edits = [
+            Edit {
+                lines: 1..2,
+                text: lines("x\ny\nz"),
+            },
+            Edit {
+                lines: 3..5,
+                text: Vec::new(),
+            },
+        ];
+
+        let mut edited = original.clone();
+        apply(&mut edited, &edits);
+        assert_eq!(edited, lines("a\nx\ny\nz\nc"));
+
+        apply(&mut edited, &invert(&original, &edits));
+        assert_eq!(edited, original);
+    }
+}
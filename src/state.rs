@@ -0,0 +1,457 @@
+//! What age.nvim knows about buffers it decrypted or encrypted.
+//!
+//! ```lua
+//!
+//! require('age').status()      -- current buffer, {} when not tracked
+//! require('age').statusline()  -- "🔒 age:2 recipients", "" when not tracked
+//!
+//! vim.o.statusline = "%f %{v:lua.require('age').statusline()}"
+//!
+//! ```
+//!
+//! A buffer has *diverged* when its text no longer matches what was last
+//! decrypted from, or encrypted to, its `.age` file. Only a keyed hash of the
+//! plaintext is kept, never the plaintext itself.
+//!
+//! The `.age` file itself is *stamped* with its hash and mtime whenever it is
+//! read or written, so a change by git or a sync tool in the meantime isn't
+//! silently overwritten.
+//!
+//! YAML, JSON and TOML buffers with `ENC[age,...]` values, and Markdown or
+//! Org buffers with encrypted sections, keep the ciphertext of each value or
+//! section next to a keyed hash of its plaintext, so only the ones that
+//! changed are encrypted again on write.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use nvim_oxi::{Array, Dictionary, Object};
+
+use crate::structured::{Choice, Leaf, Secret};
+
+#[derive(Debug, Clone)]
+pub(crate) struct BufferState {
+    /// the `.age` file the buffer belongs to
+    pub(crate) source: PathBuf,
+    /// the plaintext file on disk, if any
+    pub(crate) plaintext: Option<PathBuf>,
+    /// where the identities came from (key files, `passphrase`, ...)
+    pub(crate) identities: Vec<String>,
+    pub(crate) recipients: usize,
+    /// hash of the text as of the last decrypt or encrypt
+    hash: u64,
+    /// `b:changedtick` and the answer of the last divergence check
+    checked: Option<(i64, bool)>,
+    /// `b:changedtick` as of the last recovery journal
+    journaled: Option<i64>,
+    /// the `.age` file as last read or written
+    stamp: Option<Stamp>,
+}
+
+/// Hash and mtime of a ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    hash: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Tracked buffers by handle.
+#[derive(Debug, Default)]
+pub(crate) struct Buffers {
+    hasher: RandomState,
+    buffers: HashMap<i32, BufferState>,
+}
+
+impl Buffers {
+    /// Starts or refreshes tracking of `bufnr` with `contents` as its
+    /// in-sync text.
+    pub(crate) fn track(
+        &mut self,
+        bufnr: i32,
+        source: PathBuf,
+        plaintext: Option<PathBuf>,
+        identities: Vec<String>,
+        recipients: usize,
+        contents: &[u8],
+    ) {
+        let hash = self.hasher.hash_one(contents);
+        self.buffers.insert(
+            bufnr,
+            BufferState {
+                source,
+                plaintext,
+                identities,
+                recipients,
+                hash,
+                checked: None,
+                journaled: None,
+                stamp: None,
+            },
+        );
+    }
+
+    pub(crate) fn get(&self, bufnr: i32) -> Option<&BufferState> {
+        self.buffers.get(&bufnr)
+    }
+
+    pub(crate) fn forget(&mut self, bufnr: i32) {
+        self.buffers.remove(&bufnr);
+    }
+
+    /// Drops buffers for which `alive` is false, eg: wiped ones.
+    pub(crate) fn retain(&mut self, alive: impl Fn(i32) -> bool) {
+        self.buffers.retain(|bufnr, _| alive(*bufnr));
+    }
+
+    /// Whether `contents` differ from the text `bufnr` was tracked with.
+    ///
+    /// `contents` is only hashed again when `tick` (`b:changedtick`) moved
+    /// since the last check, statuslines ask on every redraw.
+    pub(crate) fn diverged(
+        &mut self,
+        bufnr: i32,
+        tick: i64,
+        contents: impl FnOnce() -> Vec<u8>,
+    ) -> bool {
+        let Some(state) = self.buffers.get(&bufnr) else {
+            return false;
+        };
+        if let Some((checked, diverged)) = state.checked {
+            if checked == tick {
+                return diverged;
+            }
+        }
+
+        let diverged = state.hash != self.hasher.hash_one(contents());
+        if let Some(state) = self.buffers.get_mut(&bufnr) {
+            state.checked = Some((tick, diverged));
+        }
+        diverged
+    }
+
+    /// Remembers `ciphertext` as the `.age` file of `bufnr`, as just read
+    /// or written.
+    pub(crate) fn stamp(&mut self, bufnr: i32, ciphertext: &[u8], modified: Option<SystemTime>) {
+        let hash = self.hasher.hash_one(ciphertext);
+        if let Some(state) = self.buffers.get_mut(&bufnr) {
+            state.stamp = Some(Stamp { hash, modified });
+        }
+    }
+
+    /// Whether the `.age` file of `bufnr` changed since it was stamped.
+    ///
+    /// An unchanged mtime is trusted, otherwise `ciphertext` decides, a
+    /// `touch` or a checkout of the same content isn't a change.
+    pub(crate) fn changed_on_disk(
+        &self,
+        bufnr: i32,
+        modified: Option<SystemTime>,
+        ciphertext: impl FnOnce() -> Vec<u8>,
+    ) -> bool {
+        let Some(stamp) = self.buffers.get(&bufnr).and_then(|state| state.stamp) else {
+            return false;
+        };
+        if modified.is_some() && modified == stamp.modified {
+            return false;
+        }
+        stamp.hash != self.hasher.hash_one(ciphertext())
+    }
+
+    /// Whether `bufnr` changed since its last recovery journal.
+    pub(crate) fn journal_due(&self, bufnr: i32, tick: i64) -> bool {
+        self.buffers
+            .get(&bufnr)
+            .is_some_and(|state| state.journaled != Some(tick))
+    }
+
+    /// Remembers `tick` as journaled, once the journal is written.
+    pub(crate) fn journaled(&mut self, bufnr: i32, tick: i64) {
+        if let Some(state) = self.buffers.get_mut(&bufnr) {
+            state.journaled = Some(tick);
+        }
+    }
+}
+
+impl BufferState {
+    pub(crate) fn to_dict(&self, diverged: bool) -> Dictionary {
+        let mut dict = Dictionary::new();
+        dict.insert("source", self.source.display().to_string());
+        if let Some(plaintext) = &self.plaintext {
+            dict.insert("plaintext", plaintext.display().to_string());
+        }
+        dict.insert(
+            "identities",
+            Array::from_iter(self.identities.iter().map(|i| Object::from(i.as_str()))),
+        );
+        dict.insert("recipients", self.recipients as i64);
+        dict.insert("diverged", diverged);
+        dict
+    }
+}
+
+/// `🔒 age:2 recipients`, with ` [+]` once the buffer has diverged.
+pub(crate) fn statusline(state: &BufferState, diverged: bool) -> String {
+    let noun = if state.recipients == 1 {
+        "recipient"
+    } else {
+        "recipients"
+    };
+    let mark = if diverged { " [+]" } else { "" };
+    format!("🔒 age:{} {noun}{mark}", state.recipients)
+}
+
+/// Ciphertext and plaintext hash of the values of a buffer, by document
+/// and path. Sections go by their occurrence and heading instead.
+type Encrypted = HashMap<(usize, String), (u64, String)>;
+
+/// Encrypted values and sections of tracked buffers by handle, with the
+/// recipients they were encrypted for.
+#[derive(Debug, Default)]
+pub(crate) struct Values {
+    hasher: RandomState,
+    buffers: HashMap<i32, (Vec<String>, Encrypted)>,
+}
+
+impl Values {
+    /// Starts or refreshes tracking of `bufnr` with `secrets` as its
+    /// in-sync values, encrypted for `recipients`.
+    pub(crate) fn track(&mut self, bufnr: i32, recipients: &[String], secrets: &[Secret]) {
+        let values = secrets
+            .iter()
+            .map(|secret| {
+                (
+                    (secret.doc, secret.path.clone()),
+                    (
+                        self.hasher.hash_one(&secret.plaintext),
+                        secret.ciphertext.clone(),
+                    ),
+                )
+            })
+            .collect();
+        self.buffers.insert(bufnr, (sorted(recipients), values));
+    }
+
+    pub(crate) fn is_tracked(&self, bufnr: i32) -> bool {
+        self.buffers.contains_key(&bufnr)
+    }
+
+    /// What to do with `leaf` when it was encrypted before: reuse its
+    /// ciphertext when unchanged and still for `recipients`, encrypt it
+    /// again otherwise.
+    pub(crate) fn choice(&self, bufnr: i32, recipients: &[String], leaf: &Leaf) -> Option<Choice> {
+        self.last(bufnr, leaf.doc, &leaf.path)?;
+        Some(
+            self.unchanged(bufnr, recipients, leaf.doc, &leaf.path, &leaf.value)
+                .map_or(Choice::Encrypt, Choice::Reuse),
+        )
+    }
+
+    /// The ciphertext of `path`, if it still holds `plaintext` and was
+    /// encrypted for `recipients`.
+    pub(crate) fn unchanged(
+        &self,
+        bufnr: i32,
+        recipients: &[String],
+        doc: usize,
+        path: &str,
+        plaintext: &str,
+    ) -> Option<String> {
+        let (tracked, values) = self.buffers.get(&bufnr)?;
+        let (hash, ciphertext) = values.get(&(doc, path.to_owned()))?;
+        (*tracked == sorted(recipients) && *hash == self.hasher.hash_one(plaintext))
+            .then(|| ciphertext.clone())
+    }
+
+    /// The ciphertext `path` was last tracked with, whatever it holds now.
+    pub(crate) fn last(&self, bufnr: i32, doc: usize, path: &str) -> Option<String> {
+        let (_, values) = self.buffers.get(&bufnr)?;
+        let (_, ciphertext) = values.get(&(doc, path.to_owned()))?;
+        Some(ciphertext.clone())
+    }
+
+    pub(crate) fn forget(&mut self, bufnr: i32) {
+        self.buffers.remove(&bufnr);
+    }
+}
+
+/// `recipients` in a stable order, the same set compares equal.
+fn sorted(recipients: &[String]) -> Vec<String> {
+    let mut sorted = recipients.to_vec();
+    sorted.sort_unstable();
+    sorted
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use crate::state::{statusline, Buffers, Values};
+    use crate::structured::{self, Choice, Format, Secret};
+
+    fn tracked(contents: &[u8], recipients: usize) -> Buffers {
+        let mut buffers = Buffers::default();
+        buffers.track(
+            3,
+            PathBuf::from("secret.txt.age"),
+            Some(PathBuf::from("secret.txt")),
+            vec!["keys.txt".to_owned()],
+            recipients,
+            contents,
+        );
+        buffers
+    }
+
+    #[test]
+    fn diverges_on_change_and_back() {
+        let mut buffers = tracked(b"one\n", 1);
+
+        assert!(!buffers.diverged(3, 1, || b"one\n".to_vec()));
+        assert!(buffers.diverged(3, 2, || b"two\n".to_vec()));
+        // an undo back to the decrypted text is in sync again
+        assert!(!buffers.diverged(3, 3, || b"one\n".to_vec()));
+    }
+
+    #[test]
+    fn divergence_is_cached_per_tick() {
+        let mut buffers = tracked(b"one\n", 1);
+
+        assert!(buffers.diverged(3, 10, || b"two\n".to_vec()));
+        // same tick, the contents aren't looked at again
+        assert!(buffers.diverged(3, 10, || unreachable!()));
+        assert!(!buffers.diverged(3, 11, || b"one\n".to_vec()));
+    }
+
+    #[test]
+    fn untracked_never_diverges() {
+        let mut buffers = tracked(b"one\n", 1);
+        assert!(!buffers.diverged(4, 1, || b"anything".to_vec()));
+        assert!(buffers.get(4).is_none());
+    }
+
+    #[test]
+    fn retrack_resets_divergence() {
+        let mut buffers = tracked(b"one\n", 1);
+        buffers.track(
+            3,
+            PathBuf::from("secret.txt.age"),
+            None,
+            vec![],
+            2,
+            b"two\n",
+        );
+
+        assert!(!buffers.diverged(3, 1, || b"two\n".to_vec()));
+        assert_eq!(buffers.get(3).unwrap().recipients, 2);
+    }
+
+    #[test]
+    fn forget_and_retain() {
+        let mut buffers = tracked(b"one\n", 1);
+        buffers.track(5, PathBuf::from("other.age"), None, vec![], 1, b"");
+
+        buffers.retain(|bufnr| bufnr != 5);
+        assert!(buffers.get(5).is_none());
+        assert!(buffers.get(3).is_some());
+
+        buffers.forget(3);
+        assert!(buffers.get(3).is_none());
+    }
+
+    #[test]
+    fn journal_once_per_tick() {
+        let mut buffers = tracked(b"one\n", 1);
+
+        assert!(buffers.journal_due(3, 1));
+        // a failed write leaves it due
+        assert!(buffers.journal_due(3, 1));
+        buffers.journaled(3, 1);
+        assert!(!buffers.journal_due(3, 1));
+        assert!(buffers.journal_due(3, 2));
+        assert!(!buffers.journal_due(4, 1));
+    }
+
+    #[test]
+    fn changed_on_disk() {
+        let mut buffers = tracked(b"one\n", 1);
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let later = then + Duration::from_secs(5);
+
+        // never stamped
+        assert!(!buffers.changed_on_disk(3, Some(later), || b"other".to_vec()));
+
+        buffers.stamp(3, b"cipher-1", Some(then));
+        assert!(!buffers.changed_on_disk(3, Some(then), || unreachable!()));
+        // touched, same content
+        assert!(!buffers.changed_on_disk(3, Some(later), || b"cipher-1".to_vec()));
+        assert!(buffers.changed_on_disk(3, Some(later), || b"cipher-2".to_vec()));
+        // no mtime to go by
+        assert!(buffers.changed_on_disk(3, None, || b"cipher-2".to_vec()));
+    }
+
+    #[test]
+    fn statusline_text() {
+        let buffers = tracked(b"", 2);
+        let state = buffers.get(3).unwrap();
+
+        assert_eq!(statusline(state, false), "🔒 age:2 recipients");
+        assert_eq!(statusline(state, true), "🔒 age:2 recipients [+]");
+
+        let buffers = tracked(b"", 1);
+        assert_eq!(
+            statusline(buffers.get(3).unwrap(), false),
+            "🔒 age:1 recipient"
+        );
+    }
+
+    #[test]
+    fn unchanged_values_reuse_their_ciphertext() {
+        let mut values = Values::default();
+        let secret = Secret {
+            doc: 0,
+            path: "db.password".to_owned(),
+            plaintext: "hunter2".to_owned(),
+            ciphertext: "ENC[age,b25l]".to_owned(),
+        };
+        let recipients = ["age1alice".to_owned(), "age1bob".to_owned()];
+        values.track(3, &recipients, &[secret]);
+        assert!(values.is_tracked(3));
+
+        let text = "db:\n  password: hunter2\n  user: admin\n";
+        let leaves = structured::leaves(Format::Yaml, text).unwrap();
+        assert_eq!(
+            values.choice(3, &recipients, &leaves[0]),
+            Some(Choice::Reuse("ENC[age,b25l]".to_owned()))
+        );
+        // never encrypted
+        assert_eq!(values.choice(3, &recipients, &leaves[1]), None);
+        assert_eq!(values.choice(4, &recipients, &leaves[0]), None);
+
+        // same recipients in another order, then one less
+        let reordered = ["age1bob".to_owned(), "age1alice".to_owned()];
+        assert!(values
+            .unchanged(3, &reordered, 0, "db.password", "hunter2")
+            .is_some());
+        assert_eq!(
+            values.choice(3, &recipients[..1], &leaves[0]),
+            Some(Choice::Encrypt)
+        );
+
+        let changed = structured::leaves(Format::Yaml, "db:\n  password: hunter3\n").unwrap();
+        assert_eq!(
+            values.choice(3, &recipients, &changed[0]),
+            Some(Choice::Encrypt)
+        );
+        assert_eq!(
+            values.unchanged(3, &recipients, 0, "db.password", "hunter3"),
+            None
+        );
+        assert_eq!(values.last(3, 0, "db.password").unwrap(), "ENC[age,b25l]");
+
+        values.forget(3);
+        assert!(!values.is_tracked(3));
+    }
+}
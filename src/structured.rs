@@ -0,0 +1,1454 @@
+//! YAML, JSON and TOML documents with only their values encrypted.
+//!
+//! ```yaml
+//!
+//! apiVersion: v1
+//! kind: Secret
+//! stringData:
+//!   # rotated every quarter
+//!   password: ENC[age,YWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+IFgyNTUxOSB...]
+//!
+//! ```
+//!
+//! Each encrypted value is an `ENC[age,...]` string holding the base64 of
+//! a binary age file, so the structure, comments and key order stay
+//! readable and diffable. Only string values are encrypted, numbers,
+//! booleans and nulls keep their type in the clear.
+//!
+//! The documents are not rewritten, the values are found by a scanner that
+//! knows where each string starts and ends and are replaced in place.
+//! YAML flow collections (`{ a: b }`, `[a, b]`) and multi-line plain or
+//! quoted scalars are left alone.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::crypt::{self, BoxedIdentity, BoxedRecipient};
+use crate::error::AgeError;
+
+/// Start of an encrypted value.
+pub const PREFIX: &str = "ENC[age,";
+
+/// The formats values can be encrypted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// From the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match ext.as_str() {
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// A string value of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leaf {
+    /// which `---` separated YAML document, always `0` otherwise
+    pub doc: usize,
+    /// keys and array indices joined by `.`, eg: `stringData.password`
+    pub path: String,
+    /// bytes of the value in the text, quotes included
+    pub span: Range<usize>,
+    /// the string itself, unquoted and unescaped
+    pub value: String,
+    /// column of the key, YAML block scalars are indented relative to it
+    indent: usize,
+}
+
+impl Leaf {
+    /// The last key of the path.
+    pub fn key(&self) -> &str {
+        self.path.rsplit('.').next().unwrap_or(&self.path)
+    }
+}
+
+/// An encrypted value with its plaintext.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    pub doc: usize,
+    pub path: String,
+    pub plaintext: String,
+    /// the whole `ENC[age,...]`
+    pub ciphertext: String,
+}
+
+/// What [`encrypt_document`] does with a string value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Choice {
+    /// leave it in the clear
+    Plain,
+    /// encrypt it anew
+    Encrypt,
+    /// put back this `ENC[age,...]`, the value didn't change
+    Reuse(String),
+}
+
+/// The string values of `text`.
+pub fn leaves(format: Format, text: &str) -> Result<Vec<Leaf>, AgeError> {
+    match format {
+        Format::Yaml => Ok(Yaml::new(text).leaves()),
+        Format::Json => Json::new(text).leaves(),
+        Format::Toml => Toml::new(text).leaves(),
+    }
+}
+
+/// Whether `value` is an `ENC[age,...]` string.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX) && value.ends_with(']')
+}
+
+/// `plaintext` encrypted into an `ENC[age,...]` string.
+pub fn encrypt_value(plaintext: &str, recipients: &[BoxedRecipient]) -> Result<String, AgeError> {
+    let armored = String::from_utf8(crypt::encrypt_bytes_with(plaintext.as_bytes(), recipients)?)?;
+    // the armor is base64 of the binary file, minus the header and footer
+    let base64 = armored
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+    Ok(format!("{PREFIX}{base64}]"))
+}
+
+/// The plaintext of an `ENC[age,...]` string.
+pub fn decrypt_value(value: &str, identities: &[BoxedIdentity]) -> Result<String, AgeError> {
+    let base64 = value
+        .strip_prefix(PREFIX)
+        .and_then(|value| value.strip_suffix(']'))
+        .ok_or_else(|| AgeError::from("not an ENC[age,...] value"))?;
+    let binary = BASE64_STANDARD
+        .decode(base64)
+        .map_err(|err| AgeError::from(format!("invalid ENC[age,...] value: {err}")))?;
+    crypt::decrypt_bytes_with(&binary, identities)
+}
+
+/// `text` with every `ENC[age,...]` value decrypted, and those values.
+pub fn decrypt_document(
+    format: Format,
+    text: &str,
+    identities: &[BoxedIdentity],
+) -> Result<(String, Vec<Secret>), AgeError> {
+    let mut secrets = Vec::new();
+    let mut replacements = Vec::new();
+    for leaf in leaves(format, text)? {
+        if !is_encrypted(&leaf.value) {
+            continue;
+        }
+        let plaintext = decrypt_value(&leaf.value, identities)
+            .map_err(|err| AgeError::from(format!("{}: {err}", leaf.path)))?;
+        let tail = rest_of_line(text, leaf.span.end);
+        replacements.push((leaf.span.clone(), quote(format, &plaintext, &leaf, tail)));
+        secrets.push(Secret {
+            doc: leaf.doc,
+            path: leaf.path,
+            plaintext,
+            ciphertext: leaf.value,
+        });
+    }
+    Ok((replace(text, replacements), secrets))
+}
+
+/// `text` with the values `choose` picks encrypted, and all encrypted
+/// values. Values that already are `ENC[age,...]` are left as they are.
+pub fn encrypt_document(
+    format: Format,
+    text: &str,
+    recipients: &[BoxedRecipient],
+    mut choose: impl FnMut(&Leaf) -> Choice,
+) -> Result<(String, Vec<Secret>), AgeError> {
+    let mut secrets = Vec::new();
+    let mut replacements = Vec::new();
+    for leaf in leaves(format, text)? {
+        if is_encrypted(&leaf.value) {
+            continue;
+        }
+        let ciphertext = match choose(&leaf) {
+            Choice::Plain => continue,
+            Choice::Reuse(ciphertext) => ciphertext,
+            Choice::Encrypt => encrypt_value(&leaf.value, recipients)?,
+        };
+        let quoted = match format {
+            Format::Yaml => ciphertext.clone(),
+            Format::Json | Format::Toml => format!("\"{ciphertext}\""),
+        };
+        replacements.push((leaf.span.clone(), quoted));
+        secrets.push(Secret {
+            doc: leaf.doc,
+            path: leaf.path,
+            plaintext: leaf.value,
+            ciphertext,
+        });
+    }
+    Ok((replace(text, replacements), secrets))
+}
+
+fn replace(text: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    let mut text = text.to_owned();
+    replacements.sort_by_key(|(span, _)| std::cmp::Reverse(span.start));
+    for (span, replacement) in replacements {
+        text.replace_range(span, &replacement);
+    }
+    text
+}
+
+fn rest_of_line(text: &str, from: usize) -> &str {
+    text[from..].lines().next().unwrap_or_default()
+}
+
+/// `value` as a string literal of `format` in place of `leaf`, `tail` is
+/// what follows it on the line.
+fn quote(format: Format, value: &str, leaf: &Leaf, tail: &str) -> String {
+    match format {
+        Format::Json => json_quote(value),
+        Format::Toml => toml_quote(value),
+        Format::Yaml => yaml_quote(value, leaf.indent, tail.trim().is_empty()),
+    }
+}
+
+fn join(path: &str, segment: &str) -> String {
+    if path.is_empty() {
+        segment.to_owned()
+    } else {
+        format!("{path}.{segment}")
+    }
+}
+
+/// The next `len` characters as a hexadecimal number, for `\\u` escapes.
+fn hex(chars: &mut impl Iterator<Item = (usize, char)>, len: usize) -> Option<u32> {
+    let digits = chars.take(len).map(|(_, c)| c).collect::<String>();
+    (digits.len() == len)
+        .then(|| u32::from_str_radix(&digits, 16).ok())
+        .flatten()
+}
+
+// -- JSON
+
+struct Json<'a> {
+    text: &'a str,
+    pos: usize,
+    leaves: Vec<Leaf>,
+}
+
+impl<'a> Json<'a> {
+    fn new(text: &'a str) -> Self {
+        Json {
+            text,
+            pos: 0,
+            leaves: Vec::new(),
+        }
+    }
+
+    fn leaves(mut self) -> Result<Vec<Leaf>, AgeError> {
+        self.value("")?;
+        self.whitespace();
+        if self.pos < self.text.len() {
+            return Err(self.error());
+        }
+        Ok(self.leaves)
+    }
+
+    fn error(&self) -> AgeError {
+        let line = self.text[..self.pos.min(self.text.len())]
+            .lines()
+            .count()
+            .max(1);
+        AgeError::from(format!("invalid JSON on line {line}"))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), AgeError> {
+        self.whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, path: &str) -> Result<(), AgeError> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                self.whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?.1;
+                    self.expect(b':')?;
+                    self.value(&join(path, &key))?;
+                    self.whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(());
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                self.whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                for index in 0.. {
+                    self.value(&join(path, &index.to_string()))?;
+                    self.whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+                Ok(())
+            }
+            Some(b'"') => {
+                let (span, value) = self.string()?;
+                self.leaves.push(Leaf {
+                    doc: 0,
+                    path: path.to_owned(),
+                    span,
+                    value,
+                    indent: 0,
+                });
+                Ok(())
+            }
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .peek()
+                    .is_some_and(|b| !b.is_ascii_whitespace() && !b",]}".contains(&b))
+                {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(self.error());
+                }
+                Ok(())
+            }
+            None => Err(self.error()),
+        }
+    }
+
+    /// A string at `pos`, its span with the quotes and its value.
+    fn string(&mut self) -> Result<(Range<usize>, String), AgeError> {
+        let start = self.pos;
+        if self.peek() != Some(b'"') {
+            return Err(self.error());
+        }
+        let mut value = String::new();
+        let mut chars = self.text[start + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos = start + 1 + i + 1;
+                    return Ok((start..self.pos, value));
+                }
+                '\\' => {
+                    let (_, escaped) = chars.next().ok_or_else(|| self.error())?;
+                    match escaped {
+                        'n' => value.push('\n'),
+                        't' => value.push('\t'),
+                        'r' => value.push('\r'),
+                        'b' => value.push('\u{8}'),
+                        'f' => value.push('\u{c}'),
+                        'u' => {
+                            let first = hex(&mut chars, 4).ok_or_else(|| self.error())?;
+                            let units = if (0xd800..0xdc00).contains(&first) {
+                                // a surrogate pair, `\ud83d\ude00`
+                                chars.nth(1);
+                                vec![first, hex(&mut chars, 4).ok_or_else(|| self.error())?]
+                            } else {
+                                vec![first]
+                            };
+                            value.extend(
+                                char::decode_utf16(units.into_iter().map(|unit| unit as u16))
+                                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+                            );
+                        }
+                        other => value.push(other),
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error())
+    }
+}
+
+fn json_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// -- TOML
+
+struct Toml<'a> {
+    text: &'a str,
+    pos: usize,
+    /// the current `[table]`
+    table: String,
+    /// `[[array]]` tables seen so far, by name
+    arrays: HashMap<String, usize>,
+    leaves: Vec<Leaf>,
+}
+
+impl<'a> Toml<'a> {
+    fn new(text: &'a str) -> Self {
+        Toml {
+            text,
+            pos: 0,
+            table: String::new(),
+            arrays: HashMap::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    fn leaves(mut self) -> Result<Vec<Leaf>, AgeError> {
+        loop {
+            self.blank(true);
+            match self.peek() {
+                None => return Ok(self.leaves),
+                Some(b'[') if self.text[self.pos..].starts_with("[[") => {
+                    self.pos += 2;
+                    let name = self.key(b']')?;
+                    self.expect("]]")?;
+                    let name = self.in_array(&name);
+                    let count = self.arrays.entry(name.clone()).or_insert(0);
+                    self.table = join(&name, &count.to_string());
+                    *count += 1;
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    let name = self.key(b']')?;
+                    self.expect("]")?;
+                    self.table = self.in_array(&name);
+                }
+                Some(_) => {
+                    let key = self.key(b'=')?;
+                    self.expect("=")?;
+                    let path = join(&self.table, &key);
+                    self.value(&path)?;
+                }
+            }
+        }
+    }
+
+    /// `name` with the current element of the `[[array]]` it is in,
+    /// `[fruits.physical]` after two `[[fruits]]` is `fruits.1.physical`.
+    fn in_array(&self, name: &str) -> String {
+        let parent = self
+            .arrays
+            .iter()
+            .filter(|(array, _)| name.starts_with(&format!("{array}.")))
+            .max_by_key(|(array, _)| array.len());
+        match parent {
+            Some((array, count)) => {
+                format!("{array}.{}{}", count - 1, &name[array.len()..])
+            }
+            None => name.to_owned(),
+        }
+    }
+
+    fn error(&self) -> AgeError {
+        let line = self.text[..self.pos.min(self.text.len())]
+            .lines()
+            .count()
+            .max(1);
+        AgeError::from(format!("invalid TOML on line {line}"))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    /// Skips spaces and comments, and newlines too when `newlines`.
+    fn blank(&mut self, newlines: bool) {
+        while let Some(b) = self.peek() {
+            match b {
+                b' ' | b'\t' => self.pos += 1,
+                b'\r' | b'\n' if newlines => self.pos += 1,
+                b'#' => {
+                    while self.peek().is_some_and(|b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), AgeError> {
+        self.blank(false);
+        if !self.text[self.pos..].starts_with(token) {
+            return Err(self.error());
+        }
+        self.pos += token.len();
+        Ok(())
+    }
+
+    /// A dotted key up to `end`, quoted parts unquoted.
+    fn key(&mut self, end: u8) -> Result<String, AgeError> {
+        let mut parts = Vec::new();
+        loop {
+            self.blank(false);
+            match self.peek() {
+                Some(b'"' | b'\'') => parts.push(self.string()?.1),
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+                    {
+                        self.pos += 1;
+                    }
+                    if self.pos == start {
+                        return Err(self.error());
+                    }
+                    parts.push(self.text[start..self.pos].to_owned());
+                }
+            }
+            self.blank(false);
+            match self.peek() {
+                Some(b'.') => self.pos += 1,
+                Some(b) if b == end => return Ok(parts.join(".")),
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn value(&mut self, path: &str) -> Result<(), AgeError> {
+        self.blank(false);
+        match self.peek() {
+            Some(b'"' | b'\'') => {
+                let (span, value) = self.string()?;
+                self.leaves.push(Leaf {
+                    doc: 0,
+                    path: path.to_owned(),
+                    span,
+                    value,
+                    indent: 0,
+                });
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                loop {
+                    self.blank(true);
+                    if self.peek() == Some(b'}') {
+                        self.pos += 1;
+                        break;
+                    }
+                    let key = self.key(b'=')?;
+                    self.expect("=")?;
+                    self.value(&join(path, &key))?;
+                    self.blank(true);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {}
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                for index in 0.. {
+                    self.blank(true);
+                    if self.peek() == Some(b']') {
+                        self.pos += 1;
+                        break;
+                    }
+                    self.value(&join(path, &index.to_string()))?;
+                    self.blank(true);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {}
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            Some(_) => {
+                // numbers, booleans and dates
+                let start = self.pos;
+                while self.peek().is_some_and(|b| !b",]}#\r\n".contains(&b)) {
+                    self.pos += 1;
+                }
+                if self.text[start..self.pos].trim().is_empty() {
+                    return Err(self.error());
+                }
+            }
+            None => return Err(self.error()),
+        }
+        Ok(())
+    }
+
+    /// Any of the four kinds of strings at `pos`.
+    fn string(&mut self) -> Result<(Range<usize>, String), AgeError> {
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let (delimiter, literal) = if rest.starts_with("\"\"\"") {
+            ("\"\"\"", false)
+        } else if rest.starts_with("'''") {
+            ("'''", true)
+        } else if rest.starts_with('"') {
+            ("\"", false)
+        } else {
+            ("'", true)
+        };
+        let multiline = delimiter.len() == 3;
+
+        let body_start = start + delimiter.len();
+        let mut body = &self.text[body_start..];
+        // a newline right after the opening delimiter is trimmed
+        if multiline {
+            body = body
+                .strip_prefix("\r\n")
+                .or_else(|| body.strip_prefix('\n'))
+                .unwrap_or(body);
+        }
+        let offset = self.text.len() - body.len();
+
+        let mut value = String::new();
+        let mut chars = body.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if body[i..].starts_with(delimiter) {
+                let mut end = offset + i + delimiter.len();
+                // `""""` ends with a quote in the string
+                if multiline {
+                    let quote = &delimiter[..1];
+                    let extra = self.text[end..]
+                        .bytes()
+                        .take(2)
+                        .take_while(|b| quote.as_bytes()[0] == *b)
+                        .count();
+                    value.push_str(&quote.repeat(extra));
+                    end += extra;
+                }
+                self.pos = end;
+                return Ok((start..end, value));
+            }
+            if c == '\n' && !multiline {
+                break;
+            }
+            if literal || c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let (_, escaped) = chars.next().ok_or_else(|| self.error())?;
+            match escaped {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'b' => value.push('\u{8}'),
+                'f' => value.push('\u{c}'),
+                'e' => value.push('\u{1b}'),
+                'u' | 'U' => {
+                    let len = if escaped == 'u' { 4 } else { 8 };
+                    let c = hex(&mut chars, len)
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| self.error())?;
+                    value.push(c);
+                }
+                // a line ending backslash trims the whitespace after it
+                ' ' | '\t' | '\r' | '\n' if multiline => {
+                    while chars.peek().is_some_and(|(_, c)| c.is_whitespace()) {
+                        chars.next();
+                    }
+                }
+                other => value.push(other),
+            }
+        }
+        self.pos = start;
+        Err(self.error())
+    }
+}
+
+fn toml_quote(value: &str) -> String {
+    let multiline = value.contains('\n');
+    let mut quoted = String::from(if multiline { "\"\"\"\n" } else { "\"" });
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' if multiline => quoted.push('\n'),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push('\t'),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push_str(if multiline { "\"\"\"" } else { "\"" });
+    quoted
+}
+
+// -- YAML
+
+/// A key or sequence item the current line is nested in.
+struct Frame {
+    indent: usize,
+    segment: String,
+    item: bool,
+    /// sequence items seen under it
+    items: usize,
+}
+
+struct Yaml<'a> {
+    /// start offset and content of each line, without the newline
+    lines: Vec<(usize, &'a str)>,
+    next: usize,
+    doc: usize,
+    /// whether the current document has any content yet
+    started: bool,
+    stack: Vec<Frame>,
+    /// items of a top-level sequence
+    root_items: usize,
+    leaves: Vec<Leaf>,
+}
+
+impl<'a> Yaml<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            lines.push((offset, content.strip_suffix('\r').unwrap_or(content)));
+            offset += line.len();
+        }
+        Yaml {
+            lines,
+            next: 0,
+            doc: 0,
+            started: false,
+            stack: Vec::new(),
+            root_items: 0,
+            leaves: Vec::new(),
+        }
+    }
+
+    fn leaves(mut self) -> Vec<Leaf> {
+        while let Some(&(offset, line)) = self.lines.get(self.next) {
+            self.next += 1;
+            let content = line.trim_start_matches(' ');
+            let col = line.len() - content.len();
+            if content.is_empty() || content.starts_with('#') || content.starts_with('%') {
+                continue;
+            }
+            let marker = |m: &str| {
+                col == 0
+                    && content.starts_with(m)
+                    && content[3..].chars().next().is_none_or(char::is_whitespace)
+            };
+            if marker("---") || marker("...") {
+                if self.started {
+                    self.doc += 1;
+                    self.started = false;
+                }
+                self.stack.clear();
+                self.root_items = 0;
+                continue;
+            }
+            self.started = true;
+            self.entry(offset + col, col, content);
+        }
+        self.leaves
+    }
+
+    fn path(&self) -> String {
+        self.stack
+            .iter()
+            .map(|frame| frame.segment.as_str())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// A `- item` or `key: value` starting at `start`, column `col`.
+    fn entry(&mut self, start: usize, col: usize, content: &str) {
+        if content == "-" || content.starts_with("- ") {
+            while self
+                .stack
+                .last()
+                .is_some_and(|top| top.indent > col || (top.indent == col && top.item))
+            {
+                self.stack.pop();
+            }
+            let index = match self.stack.last_mut() {
+                Some(parent) => {
+                    parent.items += 1;
+                    parent.items - 1
+                }
+                None => {
+                    self.root_items += 1;
+                    self.root_items - 1
+                }
+            };
+            self.stack.push(Frame {
+                indent: col,
+                segment: index.to_string(),
+                item: true,
+                items: 0,
+            });
+
+            let rest = content[1..].trim_start_matches(' ');
+            let skipped = content.len() - rest.len();
+            if rest.starts_with("- ") || split_key(rest).is_some() {
+                self.entry(start + skipped, col + skipped, rest);
+            } else {
+                self.scalar(start + skipped, col, rest);
+            }
+            return;
+        }
+
+        let Some((key, value)) = split_key(content) else {
+            return;
+        };
+        while self.stack.last().is_some_and(|top| top.indent >= col) {
+            self.stack.pop();
+        }
+        self.stack.push(Frame {
+            indent: col,
+            segment: key,
+            item: false,
+            items: 0,
+        });
+        let value = value.trim_start_matches(' ');
+        self.scalar(start + content.len() - value.len(), col, value);
+    }
+
+    /// The value after `key:` or `-`, `indent` is the column of either.
+    fn scalar(&mut self, start: usize, indent: usize, value: &str) {
+        let leaf = |span: Range<usize>, value: String| Leaf {
+            doc: 0,
+            path: String::new(),
+            span,
+            value,
+            indent,
+        };
+        let found = match value.as_bytes().first() {
+            None | Some(b'#' | b'[' | b'{' | b'*' | b'!' | b'&') => None,
+            Some(b'|' | b'>') => self
+                .block(start, indent, value)
+                .map(|(span, v)| leaf(span, v)),
+            Some(b'"' | b'\'') => yaml_quoted(value)
+                .filter(|(len, _)| {
+                    let tail = value[*len..].trim_start();
+                    tail.is_empty() || tail.starts_with('#')
+                })
+                .map(|(len, v)| leaf(start..start + len, v)),
+            Some(_) => {
+                let end = value.find(" #").unwrap_or(value.len());
+                let plain = value[..end].trim_end();
+                (!is_yaml_non_string(plain) && !plain.ends_with(':'))
+                    .then(|| leaf(start..start + plain.len(), plain.to_owned()))
+            }
+        };
+        if let Some(mut found) = found {
+            found.doc = self.doc;
+            found.path = self.path();
+            self.leaves.push(found);
+        }
+    }
+
+    /// A `|` or `>` block scalar, its span ends with its last non-empty
+    /// line.
+    fn block(
+        &mut self,
+        start: usize,
+        indent: usize,
+        header: &str,
+    ) -> Option<(Range<usize>, String)> {
+        let rest = &header[1..];
+        let indicators = &rest[..rest.find(char::is_whitespace).unwrap_or(rest.len())];
+        if !indicators
+            .chars()
+            .all(|c| matches!(c, '-' | '+' | '1'..='9'))
+        {
+            return None;
+        }
+        if rest[indicators.len()..]
+            .trim_start()
+            .chars()
+            .next()
+            .is_some_and(|c| c != '#')
+        {
+            return None;
+        }
+        let folded = header.starts_with('>');
+        let chomp = indicators.chars().find(|c| *c == '-' || *c == '+');
+        let explicit = indicators
+            .chars()
+            .find_map(|c| c.to_digit(10))
+            .map(|digit| indent + digit as usize);
+
+        let mut block_indent = explicit;
+        let mut lines = Vec::new();
+        let mut end = start + 1 + indicators.len();
+        let mut last = self.next;
+        for (i, &(offset, line)) in self.lines.iter().enumerate().skip(self.next) {
+            let content = line.trim_start_matches(' ');
+            let col = line.len() - content.len();
+            if content.is_empty() {
+                lines.push("");
+                continue;
+            }
+            let required = *block_indent.get_or_insert(col);
+            if col < required || col <= indent {
+                break;
+            }
+            lines.push(&line[required..]);
+            end = offset + line.len();
+            last = i + 1;
+        }
+        let trailing = lines
+            .iter()
+            .rev()
+            .take_while(|line| line.is_empty())
+            .count();
+        lines.truncate(lines.len() - trailing);
+        self.next = last;
+
+        let mut value = if folded {
+            let mut folded = String::new();
+            let mut joined = false;
+            for line in &lines {
+                if line.is_empty() {
+                    folded.push('\n');
+                    joined = false;
+                } else {
+                    if joined {
+                        folded.push(' ');
+                    }
+                    folded.push_str(line);
+                    joined = true;
+                }
+            }
+            folded
+        } else {
+            lines.join("\n")
+        };
+        match chomp {
+            Some('-') => {}
+            _ if lines.is_empty() => {}
+            Some('+') => value.push_str(&"\n".repeat(trailing + 1)),
+            _ => value.push('\n'),
+        }
+        Some((start..end, value))
+    }
+}
+
+/// `key: value` into the unquoted key and the value, `None` for anything
+/// else.
+fn split_key(content: &str) -> Option<(String, &str)> {
+    let (key, rest) = match content.as_bytes().first()? {
+        b'"' | b'\'' => {
+            let (len, key) = yaml_quoted(content)?;
+            (key, content[len..].trim_start_matches(' '))
+        }
+        b'#' | b'[' | b'{' | b'?' | b'-' | b'|' | b'>' | b'&' | b'*' | b'!' => return None,
+        _ => {
+            let comment = content.find(" #").unwrap_or(content.len());
+            let colon = content[..comment]
+                .match_indices(':')
+                .map(|(i, _)| i)
+                .find(|i| {
+                    content[i + 1..]
+                        .chars()
+                        .next()
+                        .is_none_or(|c| c == ' ' || c == '\t')
+                })?;
+            (content[..colon].trim_end().to_owned(), &content[colon..])
+        }
+    };
+    let value = rest.strip_prefix(':')?;
+    if !value.is_empty() && !value.starts_with([' ', '\t']) {
+        return None;
+    }
+    Some((key, value))
+}
+
+/// The length and value of the quoted scalar `value` starts with.
+fn yaml_quoted(value: &str) -> Option<(usize, String)> {
+    let quote = value.chars().next()?;
+    let mut unquoted = String::new();
+    let mut chars = value.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            ('\'', '\'') => {
+                if value[i + 1..].starts_with('\'') {
+                    chars.next();
+                    unquoted.push('\'');
+                } else {
+                    return Some((i + 1, unquoted));
+                }
+            }
+            ('"', '"') => return Some((i + 1, unquoted)),
+            ('"', '\\') => {
+                let (_, escaped) = chars.next()?;
+                match escaped {
+                    'n' => unquoted.push('\n'),
+                    't' => unquoted.push('\t'),
+                    'r' => unquoted.push('\r'),
+                    '0' => unquoted.push('\0'),
+                    'e' => unquoted.push('\u{1b}'),
+                    'x' | 'u' | 'U' => {
+                        let len = match escaped {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        unquoted.push(hex(&mut chars, len).and_then(char::from_u32)?);
+                    }
+                    other => unquoted.push(other),
+                }
+            }
+            _ => unquoted.push(c),
+        }
+    }
+    None
+}
+
+/// Plain scalars that resolve to something else than a string, in YAML
+/// 1.1 as Kubernetes reads it.
+fn is_yaml_non_string(plain: &str) -> bool {
+    let lower = plain.to_ascii_lowercase();
+    let keyword = [
+        "", "~", "null", "true", "false", "yes", "no", "on", "off", "y", "n", ".inf", "-.inf",
+        "+.inf", ".nan",
+    ]
+    .contains(&lower.as_str());
+    let digits = plain.replace('_', "");
+    let number = digits.parse::<f64>().is_ok()
+        || ["0x", "0o", "0b"].iter().any(|prefix| {
+            lower.starts_with(prefix)
+                && i64::from_str_radix(
+                    &digits[2..],
+                    match *prefix {
+                        "0x" => 16,
+                        "0o" => 8,
+                        _ => 2,
+                    },
+                )
+                .is_ok()
+        });
+    keyword || number
+}
+
+fn yaml_quote(value: &str, indent: usize, alone: bool) -> String {
+    let body = value.trim_end_matches('\n');
+    let block = alone
+        && body.contains('\n')
+        && !body.starts_with([' ', '\t'])
+        && !value.contains('\r')
+        && !body
+            .chars()
+            .any(|c| c.is_control() && c != '\n' && c != '\t');
+    if block {
+        let trailing = value.len() - body.len();
+        let header = match trailing {
+            0 => "|-",
+            1 => "|",
+            _ => "|+",
+        };
+        let pad = " ".repeat(indent + 2);
+        let mut quoted = String::from(header);
+        for line in body.split('\n') {
+            quoted.push('\n');
+            if !line.is_empty() {
+                quoted.push_str(&pad);
+                quoted.push_str(line);
+            }
+        }
+        quoted.push_str(&"\n".repeat(trailing.saturating_sub(1)));
+        return quoted;
+    }
+
+    let plain = !value.is_empty()
+        && value.trim() == value
+        && !value.starts_with(|c| "-?:,[]{}#&*!|>'\"%@`".contains(c))
+        && !value.contains(": ")
+        && !value.contains(" #")
+        && !value.ends_with(':')
+        && !value.chars().any(char::is_control)
+        && !is_yaml_non_string(value);
+    if plain {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::crypt::{self, BoxedIdentity, BoxedRecipient};
+    use crate::structured::{
+        decrypt_document, decrypt_value, encrypt_document, encrypt_value, is_encrypted, leaves,
+        Choice, Format, Leaf,
+    };
+
+    const KEY: &str = include_str!("../tests/test_key.txt");
+
+    fn keys() -> (Vec<BoxedIdentity>, Vec<BoxedRecipient>) {
+        let identities = crypt::parse_identities(KEY).unwrap();
+        let recipients = crypt::identity_public_keys(KEY)
+            .unwrap()
+            .iter()
+            .map(|key| crypt::parse_recipient(key).unwrap())
+            .collect();
+        (identities, recipients)
+    }
+
+    fn paths(leaves: &[Leaf]) -> Vec<(String, String)> {
+        leaves
+            .iter()
+            .map(|leaf| (leaf.path.clone(), leaf.value.clone()))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(path, value)| ((*path).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    /// Encrypts every string, then decrypts it back.
+    fn roundtrip(format: Format, text: &str) -> String {
+        let (identities, recipients) = keys();
+        let (encrypted, secrets) =
+            encrypt_document(format, text, &recipients, |_| Choice::Encrypt).unwrap();
+        assert!(!secrets.is_empty());
+        assert!(secrets
+            .iter()
+            .all(|secret| is_encrypted(&secret.ciphertext)));
+        for secret in &secrets {
+            assert!(!encrypted.contains(&format!(": {}\n", secret.plaintext)));
+        }
+        let (decrypted, again) = decrypt_document(format, &encrypted, &identities).unwrap();
+        assert_eq!(secrets.len(), again.len());
+        decrypted
+    }
+
+    #[test]
+    fn formats_from_extensions() {
+        assert_eq!(
+            Format::from_path(Path::new("a/secret.yaml")),
+            Some(Format::Yaml)
+        );
+        assert_eq!(
+            Format::from_path(Path::new("values.YML")),
+            Some(Format::Yaml)
+        );
+        assert_eq!(Format::from_path(Path::new("app.json")), Some(Format::Json));
+        assert_eq!(
+            Format::from_path(Path::new("Cargo.toml")),
+            Some(Format::Toml)
+        );
+        assert_eq!(Format::from_path(Path::new("secret.age")), None);
+    }
+
+    #[test]
+    fn values_roundtrip() {
+        let (identities, recipients) = keys();
+        let value = encrypt_value("hunter2", &recipients).unwrap();
+        assert!(is_encrypted(&value));
+        assert!(!value.contains('\n'));
+        assert_eq!(decrypt_value(&value, &identities).unwrap(), "hunter2");
+        assert!(decrypt_value("ENC[age,not base64]", &identities).is_err());
+        assert!(decrypt_value("hunter2", &identities).is_err());
+    }
+
+    #[test]
+    fn yaml_leaves() {
+        let text = "\
+# a comment
+apiVersion: v1
+kind: Secret
+metadata:
+  name: db # trailing
+  labels: {app: db}
+stringData:
+  password: \"hun\\\"ter2\"
+  user: 'o''neil'
+  port: 5432
+  enabled: true
+  empty:
+  cert: |
+    line 1
+
+    line 2
+
+  folded: >-
+    a
+    b
+items:
+- plain
+- name: first
+  value: x
+-   - nested
+---
+second: doc
+";
+        let found = leaves(Format::Yaml, text).unwrap();
+        assert_eq!(
+            paths(&found),
+            pairs(&[
+                ("apiVersion", "v1"),
+                ("kind", "Secret"),
+                ("metadata.name", "db"),
+                ("stringData.password", "hun\"ter2"),
+                ("stringData.user", "o'neil"),
+                ("stringData.cert", "line 1\n\nline 2\n"),
+                ("stringData.folded", "a b"),
+                ("items.0", "plain"),
+                ("items.1.name", "first"),
+                ("items.1.value", "x"),
+                ("items.2.0", "nested"),
+                ("second", "doc"),
+            ])
+        );
+        assert_eq!(found.last().unwrap().doc, 1);
+        assert_eq!(found[0].doc, 0);
+        assert_eq!(found[3].key(), "password");
+        assert_eq!(&text[found[3].span.clone()], "\"hun\\\"ter2\"");
+        assert!(text[found[5].span.clone()].ends_with("line 2"));
+    }
+
+    #[test]
+    fn yaml_block_headers_are_not_sliced_mid_character() {
+        let text = "\
+a: ENC[age,x]
+key: | é
+  body
+note: |#café
+  body
+cert: |- # café ✓
+  line
+";
+        let found = leaves(Format::Yaml, text).unwrap();
+        assert_eq!(
+            paths(&found),
+            pairs(&[("a", "ENC[age,x]"), ("cert", "line")])
+        );
+    }
+
+    #[test]
+    fn yaml_keeps_comments_and_order() {
+        let text = "\
+# database
+stringData:
+  password: hunter2 # rotated monthly
+  url: postgres://db:5432/app
+  cert: |
+    -----BEGIN CERTIFICATE-----
+    MIIB
+  port: 5432
+";
+        assert_eq!(roundtrip(Format::Yaml, text), text);
+
+        let (_, recipients) = keys();
+        let (encrypted, _) =
+            encrypt_document(Format::Yaml, text, &recipients, |_| Choice::Encrypt).unwrap();
+        assert!(encrypted.starts_with("# database\nstringData:\n  password: ENC[age,"));
+        assert!(encrypted.contains("] # rotated monthly\n"));
+        assert!(encrypted.ends_with("\n  port: 5432\n"));
+    }
+
+    #[test]
+    fn yaml_quotes_what_needs_it() {
+        let (identities, recipients) = keys();
+        let values = ["yes", "it's: here", "", "two\nlines\n", "3"];
+        let encrypted = ["a", "b", "c", "d", "e"]
+            .iter()
+            .zip(values)
+            .map(|(key, value)| format!("{key}: {}\n", encrypt_value(value, &recipients).unwrap()))
+            .collect::<String>();
+
+        let (decrypted, _) = decrypt_document(Format::Yaml, &encrypted, &identities).unwrap();
+        assert_eq!(
+            decrypted,
+            "a: \"yes\"\nb: \"it's: here\"\nc: \"\"\nd: |\n  two\n  lines\ne: \"3\"\n"
+        );
+        let found = leaves(Format::Yaml, &decrypted).unwrap();
+        assert_eq!(
+            found
+                .iter()
+                .map(|leaf| leaf.value.as_str())
+                .collect::<Vec<_>>(),
+            values
+        );
+    }
+
+    #[test]
+    fn json_leaves_and_roundtrip() {
+        let text = r#"{
+  "name": "app",
+  "port": 8080,
+  "db": { "password": "hun\"teré", "hosts": ["a", "b"] },
+  "debug": false
+}
+"#;
+        let found = leaves(Format::Json, text).unwrap();
+        assert_eq!(
+            paths(&found),
+            pairs(&[
+                ("name", "app"),
+                ("db.password", "hun\"teré"),
+                ("db.hosts.0", "a"),
+                ("db.hosts.1", "b"),
+            ])
+        );
+        assert_eq!(roundtrip(Format::Json, text), text);
+
+        assert!(leaves(Format::Json, "{\"a\": }").is_err());
+        assert!(leaves(Format::Json, "{\"a\": \"b\"").is_err());
+    }
+
+    #[test]
+    fn toml_leaves_and_roundtrip() {
+        let text = r#"# app config
+title = "app"
+port = 8080
+
+[database]
+password = 'hunter2' # literal
+"dotted.key" = "x"
+hosts = ["a", "b"]
+inline = { user = "me", id = 1 }
+cert = """
+line 1
+line 2"""
+
+[[servers]]
+name = "alpha"
+
+[[servers]]
+name = "beta"
+
+[servers.tls]
+key = "k"
+"#;
+        let found = leaves(Format::Toml, text).unwrap();
+        assert_eq!(
+            paths(&found),
+            pairs(&[
+                ("title", "app"),
+                ("database.password", "hunter2"),
+                ("database.dotted.key", "x"),
+                ("database.hosts.0", "a"),
+                ("database.hosts.1", "b"),
+                ("database.inline.user", "me"),
+                ("database.cert", "line 1\nline 2"),
+                ("servers.0.name", "alpha"),
+                ("servers.1.name", "beta"),
+                ("servers.1.tls.key", "k"),
+            ])
+        );
+
+        let decrypted = roundtrip(Format::Toml, text);
+        assert_eq!(
+            paths(&leaves(Format::Toml, &decrypted).unwrap()),
+            paths(&found)
+        );
+        assert!(decrypted.contains("# app config\ntitle = \"app\"\nport = 8080\n"));
+        assert!(decrypted.contains("password = \"hunter2\" # literal\n"));
+
+        assert!(leaves(Format::Toml, "a = \"unterminated\n").is_err());
+        assert!(leaves(Format::Toml, "= 1\n").is_err());
+    }
+
+    #[test]
+    fn unchanged_values_keep_their_ciphertext() {
+        let (identities, recipients) = keys();
+        let text = "user: me\npassword: hunter2\n";
+        let (encrypted, secrets) = encrypt_document(Format::Yaml, text, &recipients, |leaf| {
+            if leaf.key() == "password" {
+                Choice::Encrypt
+            } else {
+                Choice::Plain
+            }
+        })
+        .unwrap();
+        assert!(encrypted.starts_with("user: me\npassword: ENC[age,"));
+        assert_eq!(secrets.len(), 1);
+
+        let (decrypted, secrets) = decrypt_document(Format::Yaml, &encrypted, &identities).unwrap();
+        assert_eq!(decrypted, text);
+
+        let reuse = |leaf: &Leaf| match secrets.iter().find(|s| s.path == leaf.path) {
+            Some(secret) if secret.plaintext == leaf.value => {
+                Choice::Reuse(secret.ciphertext.clone())
+            }
+            Some(_) => Choice::Encrypt,
+            None => Choice::Plain,
+        };
+        let (again, _) = encrypt_document(Format::Yaml, &decrypted, &recipients, reuse).unwrap();
+        assert_eq!(again, encrypted);
+
+        let edited = decrypted.replace("hunter2", "hunter3");
+        let (changed, _) = encrypt_document(Format::Yaml, &edited, &recipients, reuse).unwrap();
+        assert_ne!(changed, encrypted);
+        assert_eq!(
+            decrypt_document(Format::Yaml, &changed, &identities)
+                .unwrap()
+                .0,
+            edited
+        );
+    }
+}
@@ -8,14 +8,14 @@ use std::path::{Path, PathBuf};
 /// 2. It have `.age` extension
 ///
 #[derive(Debug)]
-pub(crate) struct ExistingAgeFile<'a>(Cow<'a, Path>);
+pub struct ExistingAgeFile<'a>(Cow<'a, Path>);
 
 impl<'a> ExistingAgeFile<'a> {
-    pub(crate) fn path(&self) -> &Path {
+    pub fn path(&self) -> &Path {
         &self.0
     }
 
-    pub(crate) fn strip_age(&self) -> PathBuf {
+    pub fn strip_age(&self) -> PathBuf {
         self.0.with_extension("")
     }
 }
@@ -47,14 +47,14 @@ impl TryFrom<PathBuf> for ExistingAgeFile<'_> {
 }
 
 #[derive(Debug)]
-pub(crate) struct ExistingNonAgeFile<'a>(Cow<'a, Path>);
+pub struct ExistingNonAgeFile<'a>(Cow<'a, Path>);
 
 impl<'a> ExistingNonAgeFile<'a> {
-    pub(crate) fn path(&self) -> &Path {
+    pub fn path(&self) -> &Path {
         &self.0
     }
 
-    pub(crate) fn append_age(&self) -> PathBuf {
+    pub fn append_age(&self) -> PathBuf {
         self.0.with_added_extension("age")
     }
 }
@@ -93,6 +93,26 @@ impl Display for ExistingNonAgeFile<'_> {
     }
 }
 
+/// `~/notes` => `$HOME/notes`, other paths are left alone.
+pub fn expand_tilde<P: AsRef<Path>>(path: P) -> PathBuf {
+    let p = path.as_ref();
+    if !p.starts_with("~") {
+        return p.to_path_buf();
+    }
+
+    if let Ok(home_dir) = std::env::var("HOME") {
+        if p == Path::new("~") {
+            return home_dir.into();
+        }
+
+        if let Ok(suffix) = p.strip_prefix("~") {
+            return Path::new(&home_dir).join(suffix);
+        }
+    }
+
+    p.to_path_buf()
+}
+
 #[allow(clippy::unwrap_used)]
 #[cfg(test)]
 mod test {
@@ -0,0 +1,99 @@
+//! Encrypted persistent undo for decrypted buffers.
+//!
+//! Decrypted buffers have `'undofile'` off, a plain undo file holds the
+//! text. Instead, when a buffer is encrypted its undo tree is written with
+//! `:wundo`, encrypted to the same recipients and kept in
+//! `stdpath('state')/age/undo/`, next to the default `'undodir'`. The next
+//! `:Age decrypt` of the file reads it back with `:rundo`.
+//!
+//! Neovim only writes undo trees to files, so the plaintext tree goes
+//! through a file in `stdpath('run')` (private to the session) that is
+//! removed right away.
+//!
+//! Set `undo = false` in `setup()` to turn this off.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::AgeError;
+use crate::recovery;
+
+/// Below `stdpath('state')`.
+const UNDO_DIR: &str = "age/undo";
+
+/// The undo directory below `state` (`stdpath('state')`).
+pub(crate) fn undo_dir(state: &Path) -> PathBuf {
+    state.join(UNDO_DIR)
+}
+
+/// The encrypted undo tree of `source` in `dir`, named like journals.
+pub(crate) fn undo_path(dir: &Path, source: &Path) -> PathBuf {
+    recovery::journal_path(dir, source)
+}
+
+/// Where `:wundo` and `:rundo` briefly put the plaintext tree of `bufnr`,
+/// `run` is `stdpath('run')`.
+pub(crate) fn scratch_path(run: &Path, bufnr: i32) -> PathBuf {
+    run.join(format!("age-undo-{bufnr}"))
+}
+
+/// Writes `contents` to `path`, only readable by the user.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<(), AgeError> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// Reads and deletes `path`, it is deleted even when reading fails.
+pub(crate) fn take(path: &Path) -> Result<Vec<u8>, AgeError> {
+    let contents = fs::read(path);
+    fs::remove_file(path)?;
+    Ok(contents?)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::undo::{scratch_path, take, undo_dir, undo_path, write_private};
+
+    #[test]
+    fn undo_paths() {
+        let dir = undo_dir(Path::new("/state"));
+        assert_eq!(dir, Path::new("/state/age/undo"));
+        assert_eq!(
+            undo_path(&dir, Path::new("/home/me/journal.md.age")),
+            dir.join("%home%me%journal.md.age")
+        );
+        assert_eq!(
+            scratch_path(Path::new("/run/nvim"), 4),
+            Path::new("/run/nvim/age-undo-4")
+        );
+    }
+
+    #[test]
+    fn scratch_file_is_private_and_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = scratch_path(dir.path(), 1);
+
+        write_private(&path, b"tree").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert_eq!(take(&path).unwrap(), b"tree");
+        assert!(!path.exists());
+        assert!(take(&path).is_err());
+    }
+}
@@ -1 +1,44 @@
-// TODO:
+#![allow(clippy::unwrap_used)]
+
+use std::path::{Path, PathBuf};
+
+use age_nvim::crypt;
+use age_nvim::identity::{IdentitySource, KeyCmd};
+use age_nvim::types::ExistingAgeFile;
+
+fn key() -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/test_key.txt")
+        .display()
+        .to_string()
+}
+
+#[test]
+fn encrypt_and_decrypt_with_a_discovered_identity() {
+    let source = IdentitySource::discover(&key(), &KeyCmd::default()).unwrap();
+    let recipients = source.recipients().unwrap();
+
+    let ciphertext = crypt::encrypt_bytes_with(b"secret\n", &recipients).unwrap();
+    assert_eq!(crypt::recipient_count(&ciphertext).unwrap(), 1);
+
+    let plaintext = crypt::decrypt_binary_with(&ciphertext, &source.identities().unwrap()).unwrap();
+    assert_eq!(plaintext, b"secret\n");
+}
+
+#[test]
+fn files_and_path_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let age_file = dir.path().join("note.txt.age");
+    let recipients = crypt::load_recipients(vec![key()]).unwrap();
+    crypt::encrypt_bytes_to_file_with(b"hello", &age_file, &recipients).unwrap();
+
+    let file = ExistingAgeFile::try_from(age_file.clone()).unwrap();
+    assert_eq!(file.strip_age(), dir.path().join("note.txt"));
+    assert!(ExistingAgeFile::try_from(PathBuf::from("/nonexistent.age")).is_err());
+
+    let identities = crypt::load_identities(vec![key()]).unwrap();
+    assert_eq!(
+        crypt::decrypt_to_string_with(file.path(), &identities).unwrap(),
+        "hello"
+    );
+}
@@ -0,0 +1,118 @@
+#![allow(clippy::unwrap_used)]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const BIN: &str = env!("CARGO_BIN_EXE_age-nvim");
+
+fn key() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/test_key.txt")
+}
+
+/// Runs `age-nvim` in `dir` with the test key as the discovered identity.
+fn age_nvim(dir: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(BIN)
+        .current_dir(dir)
+        .args(args)
+        .env("HOME", dir)
+        .env("AGE_IDENTITY", key())
+        .env_remove("SOPS_AGE_KEY_FILE")
+        .env_remove("SOPS_AGE_KEY")
+        .env_remove("AGE_NVIM_AGENT_SOCK")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn encrypt_decrypt_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("note.txt"), "hello\n").unwrap();
+
+    let encrypted = age_nvim(
+        dir.path(),
+        &["encrypt", "-o", "note.txt.age", "note.txt"],
+        b"",
+    );
+    assert!(encrypted.status.success());
+    let ciphertext = std::fs::read(dir.path().join("note.txt.age")).unwrap();
+    assert!(ciphertext.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+
+    let decrypted = age_nvim(dir.path(), &["decrypt", "note.txt.age"], b"");
+    assert_eq!(decrypted.stdout, b"hello\n");
+
+    // stdin to stdout, with an explicit key
+    let key = key();
+    let key = key.to_str().unwrap();
+    let decrypted = age_nvim(dir.path(), &["decrypt", "-i", key], &ciphertext);
+    assert_eq!(decrypted.stdout, b"hello\n");
+
+    let textconv = age_nvim(dir.path(), &["textconv", "note.txt.age"], b"");
+    assert_eq!(textconv.stdout, b"hello\n");
+}
+
+#[test]
+fn clean_reuses_the_staged_ciphertext() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    git(root, &["init", "-q"]);
+
+    let first = age_nvim(root, &["clean", "secret.env"], b"TOKEN=1\n");
+    assert!(first.status.success());
+    std::fs::write(root.join("secret.env"), &first.stdout).unwrap();
+    git(root, &["add", "secret.env"]);
+
+    // same plaintext, same bytes: git sees no change
+    let again = age_nvim(root, &["clean", "secret.env"], b"TOKEN=1\n");
+    assert_eq!(again.stdout, first.stdout);
+
+    let changed = age_nvim(root, &["clean", "secret.env"], b"TOKEN=2\n");
+    assert_ne!(changed.stdout, first.stdout);
+    let smudged = age_nvim(root, &["smudge", "secret.env"], &changed.stdout);
+    assert_eq!(smudged.stdout, b"TOKEN=2\n");
+}
+
+#[test]
+fn smudge_passes_through_what_it_cant_decrypt() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let plain = age_nvim(dir.path(), &["smudge", "notes.txt"], b"not age\n");
+    assert!(plain.status.success());
+    assert_eq!(plain.stdout, b"not age\n");
+
+    // encrypted to someone else
+    let other = age_nvim(
+        dir.path(),
+        &[
+            "encrypt",
+            "-r",
+            "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p",
+        ],
+        b"secret\n",
+    );
+    let smudged = age_nvim(dir.path(), &["smudge", "secret.txt"], &other.stdout);
+    assert!(smudged.status.success());
+    assert_eq!(smudged.stdout, other.stdout);
+}
+
+#[test]
+fn unknown_commands_fail() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = age_nvim(dir.path(), &["frobnicate"], b"");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("usage: age-nvim"));
+}